@@ -0,0 +1,59 @@
+//! Builds the token contract the factory deploys from `../token`, so the code `create_fund`
+//! deploys always takes the init arguments the factory passes.
+//!
+//! Set `TOKEN_WASM` to the path of a prebuilt token contract, e.g. from its reproducible
+//! build, to embed that one instead.
+
+use std::env;
+use std::path::PathBuf;
+use std::process::Command;
+
+fn main() {
+    println!("cargo:rerun-if-env-changed=TOKEN_WASM");
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let embedded = out_dir.join("token.wasm");
+
+    if let Some(prebuilt) = env::var_os("TOKEN_WASM") {
+        println!(
+            "cargo:rerun-if-changed={}",
+            PathBuf::from(&prebuilt).display()
+        );
+        std::fs::copy(&prebuilt, &embedded).expect("Failed to copy TOKEN_WASM");
+        return;
+    }
+
+    let token_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap()).join("../token");
+    println!("cargo:rerun-if-changed={}", token_dir.join("src").display());
+    println!(
+        "cargo:rerun-if-changed={}",
+        token_dir.join("Cargo.toml").display()
+    );
+
+    // A target directory of its own keeps the nested build off the factory's build lock
+    let target_dir = out_dir.join("token-target");
+    let status = Command::new(env::var("CARGO").unwrap_or_else(|_| "cargo".to_string()))
+        .args([
+            "build",
+            "--release",
+            "--lib",
+            "--target",
+            "wasm32-unknown-unknown",
+        ])
+        .arg("--manifest-path")
+        .arg(token_dir.join("Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target_dir)
+        // Flags meant for the factory's own build must not leak into the contract's
+        .env_remove("CARGO_ENCODED_RUSTFLAGS")
+        .env_remove("CARGO_BUILD_TARGET")
+        .env("RUSTFLAGS", "-C link-arg=-s")
+        .status()
+        .expect("Failed to run cargo for the token contract");
+    assert!(status.success(), "Building the token contract failed");
+
+    std::fs::copy(
+        target_dir.join("wasm32-unknown-unknown/release/token.wasm"),
+        &embedded,
+    )
+    .expect("Token contract build produced no wasm");
+}
@@ -1,26 +1,32 @@
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
-use near_sdk::store::IterableMap;
+use near_sdk::store::{IterableMap, IterableSet};
 use near_sdk::{
     env, log, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseError,
     PublicKey,
 };
 use schemars::JsonSchema;
-use std::collections::HashMap;
-use once_cell::sync::Lazy;
+
+mod migrate;
+mod registry;
+
+use registry::RegisteredAsset;
 
 const TGAS: Gas = Gas::from_tgas(1);
 const NO_DEPOSIT: NearToken = NearToken::from_near(0);
-const DEFAULT_TOKEN_WASM: &[u8] = include_bytes!("./token/token.wasm");
-
-pub static TOKEN_ADDRESSES: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
-    let mut m = HashMap::new();
-    m.insert("0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6", "aurora.fakes.testnet");
-    m.insert("0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87", "weth.fakes.testnet");
-    m.insert("0xf08a50178dfcde18524640ea6618a1f965821715", "usdc.fakes.testnet");
-    m
-});
+/// Token contract built from `../token` by the build script
+const DEFAULT_TOKEN_WASM: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/token.wasm"));
+const FT_METADATA_SPEC: &str = "ft-1.0.0";
+// Shares are minted one per USDC base unit, so they carry USDC's decimals
+const SHARE_DECIMALS: u8 = 6;
+/// USDC contract of funds whose metadata names none, the one every fund was created with
+/// before it could be configured
+const DEFAULT_USDC_CONTRACT: &str =
+    "3e2210e1184b45b64c8a434c0a7e7b23cc04ea7eb7a6c3c32520d03d4afcb8af";
+/// Oracle of funds whose metadata names none, the one every fund was created with before it
+/// could be configured
+const DEFAULT_ORACLE_CONTRACT: &str = "priceoracle.testnet";
 
 #[derive(Serialize, Deserialize, JsonSchema, Clone, Debug)]
 #[serde(crate = "near_sdk::serde")]
@@ -61,15 +67,86 @@ pub struct FundMetadata {
     pub name: String,
     pub symbol: String,
     pub description: Option<String>,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    /// Base64-encoded sha256 hash of the JSON file behind `reference`
+    pub reference_hash: Option<String>,
     pub assets: Vec<AssetInfo>,
+    /// USDC contract the fund takes deposits in, `DEFAULT_USDC_CONTRACT` when omitted
+    #[schemars(with = "Option<String>")]
+    pub usdc_contract: Option<AccountId>,
+    /// Price oracle of the fund, `DEFAULT_ORACLE_CONTRACT` when omitted
+    #[schemars(with = "Option<String>")]
+    pub oracle_contract: Option<AccountId>,
+}
+
+/// NEP-148 metadata the fund's share token is initialized with.
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ShareTokenMetadata {
+    pub spec: String,
+    pub name: String,
+    pub symbol: String,
+    pub icon: Option<String>,
+    pub reference: Option<String>,
+    pub reference_hash: Option<String>,
+    pub decimals: u8,
+}
+
+impl From<&FundMetadata> for ShareTokenMetadata {
+    fn from(metadata: &FundMetadata) -> Self {
+        Self {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: metadata.name.clone(),
+            symbol: metadata.symbol.clone(),
+            icon: metadata.icon.clone(),
+            reference: metadata.reference.clone(),
+            reference_hash: metadata.reference_hash.clone(),
+            decimals: SHARE_DECIMALS,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(crate = "near_sdk::serde")]
+struct TokenInitArgs {
+    owner_id: AccountId,
+    assets: Vec<AssetInfo>,
+    usdc_contract: AccountId,
+    oracle_contract: AccountId,
+    metadata: ShareTokenMetadata,
+    registry: Vec<RegisteredAsset>,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema, Debug)]
 #[serde(crate = "near_sdk::serde")]
 pub struct AssetInfo {
     pub name: String,
+    /// Id of the asset in the registry
+    pub asset_id: String,
     pub contract_address: String,
     pub weight: u8,
+    #[serde(default)]
+    pub kind: AssetKind,
+}
+
+/// How the fund's treasury holds an asset, see the token contract.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    JsonSchema,
+    Debug,
+    Default,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AssetKind {
+    #[default]
+    Erc20,
+    Native,
 }
 
 #[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema, Debug)]
@@ -85,6 +162,10 @@ pub struct Fund {
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct IndexFundFactory {
     pub funds: IterableMap<String, Fund>,
+    /// Oracle asset id -> asset details, shared by every fund the factory creates
+    pub asset_registry: IterableMap<String, RegisteredAsset>,
+    /// Chains the funds the factory creates can hold assets on
+    pub chains: IterableSet<u64>,
 }
 
 #[near_bindgen]
@@ -93,6 +174,8 @@ impl IndexFundFactory {
     pub fn new() -> Self {
         Self {
             funds: IterableMap::new(b"f"),
+            asset_registry: IterableMap::new(b"a"),
+            chains: IterableSet::new(b"c"),
         }
     }
 
@@ -100,20 +183,73 @@ impl IndexFundFactory {
     pub fn create_fund(
         &mut self,
         prefix: String,
-        metadata: FundMetadata,
+        mut metadata: FundMetadata,
         public_key: Option<PublicKey>,
     ) -> Promise {
         let total_weight: u8 = metadata.assets.iter().map(|a| a.weight).sum();
         assert_eq!(total_weight, 100, "Total weight must be 100%");
 
+        // Seed the fund's own registry with the entries for the assets it holds
+        let registry: Vec<RegisteredAsset> = metadata
+            .assets
+            .iter()
+            .map(|asset| {
+                let registered = self
+                    .asset_registry
+                    .get(&asset.asset_id)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        env::panic_str(&format!("Asset {} is not in the registry", asset.asset_id))
+                    });
+                assert!(
+                    registered
+                        .evm_address
+                        .eq_ignore_ascii_case(&asset.contract_address),
+                    "Asset {} is registered at another address",
+                    asset.asset_id
+                );
+                assert!(
+                    self.chains.contains(&registered.chain_id),
+                    "Chain {} of asset {} is not registered",
+                    registered.chain_id,
+                    asset.asset_id
+                );
+                registered
+            })
+            .collect();
+
         let subaccount_id = format!("{}.{}", prefix, env::current_account_id());
         let subaccount = subaccount_id.parse::<AccountId>().unwrap();
 
-        let args = (
-            env::predecessor_account_id(),
-            metadata.assets.clone(),
-            "3e2210e1184b45b64c8a434c0a7e7b23cc04ea7eb7a6c3c32520d03d4afcb8af".parse::<AccountId>().unwrap(),
+        // Checked here as well as by the fund, so a bad configuration fails before the deposit
+        // is spent on the account
+        let usdc_contract = metadata
+            .usdc_contract
+            .get_or_insert_with(|| DEFAULT_USDC_CONTRACT.parse().unwrap())
+            .clone();
+        let oracle_contract = metadata
+            .oracle_contract
+            .get_or_insert_with(|| DEFAULT_ORACLE_CONTRACT.parse().unwrap())
+            .clone();
+        for dependency in [&usdc_contract, &oracle_contract] {
+            assert!(
+                *dependency != subaccount && *dependency != env::current_account_id(),
+                "The fund can't depend on itself or on the factory"
+            );
+        }
+        assert_ne!(
+            usdc_contract, oracle_contract,
+            "USDC and oracle contracts must differ"
         );
+
+        let args = TokenInitArgs {
+            owner_id: env::predecessor_account_id(),
+            assets: metadata.assets.clone(),
+            usdc_contract,
+            oracle_contract,
+            metadata: ShareTokenMetadata::from(&metadata),
+            registry,
+        };
     
         log!("Creating fund with args: {:?}", args);
     
@@ -0,0 +1,134 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::store::{IterableMap, IterableSet};
+use near_sdk::{env, log, near_bindgen};
+
+use crate::{
+    AssetInfo, AssetKind, Fund, FundMetadata, IndexFundFactory, IndexFundFactoryExt, U128Json,
+    DEFAULT_ORACLE_CONTRACT, DEFAULT_USDC_CONTRACT,
+};
+
+/// Address -> oracle id of the tokens funds created back then could price
+const LEGACY_ASSET_IDS: [(&str, &str); 3] = [
+    (
+        "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6",
+        "aurora.fakes.testnet",
+    ),
+    (
+        "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87",
+        "weth.fakes.testnet",
+    ),
+    (
+        "0xf08a50178dfcde18524640ea6618a1f965821715",
+        "usdc.fakes.testnet",
+    ),
+];
+
+/// Registry id of an asset of an old fund: its oracle id, or its address for tokens that had
+/// none. The fund is migrated with the ids recorded here, see the token's `migrate`.
+fn legacy_asset_id(contract_address: &str) -> String {
+    LEGACY_ASSET_IDS
+        .iter()
+        .find(|(address, _)| address.eq_ignore_ascii_case(contract_address))
+        .map_or_else(
+            || {
+                log!(
+                    "No oracle id for {}, it is recorded under its address",
+                    contract_address
+                );
+                contract_address.to_string()
+            },
+            |(_, asset_id)| asset_id.to_string(),
+        )
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldAssetInfo {
+    name: String,
+    contract_address: String,
+    weight: u8,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldFundMetadata {
+    name: String,
+    symbol: String,
+    description: Option<String>,
+    assets: Vec<OldAssetInfo>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldFund {
+    metadata: OldFundMetadata,
+    token_address: String,
+    total_supply: U128Json,
+    creation_timestamp: u64,
+}
+
+/// State layout of factories deployed before the asset registry.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldIndexFundFactory {
+    funds: IterableMap<String, OldFund>,
+}
+
+impl From<OldFund> for Fund {
+    fn from(old: OldFund) -> Self {
+        Self {
+            metadata: FundMetadata {
+                name: old.metadata.name,
+                symbol: old.metadata.symbol,
+                description: old.metadata.description,
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                assets: old
+                    .metadata
+                    .assets
+                    .into_iter()
+                    .map(|asset| AssetInfo {
+                        name: asset.name,
+                        asset_id: legacy_asset_id(&asset.contract_address),
+                        contract_address: asset.contract_address,
+                        weight: asset.weight,
+                        kind: AssetKind::Erc20,
+                    })
+                    .collect(),
+                usdc_contract: Some(DEFAULT_USDC_CONTRACT.parse().unwrap()),
+                oracle_contract: Some(DEFAULT_ORACLE_CONTRACT.parse().unwrap()),
+            },
+            token_address: old.token_address,
+            total_supply: old.total_supply,
+            creation_timestamp: old.creation_timestamp,
+        }
+    }
+}
+
+#[near_bindgen]
+impl IndexFundFactory {
+    /// Converts the state of a factory deployed with the old layout.
+    ///
+    /// Every fund is rewritten with the fields its metadata gained, and the asset registry and
+    /// its chains start out empty for the owner to fill. Only the old layout deserializes
+    /// here, so the migration can't run twice.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let mut old: OldIndexFundFactory =
+            env::state_read().unwrap_or_else(|| env::panic_str("No contract state to migrate"));
+
+        let old_funds: Vec<(String, OldFund)> = old.funds.drain().collect();
+        // The new map reuses the prefix, so the old entries have to be gone from storage first
+        old.funds.flush();
+
+        let mut funds = IterableMap::new(b"f");
+        for (prefix, fund) in old_funds {
+            funds.insert(prefix, Fund::from(fund));
+        }
+
+        log!("Migrated {} funds", funds.len());
+        Self {
+            funds,
+            asset_registry: IterableMap::new(b"a"),
+            chains: IterableSet::new(b"c"),
+        }
+    }
+}
@@ -0,0 +1,111 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, log, near_bindgen, AccountId};
+use schemars::JsonSchema;
+
+use crate::{IndexFundFactory, IndexFundFactoryExt};
+
+/// Everything a fund needs to know about a token it can price or hold.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, JsonSchema, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RegisteredAsset {
+    /// Id the price oracle reports the asset under
+    pub asset_id: String,
+    pub chain_id: u64,
+    /// Token contract on `chain_id`, as used in `AssetInfo::contract_address`. Unique per
+    /// chain only, e.g. the native coin's placeholder is registered once for every chain
+    pub evm_address: String,
+    /// Token the asset is represented by on NEAR
+    #[schemars(with = "String")]
+    pub near_token: AccountId,
+    pub decimals: u8,
+}
+
+impl IndexFundFactory {
+    pub(crate) fn find_asset_by_address(
+        &self,
+        chain_id: u64,
+        evm_address: &str,
+    ) -> Option<&RegisteredAsset> {
+        self.asset_registry.values().find(|asset| {
+            asset.chain_id == chain_id && asset.evm_address.eq_ignore_ascii_case(evm_address)
+        })
+    }
+}
+
+#[near_bindgen]
+impl IndexFundFactory {
+    #[private]
+    pub fn add_asset(&mut self, asset: RegisteredAsset) {
+        assert!(
+            !self.asset_registry.contains_key(&asset.asset_id),
+            "Asset is already registered"
+        );
+        assert!(
+            self.find_asset_by_address(asset.chain_id, &asset.evm_address)
+                .is_none(),
+            "Address is already registered on the chain"
+        );
+
+        log!("Registered asset {}", asset.asset_id);
+        self.asset_registry.insert(asset.asset_id.clone(), asset);
+    }
+
+    #[private]
+    pub fn update_asset(&mut self, asset: RegisteredAsset) {
+        assert!(
+            self.asset_registry.contains_key(&asset.asset_id),
+            "Asset is not registered"
+        );
+        assert!(
+            self.find_asset_by_address(asset.chain_id, &asset.evm_address)
+                .map_or(true, |existing| existing.asset_id == asset.asset_id),
+            "Address is already registered on the chain"
+        );
+
+        log!("Updated asset {}", asset.asset_id);
+        self.asset_registry.insert(asset.asset_id.clone(), asset);
+    }
+
+    /// Funds keep their own copy of the registry, so removing an entry only affects funds
+    /// created afterwards.
+    #[private]
+    pub fn remove_asset(&mut self, asset_id: String) {
+        if self.asset_registry.remove(&asset_id).is_none() {
+            env::panic_str("Asset is not registered");
+        }
+        log!("Removed asset {}", asset_id);
+    }
+
+    pub fn get_registered_asset(&self, asset_id: String) -> Option<RegisteredAsset> {
+        self.asset_registry.get(&asset_id).cloned()
+    }
+
+    pub fn get_registered_assets(&self, from_index: u64, limit: u64) -> Vec<RegisteredAsset> {
+        self.asset_registry
+            .values()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    }
+
+    /// Lets funds hold assets registered on `chain_id`. The funds register the chain's
+    /// treasury and fees themselves.
+    #[private]
+    pub fn add_chain(&mut self, chain_id: u64) {
+        assert!(self.chains.insert(chain_id), "Chain is already registered");
+        log!("Registered chain {}", chain_id);
+    }
+
+    /// Funds created before keep the assets they hold on the chain.
+    #[private]
+    pub fn remove_chain(&mut self, chain_id: u64) {
+        assert!(self.chains.remove(&chain_id), "Chain is not registered");
+        log!("Removed chain {}", chain_id);
+    }
+
+    pub fn get_chains(&self) -> Vec<u64> {
+        self.chains.iter().copied().collect()
+    }
+}
@@ -0,0 +1,333 @@
+//! Solidity contract ABI encoding of the calls the treasuries sign.
+//!
+//! Every value of a tuple, including the arguments of a call, takes a slot in the head of the
+//! encoding: static values are written there directly, dynamic ones (`bytes`, `T[]` and tuples
+//! holding either) are written after the head, with their offset from the start of the tuple in
+//! their slot. The module is public so that relayers and front ends build the exact same calldata.
+
+use near_sdk::env;
+
+pub use crate::math::U256;
+
+/// A value passed to a contract.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Token {
+    Address([u8; 20]),
+    /// Any `uintN`, `bool` included
+    Uint(U256),
+    Bytes(Vec<u8>),
+    /// `T[]`, every item being of the same type
+    Array(Vec<Token>),
+    Tuple(Vec<Token>),
+}
+
+impl Token {
+    fn is_dynamic(&self) -> bool {
+        match self {
+            Token::Address(_) | Token::Uint(_) => false,
+            Token::Bytes(_) | Token::Array(_) => true,
+            Token::Tuple(tokens) => tokens.iter().any(Token::is_dynamic),
+        }
+    }
+
+    /// Bytes the token takes in the head of its tuple.
+    fn head_len(&self) -> usize {
+        match self {
+            Token::Tuple(tokens) if !self.is_dynamic() => tokens.iter().map(Token::head_len).sum(),
+            _ => 32,
+        }
+    }
+}
+
+/// First four bytes of the keccak hash of a canonical signature, e.g. `transfer(address,uint256)`.
+pub fn selector(signature: &str) -> [u8; 4] {
+    let hash = env::keccak256_array(signature.as_bytes());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Encodes `tokens` as the values of a tuple.
+pub fn encode(tokens: &[Token]) -> Vec<u8> {
+    let head_len: usize = tokens.iter().map(Token::head_len).sum();
+    let mut head = Vec::with_capacity(head_len);
+    let mut tail = Vec::new();
+    for token in tokens {
+        if token.is_dynamic() {
+            head.extend_from_slice(&word(U256::from(head_len + tail.len())));
+            tail.extend(encode_token(token));
+        } else {
+            head.extend(encode_token(token));
+        }
+    }
+    head.extend(tail);
+    head
+}
+
+/// Calldata of a call to `signature` with `args`.
+pub fn encode_call(signature: &str, args: &[Token]) -> Vec<u8> {
+    let mut data = selector(signature).to_vec();
+    data.extend(encode(args));
+    data
+}
+
+fn encode_token(token: &Token) -> Vec<u8> {
+    match token {
+        Token::Address(address) => {
+            let mut data = vec![0; 12];
+            data.extend_from_slice(address);
+            data
+        }
+        Token::Uint(value) => word(*value).to_vec(),
+        Token::Bytes(bytes) => {
+            let mut data = word(U256::from(bytes.len())).to_vec();
+            data.extend_from_slice(bytes);
+            data.resize(data.len() + (32 - bytes.len() % 32) % 32, 0);
+            data
+        }
+        Token::Array(tokens) => {
+            let mut data = word(U256::from(tokens.len())).to_vec();
+            data.extend(encode(tokens));
+            data
+        }
+        Token::Tuple(tokens) => encode(tokens),
+    }
+}
+
+fn word(value: U256) -> [u8; 32] {
+    let mut word = [0; 32];
+    for (index, byte) in word.iter_mut().enumerate() {
+        *byte = value.byte(31 - index);
+    }
+    word
+}
+
+/// ERC-20 `transfer(address,uint256)`.
+pub fn erc20_transfer(to: [u8; 20], amount: U256) -> Vec<u8> {
+    encode_call(
+        "transfer(address,uint256)",
+        &[Token::Address(to), Token::Uint(amount)],
+    )
+}
+
+/// ERC-20 `approve(address,uint256)`.
+pub fn erc20_approve(spender: [u8; 20], amount: U256) -> Vec<u8> {
+    encode_call(
+        "approve(address,uint256)",
+        &[Token::Address(spender), Token::Uint(amount)],
+    )
+}
+
+/// ERC-20 `transferFrom(address,address,uint256)`.
+pub fn erc20_transfer_from(from: [u8; 20], to: [u8; 20], amount: U256) -> Vec<u8> {
+    encode_call(
+        "transferFrom(address,address,uint256)",
+        &[
+            Token::Address(from),
+            Token::Address(to),
+            Token::Uint(amount),
+        ],
+    )
+}
+
+/// Uniswap V2 style router `swapExactTokensForTokens`, swapping `amount_in` of `path[0]` for at
+/// least `amount_out_min` of the last token of `path`.
+pub fn swap_exact_tokens_for_tokens(
+    amount_in: U256,
+    amount_out_min: U256,
+    path: &[[u8; 20]],
+    to: [u8; 20],
+    deadline: U256,
+) -> Vec<u8> {
+    encode_call(
+        "swapExactTokensForTokens(uint256,uint256,address[],address,uint256)",
+        &[
+            Token::Uint(amount_in),
+            Token::Uint(amount_out_min),
+            Token::Array(path.iter().copied().map(Token::Address).collect()),
+            Token::Address(to),
+            Token::Uint(deadline),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPIENT: [u8; 20] = [
+        0x12, 0x34, 0x56, 0x78, 0x90, 0x12, 0x34, 0x56, 0x78, 0x90, 0x12, 0x34, 0x56, 0x78, 0x90,
+        0x12, 0x34, 0x56, 0x78, 0x90,
+    ];
+
+    fn address(hex_address: &str) -> [u8; 20] {
+        hex::decode(hex_address).unwrap().try_into().unwrap()
+    }
+
+    fn uint(value: u128) -> Token {
+        Token::Uint(U256::from(value))
+    }
+
+    #[test]
+    fn test_selectors() {
+        assert_eq!(
+            selector("transfer(address,uint256)"),
+            [0xa9, 0x05, 0x9c, 0xbb]
+        );
+        assert_eq!(
+            selector("approve(address,uint256)"),
+            [0x09, 0x5e, 0xa7, 0xb3]
+        );
+        assert_eq!(
+            selector("transferFrom(address,address,uint256)"),
+            [0x23, 0xb8, 0x72, 0xdd]
+        );
+    }
+
+    #[test]
+    fn test_erc20_calls() {
+        assert_eq!(
+            hex::encode(erc20_transfer(RECIPIENT, U256::from(700u64))),
+            concat!(
+                "a9059cbb",
+                "0000000000000000000000001234567890123456789012345678901234567890",
+                "00000000000000000000000000000000000000000000000000000000000002bc",
+            )
+        );
+        // Unlimited allowance takes all 256 bits
+        assert_eq!(
+            hex::encode(erc20_approve(RECIPIENT, U256::MAX)),
+            concat!(
+                "095ea7b3",
+                "0000000000000000000000001234567890123456789012345678901234567890",
+                "ffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff",
+            )
+        );
+        assert_eq!(
+            hex::encode(erc20_transfer_from(
+                address("2e5221b0f855be4ea5cefffb8311eed0563b6e87"),
+                RECIPIENT,
+                U256::from(1u64) << 128,
+            )),
+            concat!(
+                "23b872dd",
+                "0000000000000000000000002e5221b0f855be4ea5cefffb8311eed0563b6e87",
+                "0000000000000000000000001234567890123456789012345678901234567890",
+                "0000000000000000000000000000000100000000000000000000000000000000",
+            )
+        );
+    }
+
+    #[test]
+    fn test_router_swap() {
+        let path = [
+            address("2e5221b0f855be4ea5cefffb8311eed0563b6e87"),
+            address("e09d8adae1141181f4cddddef97e4cf68f5436e6"),
+        ];
+        assert_eq!(
+            hex::encode(swap_exact_tokens_for_tokens(
+                U256::from(1_000_000_000_000_000_000u64),
+                U256::from(990u64),
+                &path,
+                RECIPIENT,
+                U256::from(1_700_000_000u64),
+            )),
+            concat!(
+                "38ed1739",
+                "0000000000000000000000000000000000000000000000000de0b6b3a7640000",
+                "00000000000000000000000000000000000000000000000000000000000003de",
+                "00000000000000000000000000000000000000000000000000000000000000a0",
+                "0000000000000000000000001234567890123456789012345678901234567890",
+                "000000000000000000000000000000000000000000000000000000006553f100",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000002e5221b0f855be4ea5cefffb8311eed0563b6e87",
+                "000000000000000000000000e09d8adae1141181f4cddddef97e4cf68f5436e6",
+            )
+        );
+    }
+
+    // Examples from the Solidity ABI specification
+    #[test]
+    fn test_dynamic_values() {
+        assert_eq!(
+            hex::encode(encode_call(
+                "sam(bytes,bool,uint256[])",
+                &[
+                    Token::Bytes(b"dave".to_vec()),
+                    uint(1),
+                    Token::Array(vec![uint(1), uint(2), uint(3)]),
+                ],
+            )),
+            concat!(
+                "a5643bf2",
+                "0000000000000000000000000000000000000000000000000000000000000060",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "00000000000000000000000000000000000000000000000000000000000000a0",
+                "0000000000000000000000000000000000000000000000000000000000000004",
+                "6461766500000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+            )
+        );
+    }
+
+    #[test]
+    fn test_nested_arrays() {
+        let string = |value: &str| Token::Bytes(value.as_bytes().to_vec());
+        assert_eq!(
+            hex::encode(encode_call(
+                "g(uint256[][],string[])",
+                &[
+                    Token::Array(vec![
+                        Token::Array(vec![uint(1), uint(2)]),
+                        Token::Array(vec![uint(3)]),
+                    ]),
+                    Token::Array(vec![string("one"), string("two"), string("three")]),
+                ],
+            )),
+            concat!(
+                "2289b18c",
+                "0000000000000000000000000000000000000000000000000000000000000040",
+                "0000000000000000000000000000000000000000000000000000000000000140",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000040",
+                "00000000000000000000000000000000000000000000000000000000000000a0",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "0000000000000000000000000000000000000000000000000000000000000060",
+                "00000000000000000000000000000000000000000000000000000000000000a0",
+                "00000000000000000000000000000000000000000000000000000000000000e0",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "6f6e650000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000003",
+                "74776f0000000000000000000000000000000000000000000000000000000000",
+                "0000000000000000000000000000000000000000000000000000000000000005",
+                "7468726565000000000000000000000000000000000000000000000000000000",
+            )
+        );
+    }
+
+    #[test]
+    fn test_tuples() {
+        // A static tuple is written in place, a dynamic one after the head
+        assert_eq!(
+            hex::encode(encode(&[
+                Token::Tuple(vec![uint(1), Token::Address(RECIPIENT)]),
+                Token::Tuple(vec![uint(2), Token::Bytes(vec![0xab, 0xcd])]),
+            ])),
+            concat!(
+                "0000000000000000000000000000000000000000000000000000000000000001",
+                "0000000000000000000000001234567890123456789012345678901234567890",
+                "0000000000000000000000000000000000000000000000000000000000000060",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "0000000000000000000000000000000000000000000000000000000000000040",
+                "0000000000000000000000000000000000000000000000000000000000000002",
+                "abcd000000000000000000000000000000000000000000000000000000000000",
+            )
+        );
+    }
+}
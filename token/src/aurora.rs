@@ -0,0 +1,263 @@
+//! Withdrawals on Aurora executed by the fund itself through the Aurora Engine.
+//!
+//! Every NEAR account controls an Aurora address, the last 20 bytes of the keccak hash of its
+//! account id, and moves it with the engine's `call` method. A chain of kind `AuroraEngine`
+//! holds its assets at the fund's own address instead of an MPC derived one, so its legs are
+//! sent and finalized within the withdrawal, without signatures or relayers.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::{env, near_bindgen, AccountId, NearToken, Promise, PromiseResult};
+use omni_transaction::evm::utils::parse_eth_address;
+
+use crate::abi;
+use crate::withdrawal::{WithdrawalLeg, LEG_CALLBACK_GAS, LEG_CALL_GAS};
+use crate::{AssetKind, Contract, ContractExt};
+
+/// Arguments of the engine's `call` method, borsh serialized.
+#[derive(BorshSerialize)]
+enum CallArgs {
+    V2(FunctionCallArgsV2),
+}
+
+#[derive(BorshSerialize)]
+struct FunctionCallArgsV2 {
+    contract: [u8; 20],
+    /// Wei sent along, big endian
+    value: [u8; 32],
+    input: Vec<u8>,
+}
+
+/// What the engine returns for a `call`, borsh serialized.
+#[derive(BorshDeserialize)]
+struct SubmitResult {
+    _version: u8,
+    status: TransactionStatus,
+    _gas_used: u64,
+    _logs: Vec<ResultLog>,
+}
+
+#[derive(BorshDeserialize)]
+enum TransactionStatus {
+    Succeed(Vec<u8>),
+    Revert(Vec<u8>),
+    OutOfGas,
+    OutOfFund,
+    OutOfOffset,
+    CallTooDeep,
+}
+
+#[derive(BorshDeserialize)]
+struct ResultLog {
+    _address: [u8; 20],
+    _topics: Vec<[u8; 32]>,
+    _data: Vec<u8>,
+}
+
+/// Aurora address controlled by `account_id`.
+pub fn implicit_address(account_id: &AccountId) -> [u8; 20] {
+    let hash = env::keccak256_array(account_id.as_bytes());
+    let mut address = [0; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+/// Input of the engine `call` that sends `leg`.
+fn leg_call_args(leg: &WithdrawalLeg) -> Vec<u8> {
+    let destination = parse_eth_address(&leg.destination);
+    let (contract, amount, input) = match leg.kind {
+        AssetKind::Erc20 => (
+            parse_eth_address(&leg.asset),
+            0,
+            abi::erc20_transfer(destination, abi::U256::from(leg.amount.0)),
+        ),
+        AssetKind::Native => (destination, leg.amount.0, Vec::new()),
+    };
+    let mut value = [0; 32];
+    value[16..].copy_from_slice(&amount.to_be_bytes());
+
+    borsh::to_vec(&CallArgs::V2(FunctionCallArgsV2 {
+        contract,
+        value,
+        input,
+    }))
+    .expect("Call arguments always serialize")
+}
+
+/// Whether the engine executed the call and the token didn't report a failed transfer.
+///
+/// Tokens that return nothing from `transfer` are taken at their word, as their callers do.
+fn call_succeeded(result: &[u8]) -> Result<(), String> {
+    let result = SubmitResult::try_from_slice(result)
+        .map_err(|_| "Engine returned an invalid result".to_string())?;
+    let success = abi::encode(&[abi::Token::Uint(abi::U256::from(1u64))]);
+    match result.status {
+        TransactionStatus::Succeed(output) if output.is_empty() || output == success => Ok(()),
+        TransactionStatus::Succeed(_) => Err("Transfer returned false".to_string()),
+        TransactionStatus::Revert(output) => {
+            Err(format!("Call reverted with 0x{}", hex::encode(output)))
+        }
+        TransactionStatus::OutOfGas => Err("Call ran out of gas".to_string()),
+        TransactionStatus::OutOfFund => Err("Treasury can't pay the value".to_string()),
+        TransactionStatus::OutOfOffset | TransactionStatus::CallTooDeep => {
+            Err("Call failed".to_string())
+        }
+    }
+}
+
+impl Contract {
+    /// Sends a leg from the fund's Aurora address with a `call` on `engine`.
+    pub(crate) fn create_aurora_engine_withdrawal(
+        &self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        leg: &WithdrawalLeg,
+        engine: AccountId,
+    ) -> Promise {
+        Promise::new(engine)
+            .function_call(
+                "call".to_string(),
+                leg_call_args(leg),
+                NearToken::from_yoctonear(0),
+                LEG_CALL_GAS,
+            )
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(LEG_CALLBACK_GAS)
+                    .aurora_call_callback(withdrawal_id, leg_index),
+            )
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Confirms a leg the engine executed, or credits it back to the user when the call failed.
+    #[private]
+    pub fn aurora_call_callback(&mut self, withdrawal_id: u64, leg_index: u32) -> bool {
+        let outcome = match env::promise_result(0) {
+            PromiseResult::Successful(result) => call_succeeded(&result),
+            PromiseResult::Failed => Err("Engine call failed".to_string()),
+        };
+        match outcome {
+            Ok(()) => {
+                self.internal_execute_leg(withdrawal_id, leg_index);
+                true
+            }
+            Err(reason) => {
+                env::log_str(&format!(
+                    "Aurora call failed for withdrawal {} leg {}, refunding: {}",
+                    withdrawal_id, leg_index, reason
+                ));
+                self.internal_refund_leg(withdrawal_id, leg_index);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::json_types::U128;
+
+    use crate::withdrawal::WithdrawalStatus;
+
+    const RECIPIENT: &str = "0x1234567890123456789012345678901234567890";
+
+    fn leg(kind: AssetKind, asset: &str) -> WithdrawalLeg {
+        WithdrawalLeg {
+            asset: asset.to_string(),
+            asset_id: "aurora.fakes.testnet".to_string(),
+            kind,
+            amount: U128(700),
+            destination: RECIPIENT.to_string(),
+            treasury_path: "aurora-treasury".to_string(),
+            chain_id: 1313161555,
+            nonce: None,
+            status: WithdrawalStatus::Pending,
+            sent_at: 0,
+            tx_id: None,
+            inputs: Vec::new(),
+        }
+    }
+
+    fn submit_result(status: TransactionStatus) -> Vec<u8> {
+        let mut result = vec![7];
+        match status {
+            TransactionStatus::Succeed(output) => {
+                result.push(0);
+                result.extend((output.len() as u32).to_le_bytes());
+                result.extend(output);
+            }
+            TransactionStatus::Revert(output) => {
+                result.push(1);
+                result.extend((output.len() as u32).to_le_bytes());
+                result.extend(output);
+            }
+            _ => result.push(2),
+        }
+        result.extend(21_000u64.to_le_bytes());
+        // One log with a topic and no data
+        result.extend(1u32.to_le_bytes());
+        result.extend([0x35; 20]);
+        result.extend(1u32.to_le_bytes());
+        result.extend([0xdd; 32]);
+        result.extend(0u32.to_le_bytes());
+        result
+    }
+
+    #[test]
+    fn test_implicit_address() {
+        assert_eq!(
+            hex::encode(implicit_address(&"alice.near".parse().unwrap())),
+            "10315b5be6b5369e2188c8d7b18ec932c936a21e"
+        );
+    }
+
+    #[test]
+    fn test_erc20_call_args() {
+        let args = leg_call_args(&leg(
+            AssetKind::Erc20,
+            "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6",
+        ));
+        assert_eq!(args[0], 0);
+        assert_eq!(
+            hex::encode(&args[1..21]),
+            "e09d8adae1141181f4cddddef97e4cf68f5436e6"
+        );
+        assert_eq!(args[21..53], [0; 32]);
+        assert_eq!(args[53..57], 68u32.to_le_bytes());
+        assert_eq!(
+            args[57..],
+            abi::erc20_transfer(parse_eth_address(RECIPIENT), abi::U256::from(700u64))
+        );
+    }
+
+    #[test]
+    fn test_native_call_args() {
+        let args = leg_call_args(&leg(AssetKind::Native, crate::NATIVE_ASSET_ADDRESS));
+        assert_eq!(hex::encode(&args[1..21]), &RECIPIENT[2..]);
+        assert_eq!(args[51..53], [0x02, 0xbc]);
+        assert_eq!(args[53..], [0; 4]);
+    }
+
+    #[test]
+    fn test_call_outcome() {
+        let mut transfer_true = vec![0; 32];
+        transfer_true[31] = 1;
+        assert!(call_succeeded(&submit_result(TransactionStatus::Succeed(transfer_true))).is_ok());
+        assert!(call_succeeded(&submit_result(TransactionStatus::Succeed(Vec::new()))).is_ok());
+        assert_eq!(
+            call_succeeded(&submit_result(TransactionStatus::Succeed(vec![0; 32]))),
+            Err("Transfer returned false".to_string())
+        );
+        assert_eq!(
+            call_succeeded(&submit_result(TransactionStatus::Revert(vec![0x08]))),
+            Err("Call reverted with 0x08".to_string())
+        );
+        assert_eq!(
+            call_succeeded(&submit_result(TransactionStatus::OutOfGas)),
+            Err("Call ran out of gas".to_string())
+        );
+        assert!(call_succeeded(&[7, 0]).is_err());
+    }
+}
@@ -0,0 +1,154 @@
+//! Bech32 segwit version 0 addresses, as specified by BIP-173.
+//!
+//! Only version 0 programs are supported: 20 byte P2WPKH and 32 byte P2WSH ones. Later versions
+//! use the bech32m checksum, which the treasuries never need.
+
+const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+/// Address of the version 0 witness `program` on the network with human readable part `hrp`.
+pub fn encode_segwit_v0(hrp: &str, program: &[u8]) -> String {
+    let mut data = vec![0];
+    data.extend(convert_bits(program, 8, 5, true).expect("Any program converts to 5 bits"));
+    let checksum = polymod(&[hrp_expand(hrp), data.clone(), vec![0; 6]].concat()) ^ 1;
+    data.extend((0..6).map(|i| ((checksum >> (5 * (5 - i))) & 31) as u8));
+
+    let mut address = format!("{}1", hrp);
+    address.extend(data.iter().map(|&value| CHARSET[value as usize] as char));
+    address
+}
+
+/// Witness program of a version 0 `address` on the network with human readable part `hrp`.
+pub fn decode_segwit_v0(hrp: &str, address: &str) -> Result<Vec<u8>, String> {
+    let lowercase = address.to_ascii_lowercase();
+    if address != lowercase && address != address.to_ascii_uppercase() {
+        return Err("Address mixes upper and lower case".to_string());
+    }
+    let (address_hrp, data) = lowercase
+        .rsplit_once('1')
+        .ok_or_else(|| "Address has no separator".to_string())?;
+    if address_hrp != hrp {
+        return Err(format!("Address is not on the {} network", hrp));
+    }
+    let data = data
+        .bytes()
+        .map(|c| {
+            CHARSET
+                .iter()
+                .position(|&d| d == c)
+                .map(|value| value as u8)
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| "Address has an invalid character".to_string())?;
+    if data.len() < 7 || polymod(&[hrp_expand(hrp), data.clone()].concat()) != 1 {
+        return Err("Address checksum is invalid".to_string());
+    }
+
+    let (version, program) = (data[0], &data[1..data.len() - 6]);
+    if version != 0 {
+        return Err(format!("Witness version {} is not supported", version));
+    }
+    let program = convert_bits(program, 5, 8, false)
+        .ok_or_else(|| "Address has an invalid padding".to_string())?;
+    match program.len() {
+        20 | 32 => Ok(program),
+        len => Err(format!("Witness program can't be {} bytes", len)),
+    }
+}
+
+fn polymod(values: &[u8]) -> u32 {
+    values.iter().fold(1, |checksum, &value| {
+        let top = checksum >> 25;
+        let checksum = ((checksum & 0x1ffffff) << 5) ^ u32::from(value);
+        GENERATOR
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| (top >> i) & 1 == 1)
+            .fold(checksum, |checksum, (_, generator)| checksum ^ generator)
+    })
+}
+
+fn hrp_expand(hrp: &str) -> Vec<u8> {
+    let mut expanded: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    expanded.push(0);
+    expanded.extend(hrp.bytes().map(|c| c & 31));
+    expanded
+}
+
+/// Regroups `data` from `from`-bit to `to`-bit values, `None` when the padding is invalid.
+fn convert_bits(data: &[u8], from: u32, to: u32, pad: bool) -> Option<Vec<u8>> {
+    let (mut acc, mut bits) = (0u32, 0u32);
+    let max = (1 << to) - 1;
+    let mut converted = Vec::new();
+    for &value in data {
+        acc = (acc << from) | u32::from(value);
+        bits += from;
+        while bits >= to {
+            bits -= to;
+            converted.push(((acc >> bits) & max) as u8);
+        }
+    }
+    if pad {
+        if bits > 0 {
+            converted.push(((acc << (to - bits)) & max) as u8);
+        }
+    } else if bits >= from || (acc << (to - bits)) & max != 0 {
+        return None;
+    }
+    Some(converted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBKEY_HASH: &str = "751e76e8199196d454941c45d1b3a323f1433bd6";
+
+    // Examples from BIP-173
+    #[test]
+    fn test_encode() {
+        let program = hex::decode(PUBKEY_HASH).unwrap();
+        assert_eq!(
+            encode_segwit_v0("bc", &program),
+            "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"
+        );
+        assert_eq!(
+            encode_segwit_v0("tb", &program),
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx"
+        );
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(
+            hex::encode(
+                decode_segwit_v0("bc", "BC1QW508D6QEJXTDG4Y5R3ZARVARY0C5XW7KV8F3T4").unwrap()
+            ),
+            PUBKEY_HASH
+        );
+        assert_eq!(
+            hex::encode(
+                decode_segwit_v0(
+                    "tb",
+                    "tb1qrp33g0q5c5txsp9arysrx4k6zdkfs4nce4xj0gdcccefvpysxf3q0sl5k7"
+                )
+                .unwrap()
+            ),
+            "1863143c14c5166804bd19203356da136c985678cd4d27a1b8c6329604903262"
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_invalid_addresses() {
+        let invalid = [
+            ("bc", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t5"),
+            ("tb", "bc1qw508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            ("bc", "bc1QW508d6qejxtdg4y5r3zarvary0c5xw7kv8f3t4"),
+            ("bc", "bc1zw508d6qejxtdg4y5r3zarvaryvqyzf3du"),
+            ("bc", "bc1rw5uspcuh"),
+        ];
+        for (hrp, address) in invalid {
+            assert!(decode_segwit_v0(hrp, address).is_err(), "{}", address);
+        }
+    }
+}
@@ -0,0 +1,613 @@
+//! Bitcoin treasuries, spending the P2WPKH outputs of their MPC derived key.
+//!
+//! Relayers report the unspent outputs of each treasury. A withdrawal spends the oldest ones
+//! that cover its amount and fee, sends the change back to the treasury and has the BIP-143
+//! sighash of every input signed by the MPC signer. Outputs leave the set as soon as a
+//! withdrawal picks them, so concurrent withdrawals never spend the same one; the change joins
+//! the set the next time a relayer reports it. Picked outputs are held until their leg is
+//! confirmed or refunded, and stay out of the set even when a relayer reports them again
+//! before the transaction spending them is broadcast.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U64;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, serde_json, Gas, Promise, PromiseResult};
+use omni_transaction::bitcoin::bitcoin_transaction::BitcoinTransaction;
+use omni_transaction::bitcoin::types::{
+    Amount, EcdsaSighashType, Hash, LockTime, OutPoint, ScriptBuf, Sequence, TransactionType, TxIn,
+    TxOut, Txid, Version, Witness,
+};
+use omni_transaction::transaction_builder::{TransactionBuilder, TxBuilder};
+use omni_transaction::types::BITCOIN;
+
+use crate::bech32;
+use crate::chains::ChainKind;
+use crate::events::FundEvent;
+use crate::fees::CallType;
+use crate::nonce::nonce_key;
+use crate::signer::{self, mpc, SignRequest, SignResult};
+use crate::withdrawal::{WithdrawalLeg, LEG_CALL_GAS, LEG_GAS_BUDGET};
+use crate::{Contract, ContractExt, NetworkDetails, MPC_CONTRACT_ACCOUNT_ID};
+
+/// Change below this is worth less than the fee to spend it and is left to the miner.
+const DUST_LIMIT: u64 = 294;
+/// Most outputs a withdrawal spends, as each of them is signed by its own MPC call.
+pub(crate) const MAX_INPUTS: usize = 2;
+/// Gas of the callback settling a Bitcoin leg: per input, its sighash and an `ecrecover`
+/// against the cached treasury key, then the witnesses and the records of `LEG_CALLBACK_GAS`.
+pub(crate) const BITCOIN_CALLBACK_GAS: Gas = Gas::from_tgas(30);
+// A leg spending `MAX_INPUTS` outputs must fit in the gas of a single call
+const _: () = assert!(
+    LEG_CALL_GAS.as_gas() * MAX_INPUTS as u64 + BITCOIN_CALLBACK_GAS.as_gas()
+        <= LEG_GAS_BUDGET.as_gas()
+);
+/// Opts in to replace-by-fee so a stuck withdrawal can be bumped.
+const SEQUENCE: u32 = 0xffff_fffd;
+// Virtual sizes of the parts of a P2WPKH spend, rounded up
+const TX_OVERHEAD_VBYTES: u64 = 11;
+const INPUT_VBYTES: u64 = 68;
+const OUTPUT_VBYTES: u64 = 31;
+
+/// An unspent output of a treasury.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Utxo {
+    /// Id of the transaction that created the output, as shown by explorers
+    pub txid: String,
+    pub vout: u32,
+    /// Value in satoshis
+    pub value: U64,
+}
+
+/// An output of a transaction the treasury signs.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BitcoinOutput {
+    /// Value in satoshis
+    pub value: U64,
+    pub script_pubkey: Vec<u8>,
+}
+
+/// Unsigned transaction of a withdrawal leg, handed to the signing callback.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct BitcoinSpend {
+    pub inputs: Vec<Utxo>,
+    pub outputs: Vec<BitcoinOutput>,
+}
+
+impl BitcoinSpend {
+    fn to_omni_transaction(&self) -> BitcoinTransaction {
+        let inputs = self
+            .inputs
+            .iter()
+            .map(|utxo| TxIn {
+                previous_output: OutPoint {
+                    txid: Txid(Hash(txid_bytes(&utxo.txid))),
+                    vout: utxo.vout,
+                },
+                script_sig: ScriptBuf::default(),
+                sequence: Sequence(SEQUENCE),
+                witness: Witness::default(),
+            })
+            .collect();
+        let outputs = self
+            .outputs
+            .iter()
+            .map(|output| TxOut {
+                value: Amount::from_sat(output.value.0),
+                script_pubkey: ScriptBuf(output.script_pubkey.clone()),
+            })
+            .collect();
+
+        TransactionBuilder::new::<BITCOIN>()
+            .version(Version::Two)
+            .lock_time(LockTime::from_height(0).unwrap())
+            .inputs(inputs)
+            .outputs(outputs)
+            .build()
+    }
+
+    /// Digest the treasury key signs to spend input `index`, owned by `pubkey_hash`.
+    fn sighash(&self, index: usize, pubkey_hash: &[u8; 20]) -> [u8; 32] {
+        let preimage = self.to_omni_transaction().build_for_signing_segwit(
+            EcdsaSighashType::All,
+            index,
+            &ScriptBuf(p2wpkh_script_code(pubkey_hash)),
+            self.inputs[index].value.0,
+        );
+        sha256d(&preimage)
+    }
+
+    /// Id of the transaction, which commits to everything but the witnesses.
+    fn txid(&self) -> String {
+        let mut serialized = 2u32.to_le_bytes().to_vec();
+        serialized.push(self.inputs.len() as u8);
+        for utxo in &self.inputs {
+            serialized.extend(txid_bytes(&utxo.txid));
+            serialized.extend(utxo.vout.to_le_bytes());
+            serialized.push(0);
+            serialized.extend(SEQUENCE.to_le_bytes());
+        }
+        serialized.push(self.outputs.len() as u8);
+        for output in &self.outputs {
+            serialized.extend(output.value.0.to_le_bytes());
+            serialized.push(output.script_pubkey.len() as u8);
+            serialized.extend(&output.script_pubkey);
+        }
+        serialized.extend(0u32.to_le_bytes());
+
+        let mut txid = sha256d(&serialized);
+        txid.reverse();
+        hex::encode(txid)
+    }
+}
+
+/// RIPEMD-160 of the SHA-256 of a compressed public key, what P2WPKH outputs pay to.
+pub fn pubkey_hash(compressed_public_key: &[u8]) -> [u8; 20] {
+    env::ripemd160_array(&env::sha256_array(compressed_public_key))
+}
+
+/// Output script paying to a version 0 witness program.
+pub fn segwit_v0_script(program: &[u8]) -> Vec<u8> {
+    let mut script = vec![0x00, program.len() as u8];
+    script.extend_from_slice(program);
+    script
+}
+
+/// Script code BIP-143 signs P2WPKH inputs with, the P2PKH script of the key.
+fn p2wpkh_script_code(pubkey_hash: &[u8; 20]) -> Vec<u8> {
+    let mut script = vec![0x76, 0xa9, 0x14];
+    script.extend_from_slice(pubkey_hash);
+    script.extend_from_slice(&[0x88, 0xac]);
+    script
+}
+
+/// DER encoding of a signature, as witnesses carry it.
+fn der_signature(r: &[u8], s: &[u8]) -> Vec<u8> {
+    let der_integer = |value: &[u8]| {
+        let start = value
+            .iter()
+            .position(|byte| *byte != 0)
+            .unwrap_or(value.len());
+        let mut value = value[start..].to_vec();
+        // A set top bit would make the integer negative
+        if value.first().is_none_or(|byte| byte & 0x80 != 0) {
+            value.insert(0, 0);
+        }
+        [vec![0x02, value.len() as u8], value].concat()
+    };
+    let body = [der_integer(r), der_integer(s)].concat();
+    [vec![0x30, body.len() as u8], body].concat()
+}
+
+/// Transaction id as shown by explorers to the byte order transactions use.
+fn txid_bytes(txid: &str) -> [u8; 32] {
+    let mut bytes: [u8; 32] = hex::decode(txid)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .unwrap_or_else(|| env::panic_str(&format!("Invalid txid {}", txid)));
+    bytes.reverse();
+    bytes
+}
+
+fn sha256d(data: &[u8]) -> [u8; 32] {
+    env::sha256_array(&env::sha256_array(data))
+}
+
+fn vsize(inputs: usize, outputs: usize) -> u64 {
+    TX_OVERHEAD_VBYTES + INPUT_VBYTES * inputs as u64 + OUTPUT_VBYTES * outputs as u64
+}
+
+/// Satoshis a transaction spending `inputs` outputs needs to pay `amount` and its fee, with
+/// change.
+fn amount_with_fee(amount: u64, fee_rate: u64, inputs: usize) -> u64 {
+    fee_rate
+        .checked_mul(vsize(inputs, 2))
+        .and_then(|fee| fee.checked_add(amount))
+        .unwrap_or_else(|| env::panic_str("Fee of the Bitcoin transaction overflows"))
+}
+
+impl Contract {
+    /// Builds the transaction paying a Bitcoin leg out of its treasury and asks the MPC signer
+    /// to sign each of its inputs.
+    pub(crate) fn create_and_sign_bitcoin_withdrawal(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        leg: &WithdrawalLeg,
+        hrp: &str,
+        network_details: NetworkDetails,
+    ) -> Promise {
+        let destination = bech32::decode_segwit_v0(hrp, &leg.destination).unwrap_or_else(|err| {
+            env::panic_str(&format!("Invalid destination {}: {}", leg.destination, err))
+        });
+        let amount = u64::try_from(leg.amount.0)
+            .unwrap_or_else(|_| env::panic_str("Amount exceeds the Bitcoin supply"));
+        let fee_rate = u64::try_from(
+            self.internal_gas_fees(&network_details, CallType::NativeTransfer)
+                .max_fee_per_gas,
+        )
+        .unwrap_or_else(|_| env::panic_str("Fee rate is too high for a Bitcoin transaction"));
+        let treasury_hash = pubkey_hash(&self.internal_treasury_key(&leg.treasury_path).public_key);
+
+        let spent = if leg.inputs.is_empty() {
+            self.internal_pick_utxos(withdrawal_id, leg_index, amount, fee_rate)
+        } else {
+            // Signing the leg again spends the same outputs, so at most one of its transactions
+            // can be mined
+            leg.inputs.clone()
+        };
+        let inputs = spent.len();
+        let total = spent
+            .iter()
+            .fold(0u64, |total, utxo| total.saturating_add(utxo.value.0));
+        let needed = amount_with_fee(amount, fee_rate, inputs);
+        assert!(
+            total >= needed,
+            "Outputs of the withdrawal don't cover its fee"
+        );
+
+        let mut outputs = vec![BitcoinOutput {
+            value: U64(amount),
+            script_pubkey: segwit_v0_script(&destination),
+        }];
+        let change = total - needed;
+        if change >= DUST_LIMIT {
+            outputs.push(BitcoinOutput {
+                value: U64(change),
+                script_pubkey: segwit_v0_script(&treasury_hash),
+            });
+        }
+        let spend = BitcoinSpend {
+            inputs: spent,
+            outputs,
+        };
+
+        let sign = |index| {
+            mpc::ext(MPC_CONTRACT_ACCOUNT_ID.parse().unwrap())
+                .with_static_gas(LEG_CALL_GAS)
+                .sign(SignRequest {
+                    payload: spend.sighash(index, &treasury_hash).to_vec(),
+                    path: leg.treasury_path.clone(),
+                    key_version: 0,
+                })
+        };
+        (1..inputs)
+            .fold(sign(0), |promise, index| promise.and(sign(index)))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(BITCOIN_CALLBACK_GAS)
+                    .bitcoin_sign_callback(withdrawal_id, leg_index, spend),
+            )
+    }
+
+    /// Takes the oldest outputs of the treasury that cover `amount` and the fee of a transaction
+    /// with change out of its set, and keeps them on the leg for every time it is signed.
+    fn internal_pick_utxos(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        amount: u64,
+        fee_rate: u64,
+    ) -> Vec<Utxo> {
+        let leg = self.internal_leg(withdrawal_id, leg_index);
+        let key = nonce_key(leg.chain_id, &leg.treasury_path);
+        let mut utxos = self.utxos.get(&key).cloned().unwrap_or_default();
+        let mut total = 0;
+        let mut inputs = 0;
+        while total < amount_with_fee(amount, fee_rate, inputs) {
+            let utxo = utxos.get(inputs).unwrap_or_else(|| {
+                env::panic_str("Treasury outputs don't cover the withdrawal and its fee")
+            });
+            total = total.saturating_add(utxo.value.0);
+            inputs += 1;
+        }
+        assert!(
+            inputs <= MAX_INPUTS,
+            "Withdrawal needs more than {} outputs, the treasury must be consolidated",
+            MAX_INPUTS
+        );
+
+        let picked: Vec<Utxo> = utxos.drain(..inputs).collect();
+        self.utxos.insert(key.clone(), utxos);
+        self.held_utxos
+            .entry(key)
+            .or_default()
+            .extend(picked.iter().cloned());
+        self.withdrawals
+            .get_mut(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+            .legs[leg_index as usize]
+            .inputs = picked.clone();
+        picked
+    }
+
+    /// Stops holding the outputs a leg picked, once it is confirmed or refunded.
+    pub(crate) fn internal_release_utxos(&mut self, leg: &WithdrawalLeg) {
+        if leg.inputs.is_empty() {
+            return;
+        }
+        let key = nonce_key(leg.chain_id, &leg.treasury_path);
+        for outputs in [&mut self.held_utxos, &mut self.spent_utxos] {
+            let Some(held) = outputs.get_mut(&key) else {
+                continue;
+            };
+            held.retain(|utxo| !leg.inputs.contains(utxo));
+            if held.is_empty() {
+                outputs.remove(&key);
+            }
+        }
+    }
+
+    /// Whether the last `report_utxos` left out every output a leg picked, so no transaction
+    /// signed for the leg can be mined anymore.
+    pub(crate) fn internal_utxos_spent(&self, leg: &WithdrawalLeg) -> bool {
+        let spent = self
+            .spent_utxos
+            .get(&nonce_key(leg.chain_id, &leg.treasury_path));
+        leg.inputs
+            .iter()
+            .all(|utxo| spent.is_some_and(|spent| spent.contains(utxo)))
+    }
+
+    /// Puts outputs picked by a withdrawal that was never signed back in the treasury's set.
+    fn internal_restore_utxos(&mut self, chain_id: u64, path: &str, spent: Vec<Utxo>) {
+        let utxos = self.utxos.entry(nonce_key(chain_id, path)).or_default();
+        let reported = std::mem::take(utxos);
+        *utxos = spent;
+        utxos.extend(reported);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Stores the signed transaction of a Bitcoin leg, or settles the leg as unsigned when any of
+    /// its inputs wasn't signed by the treasury. Its outputs return to the treasury's set when it
+    /// is refunded.
+    #[private]
+    pub fn bitcoin_sign_callback(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        spend: BitcoinSpend,
+    ) -> Option<Vec<u8>> {
+        let leg = self.internal_leg(withdrawal_id, leg_index);
+        let (chain_id, treasury_path) = (leg.chain_id, leg.treasury_path.clone());
+        let key = self.internal_treasury_key(&treasury_path);
+        let (public_key, treasury) = (key.public_key.clone(), key.evm_address);
+        let treasury_hash = pubkey_hash(&public_key);
+
+        let signatures: Result<Vec<_>, String> = (0..spend.inputs.len())
+            .map(|index| {
+                let sign_result = match env::promise_result(index as u64) {
+                    PromiseResult::Successful(result) => {
+                        serde_json::from_slice::<SignResult>(&result)
+                            .map_err(|_| "MPC returned an invalid signature".to_string())?
+                    }
+                    PromiseResult::Failed => return Err("MPC call failed".to_string()),
+                };
+                let sighash = spend.sighash(index, &treasury_hash);
+                signer::verify_signature(&sign_result, &sighash, &treasury)
+            })
+            .collect();
+        let signatures = match signatures {
+            Ok(signatures) => signatures,
+            Err(reason) => {
+                env::log_str(&format!(
+                    "Signing failed for withdrawal {} leg {}: {}",
+                    withdrawal_id, leg_index, reason
+                ));
+                if self.internal_settle_unsigned_leg(withdrawal_id, leg_index) {
+                    self.internal_restore_utxos(chain_id, &treasury_path, spend.inputs);
+                }
+                return None;
+            }
+        };
+
+        let mut omni_tx = spend.to_omni_transaction();
+        let mut signed_tx = Vec::new();
+        for (index, signature) in signatures.iter().enumerate() {
+            let mut der = der_signature(&signature.r, &signature.s);
+            der.push(0x01); // SIGHASH_ALL
+            signed_tx = omni_tx.build_with_witness(
+                index,
+                vec![der, public_key.clone()],
+                TransactionType::P2WPKH,
+            );
+        }
+
+        self.internal_record_signed_tx(withdrawal_id, leg_index, signed_tx.clone(), spend.txid());
+        self.internal_sign_leg(withdrawal_id, leg_index);
+        Some(signed_tx)
+    }
+
+    /// Replaces the unspent outputs of the treasury derived from `path` on a Bitcoin chain.
+    ///
+    /// Outputs spent by transactions still in the mempool must be left out, or withdrawals will
+    /// try to spend them again. Outputs held by legs that are still to be paid are left out
+    /// here, as their transactions may not have been broadcast yet; the held outputs missing
+    /// from `utxos` are recorded as spent.
+    pub fn report_utxos(&mut self, chain_id: u64, path: String, mut utxos: Vec<Utxo>) {
+        self.assert_owner_or_relayer();
+        assert!(
+            matches!(
+                self.internal_chain(chain_id).kind,
+                ChainKind::Bitcoin { .. }
+            ),
+            "Chain {} is not a Bitcoin chain",
+            chain_id
+        );
+        // Reject malformed ids now rather than when a withdrawal spends them
+        for utxo in &utxos {
+            txid_bytes(&utxo.txid);
+        }
+
+        let key = nonce_key(chain_id, &path);
+        if let Some(held) = self.held_utxos.get(&key) {
+            let spent: Vec<Utxo> = held
+                .iter()
+                .filter(|utxo| !utxos.contains(utxo))
+                .cloned()
+                .collect();
+            utxos.retain(|utxo| !held.contains(utxo));
+            if spent.is_empty() {
+                self.spent_utxos.remove(&key);
+            } else {
+                self.spent_utxos.insert(key.clone(), spent);
+            }
+        }
+        let count = utxos.len() as u32;
+        self.utxos.insert(key, utxos);
+        FundEvent::UtxosReported {
+            chain_id,
+            path,
+            count,
+        }
+        .emit();
+    }
+
+    pub fn get_utxos(&self, chain_id: u64, path: String) -> Vec<Utxo> {
+        self.utxos
+            .get(&nonce_key(chain_id, &path))
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hash of the compressed generator point, the key of private key 1.
+    const PUBKEY_HASH: &str = "751e76e8199196d454941c45d1b3a323f1433bd6";
+
+    fn hash160(hex_hash: &str) -> [u8; 20] {
+        hex::decode(hex_hash).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn test_pubkey_hash() {
+        let generator =
+            hex::decode("0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798")
+                .unwrap();
+        assert_eq!(hex::encode(pubkey_hash(&generator)), PUBKEY_HASH);
+        assert_eq!(
+            hex::encode(segwit_v0_script(&hash160(PUBKEY_HASH))),
+            "0014751e76e8199196d454941c45d1b3a323f1433bd6"
+        );
+    }
+
+    #[test]
+    fn test_der_signature() {
+        let r = hex::decode("e6bf3f02fa1d3e19d86f434c46e698cdddd710c2b26a2ab78600ae333a3fdf5e")
+            .unwrap();
+        let s = hex::decode("0002d23b5fb20bbfe3360122dfa0b502a35de6e85eb58457e3cad9a415b6f2c6")
+            .unwrap();
+        assert_eq!(
+            hex::encode(der_signature(&r, &s)),
+            concat!(
+                "3044022100e6bf3f02fa1d3e19d86f434c46e698cdddd710c2b26a2ab78600ae333a3fdf5e021f",
+                "02d23b5fb20bbfe3360122dfa0b502a35de6e85eb58457e3cad9a415b6f2c6",
+            )
+        );
+    }
+
+    #[test]
+    fn test_txid() {
+        let spend = BitcoinSpend {
+            inputs: vec![Utxo {
+                txid: "9f96ade4b41d5433f4eda31e1738ec2b36f6e7d1420d94a6af99801a88f7f7ff"
+                    .to_string(),
+                vout: 0,
+                value: U64(100_000),
+            }],
+            outputs: vec![
+                BitcoinOutput {
+                    value: U64(60_000),
+                    script_pubkey: segwit_v0_script(&hash160(PUBKEY_HASH)),
+                },
+                BitcoinOutput {
+                    value: U64(39_000),
+                    script_pubkey: segwit_v0_script(&hash160(
+                        "1d0f172a0ecb48aee1be1f2687d2963ae33f71a1",
+                    )),
+                },
+            ],
+        };
+        assert_eq!(
+            spend.txid(),
+            "6989c94a3f255326866b0152d4bc77a970764739e1b66ced9d675c605e700b32"
+        );
+    }
+
+    // The native P2WPKH example of BIP-143, whose version, lock time and sequences differ from
+    // the ones withdrawals use
+    #[test]
+    fn test_segwit_sighash() {
+        let input = |txid: &str, vout, sequence| TxIn {
+            previous_output: OutPoint {
+                txid: Txid(Hash(hex::decode(txid).unwrap().try_into().unwrap())),
+                vout,
+            },
+            script_sig: ScriptBuf::default(),
+            sequence: Sequence(sequence),
+            witness: Witness::default(),
+        };
+        let output = |value, script: &str| TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf(hex::decode(script).unwrap()),
+        };
+        let tx = TransactionBuilder::new::<BITCOIN>()
+            .version(Version::One)
+            .lock_time(LockTime::from_height(17).unwrap())
+            .inputs(vec![
+                input(
+                    "fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f",
+                    0,
+                    0xffff_ffee,
+                ),
+                input(
+                    "ef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a",
+                    1,
+                    0xffff_ffff,
+                ),
+            ])
+            .outputs(vec![
+                output(
+                    112_340_000,
+                    "76a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac",
+                ),
+                output(
+                    223_450_000,
+                    "76a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac",
+                ),
+            ])
+            .build();
+
+        let preimage = tx.build_for_signing_segwit(
+            EcdsaSighashType::All,
+            1,
+            &ScriptBuf(p2wpkh_script_code(&hash160(
+                "1d0f172a0ecb48aee1be1f2687d2963ae33f71a1",
+            ))),
+            600_000_000,
+        );
+        assert_eq!(
+            hex::encode(sha256d(&preimage)),
+            "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670"
+        );
+    }
+
+    #[test]
+    fn test_amount_with_fee() {
+        // 11 + 2 * 68 + 2 * 31 vbytes at 2 sats each
+        assert_eq!(amount_with_fee(300, 2, 2), 718);
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee of the Bitcoin transaction overflows")]
+    fn test_fee_overflow_is_rejected() {
+        amount_with_fee(1, u64::MAX / 100, 2);
+    }
+}
@@ -0,0 +1,257 @@
+//! Circuit breaker on the prices aggregated from the oracles.
+//!
+//! Every asset keeps the last price the breaker accepted. A new price may only move away from
+//! it by the guard's deviation for each window of time between the two, up to the guard's
+//! number of windows; a larger move halts everything that depends on prices until the owner or
+//! a guardian acknowledges it.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::events::FundEvent;
+use crate::math::U256;
+use crate::withdrawal::BASIS_POINTS;
+use crate::{Contract, ContractExt, PriceFeedInfo};
+
+/// How far prices may move before the circuit breaker halts them.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceGuard {
+    /// Largest move from the accepted price in each window, in basis points
+    pub max_deviation_bps: u32,
+    pub window_sec: u64,
+    /// Most windows the move may grow over, however long prices went without an update
+    pub max_windows: u32,
+}
+
+impl Default for PriceGuard {
+    fn default() -> Self {
+        Self {
+            max_deviation_bps: 2_000,
+            window_sec: 3_600,
+            max_windows: 6,
+        }
+    }
+}
+
+impl PriceGuard {
+    fn assert_valid(&self) {
+        assert!(
+            self.max_deviation_bps > 0 && self.window_sec > 0 && self.max_windows > 0,
+            "Price guard deviation, window and windows must be positive"
+        );
+    }
+
+    /// Whether `price` is close enough to `accepted`, the allowed move growing with every
+    /// window started between their oracle timestamps up to `max_windows` of them.
+    fn allows(&self, accepted: &PriceFeedInfo, price: &PriceFeedInfo) -> bool {
+        let elapsed = price.last_updated.saturating_sub(accepted.last_updated);
+        let windows = elapsed
+            .div_ceil(self.window_sec.saturating_mul(1_000_000_000))
+            .clamp(1, u64::from(self.max_windows));
+
+        let decimals = accepted.decimals.max(price.decimals);
+        let scaled = |feed: &PriceFeedInfo| {
+            U256::from(10u64)
+                .checked_pow(U256::from(decimals - feed.decimals))
+                .and_then(|scale| scale.checked_mul(U256::from(feed.price.0)))
+        };
+        let (Some(old), Some(new)) = (scaled(accepted), scaled(price)) else {
+            return false;
+        };
+        let deviation = if new > old { new - old } else { old - new };
+
+        let Some(deviation_bps) = deviation.checked_mul(U256::from(BASIS_POINTS)) else {
+            return false;
+        };
+        (U256::from(self.max_deviation_bps) * U256::from(windows))
+            .checked_mul(old)
+            .is_none_or(|limit| deviation_bps <= limit)
+    }
+}
+
+/// A price the circuit breaker refused, which halts prices until it is acknowledged.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PriceHalt {
+    /// Last price accepted for the asset
+    pub accepted: PriceFeedInfo,
+    /// Price that moved too far from it
+    pub rejected: PriceFeedInfo,
+    pub halted_at: u64,
+}
+
+impl Contract {
+    /// Runs freshly aggregated prices through the circuit breaker.
+    ///
+    /// Prices within the guard become the accepted ones, and the first that isn't halts prices.
+    /// Accepted prices are left as they are while halted.
+    pub(crate) fn internal_guard_prices(&mut self, price_feeds: &[PriceFeedInfo]) {
+        for feed in price_feeds {
+            if self.price_halt.is_some() {
+                return;
+            }
+            match self.accepted_prices.get(&feed.asset_id).cloned() {
+                Some(accepted) if !self.price_guard.allows(&accepted, feed) => {
+                    FundEvent::PricesHalted {
+                        asset_id: feed.asset_id.clone(),
+                    }
+                    .emit();
+                    self.price_halt = Some(PriceHalt {
+                        accepted,
+                        rejected: feed.clone(),
+                        halted_at: env::block_timestamp(),
+                    });
+                }
+                _ => self.internal_accept_price(feed.clone()),
+            }
+        }
+    }
+
+    pub(crate) fn assert_prices_not_halted(&self) {
+        assert!(
+            self.price_halt.is_none(),
+            "Prices are halted until the circuit breaker is acknowledged"
+        );
+    }
+
+    fn assert_owner_or_guardian(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || self.guardians.contains(&caller),
+            "Only the owner or a guardian can call this method"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_guardian(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.guardians.insert(account_id.clone()),
+            "Account is already a guardian"
+        );
+        FundEvent::GuardianAdded { account_id }.emit();
+    }
+
+    pub fn remove_guardian(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.guardians.remove(&account_id),
+            "Account is not a guardian"
+        );
+        FundEvent::GuardianRemoved { account_id }.emit();
+    }
+
+    pub fn get_guardians(&self) -> Vec<AccountId> {
+        self.guardians.iter().cloned().collect()
+    }
+
+    pub fn set_price_guard(&mut self, guard: PriceGuard) {
+        self.assert_owner();
+        guard.assert_valid();
+        FundEvent::PriceGuardUpdated {
+            max_deviation_bps: guard.max_deviation_bps,
+            window_sec: guard.window_sec,
+            max_windows: guard.max_windows,
+        }
+        .emit();
+        self.price_guard = guard;
+    }
+
+    pub fn get_price_guard(&self) -> PriceGuard {
+        self.price_guard.clone()
+    }
+
+    /// Resumes prices halted by the circuit breaker.
+    ///
+    /// With `accept_price` the move was genuine and the rejected price becomes the accepted one;
+    /// without, the last accepted price stays the reference and the move halts prices again if
+    /// the oracles keep reporting it.
+    pub fn acknowledge_price_halt(&mut self, accept_price: bool) {
+        self.assert_owner_or_guardian();
+        let halt = self
+            .price_halt
+            .take()
+            .unwrap_or_else(|| env::panic_str("Prices are not halted"));
+        if accept_price {
+            self.internal_accept_price(halt.rejected);
+        }
+        FundEvent::PricesResumed {
+            account_id: env::predecessor_account_id(),
+            price_accepted: accept_price,
+        }
+        .emit();
+    }
+
+    pub fn get_price_halt(&self) -> Option<PriceHalt> {
+        self.price_halt.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::json_types::U128;
+
+    const MINUTE: u64 = 60 * 1_000_000_000;
+
+    fn feed(price: u128, decimals: u8, last_updated: u64) -> PriceFeedInfo {
+        PriceFeedInfo {
+            asset_id: "weth.fakes.testnet".to_string(),
+            price: U128(price),
+            decimals,
+            last_updated,
+            sources: Vec::new(),
+        }
+    }
+
+    fn guard() -> PriceGuard {
+        PriceGuard {
+            max_deviation_bps: 1_000,
+            window_sec: 3_600,
+            max_windows: 4,
+        }
+    }
+
+    #[test]
+    fn test_guard_allows_moves_within_deviation() {
+        let accepted = feed(3_000, 0, 0);
+        assert!(guard().allows(&accepted, &feed(3_300, 0, MINUTE)));
+        assert!(guard().allows(&accepted, &feed(2_700, 0, MINUTE)));
+        assert!(!guard().allows(&accepted, &feed(3_301, 0, MINUTE)));
+        assert!(!guard().allows(&accepted, &feed(2_699, 0, MINUTE)));
+    }
+
+    #[test]
+    fn test_guard_deviation_grows_per_window() {
+        let accepted = feed(3_000, 0, 0);
+        // Three windows have started after two hours and a minute
+        let later = 121 * MINUTE;
+        assert!(guard().allows(&accepted, &feed(3_900, 0, later)));
+        assert!(!guard().allows(&accepted, &feed(3_901, 0, later)));
+        // Prices older than the accepted one get a single window
+        assert!(!guard().allows(&feed(3_000, 0, later), &feed(3_900, 0, 0)));
+    }
+
+    #[test]
+    fn test_guard_deviation_is_capped_after_long_gaps() {
+        let accepted = feed(3_000, 0, 0);
+        // A month without prices still allows only four windows of 10%
+        let month = 30 * 24 * 60 * MINUTE;
+        assert!(guard().allows(&accepted, &feed(4_200, 0, month)));
+        assert!(!guard().allows(&accepted, &feed(4_201, 0, month)));
+        assert!(!guard().allows(&accepted, &feed(30_000, 0, u64::MAX)));
+    }
+
+    #[test]
+    fn test_guard_compares_across_decimals() {
+        let accepted = feed(300_000, 2, 0);
+        assert!(guard().allows(&accepted, &feed(3_300_000, 3, MINUTE)));
+        assert!(!guard().allows(&accepted, &feed(3_300_001, 3, MINUTE)));
+        // Decimals no price can be scaled to halt prices
+        assert!(!guard().allows(&accepted, &feed(3_000, 255, MINUTE)));
+    }
+}
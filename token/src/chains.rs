@@ -0,0 +1,213 @@
+use k256::AffinePoint;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::aurora;
+use crate::bech32;
+use crate::bitcoin;
+use crate::events::FundEvent;
+use crate::fees::FeePolicy;
+use crate::kdf;
+use crate::transaction::TxType;
+use crate::{Contract, ContractExt};
+
+/// How the treasury of a chain holds and sends its assets.
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, Default, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum ChainKind {
+    #[default]
+    Evm,
+    /// Bitcoin network whose segwit addresses start with `hrp`, e.g. `bc` or `tb`. Its fee
+    /// policy and quotes are in satoshis per virtual byte, set as the max fee per gas
+    Bitcoin { hrp: String },
+    /// Aurora, sent from the fund's own Aurora address by calling the engine at `engine` rather
+    /// than with signed transactions. The treasury path only names its nonces
+    AuroraEngine { engine: AccountId },
+}
+
+/// Key of a treasury, derived when its chain is registered so that callbacks only read it.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TreasuryKey {
+    /// SEC1 compressed public key
+    pub public_key: Vec<u8>,
+    pub evm_address: [u8; 20],
+}
+
+/// A chain the fund holds assets on and withdraws them from.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ChainConfig {
+    pub chain_id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub kind: ChainKind,
+    /// MPC derivation path of the treasury address holding the fund's assets on this chain
+    pub treasury_path: String,
+    pub fee_policy: FeePolicy,
+    /// Transaction type the chain accepts, EIP-1559 unless set
+    #[serde(default)]
+    pub tx_type: TxType,
+    /// Registry ids of the assets that can be withdrawn on this chain
+    pub supported_assets: Vec<String>,
+}
+
+impl Contract {
+    /// Chain `asset_id` is withdrawn on, per the asset and chain registries.
+    pub(crate) fn withdrawal_chain(&self, asset_id: &str) -> &ChainConfig {
+        let asset = self
+            .asset_registry
+            .get(asset_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Asset {} is not registered", asset_id)));
+        self.chains
+            .get(&asset.chain_id)
+            .filter(|chain| chain.supported_assets.contains(&asset.asset_id))
+            .unwrap_or_else(|| {
+                env::panic_str(&format!(
+                    "Asset {} can't be withdrawn on chain {}",
+                    asset.asset_id, asset.chain_id
+                ))
+            })
+    }
+
+    pub(crate) fn internal_chain(&self, chain_id: u64) -> &ChainConfig {
+        self.chains
+            .get(&chain_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Chain {} is not registered", chain_id)))
+    }
+
+    /// Key the MPC signer uses for this contract and `path`.
+    pub(crate) fn derived_public_key(&self, path: &str) -> AffinePoint {
+        let root_key = kdf::parse_root_key(&self.mpc_public_key);
+        let epsilon = kdf::derive_epsilon(&env::current_account_id(), path);
+        kdf::derive_public_key(&root_key, epsilon)
+    }
+
+    /// EVM address of the key the MPC signer uses for this contract and `path`.
+    pub(crate) fn derived_address(&self, path: &str) -> [u8; 20] {
+        kdf::evm_address(&self.derived_public_key(path))
+    }
+
+    /// Derives the key of the treasury at `path` and stores it for `internal_treasury_key`.
+    pub(crate) fn internal_cache_treasury_key(&mut self, path: &str) {
+        let public_key = self.derived_public_key(path);
+        self.treasury_keys.insert(
+            path.to_string(),
+            TreasuryKey {
+                public_key: kdf::compressed_public_key(&public_key),
+                evm_address: kdf::evm_address(&public_key),
+            },
+        );
+    }
+
+    /// Key of the treasury at `path`, as derived when its chain was registered.
+    pub(crate) fn internal_treasury_key(&self, path: &str) -> &TreasuryKey {
+        self.treasury_keys
+            .get(path)
+            .unwrap_or_else(|| env::panic_str(&format!("No treasury key for {}", path)))
+    }
+
+    fn assert_valid_chain(&self, chain: &ChainConfig) {
+        assert!(
+            !chain.treasury_path.is_empty(),
+            "Treasury path can't be empty"
+        );
+        chain.fee_policy.assert_valid();
+        if let ChainKind::Bitcoin { hrp } = &chain.kind {
+            assert!(!hrp.is_empty(), "Bitcoin network prefix can't be empty");
+        }
+        for asset_id in &chain.supported_assets {
+            let asset = self.asset_registry.get(asset_id).unwrap_or_else(|| {
+                env::panic_str(&format!("Asset {} is not registered", asset_id))
+            });
+            assert_eq!(
+                asset.chain_id, chain.chain_id,
+                "Asset {} is registered on another chain",
+                asset_id
+            );
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_chain(&mut self, chain: ChainConfig) {
+        self.assert_owner();
+        assert!(
+            !self.chains.contains_key(&chain.chain_id),
+            "Chain is already registered"
+        );
+        self.assert_valid_chain(&chain);
+
+        let chain_id = chain.chain_id;
+        self.internal_cache_treasury_key(&chain.treasury_path);
+        self.chains.insert(chain_id, chain);
+        FundEvent::ChainAdded { chain_id }.emit();
+    }
+
+    pub fn update_chain(&mut self, chain: ChainConfig) {
+        self.assert_owner();
+        assert!(
+            self.chains.contains_key(&chain.chain_id),
+            "Chain is not registered"
+        );
+        self.assert_valid_chain(&chain);
+
+        let chain_id = chain.chain_id;
+        self.internal_cache_treasury_key(&chain.treasury_path);
+        self.chains.insert(chain_id, chain);
+        FundEvent::ChainUpdated { chain_id }.emit();
+    }
+
+    pub fn remove_chain(&mut self, chain_id: u64) {
+        self.assert_owner();
+        let chain = self.internal_chain(chain_id);
+        let held = self
+            .assets
+            .iter()
+            .any(|held| chain.supported_assets.contains(&held.asset_id));
+        assert!(!held, "Chain has assets held by the fund");
+
+        self.chains.remove(&chain_id);
+        FundEvent::ChainRemoved { chain_id }.emit();
+    }
+
+    pub fn get_chain(&self, chain_id: u64) -> Option<ChainConfig> {
+        self.chains.get(&chain_id).cloned()
+    }
+
+    /// Address of the treasury holding the fund's assets on `chain_id`: checksummed on EVM
+    /// chains, the fund's own address when Aurora is called through its engine and the P2WPKH
+    /// address on Bitcoin.
+    pub fn get_treasury_address(&self, chain_id: u64) -> String {
+        let chain = self.internal_chain(chain_id);
+        match &chain.kind {
+            ChainKind::Evm => kdf::to_checksum_address(&self.derived_address(&chain.treasury_path)),
+            ChainKind::AuroraEngine { .. } => {
+                kdf::to_checksum_address(&aurora::implicit_address(&env::current_account_id()))
+            }
+            ChainKind::Bitcoin { hrp } => {
+                let public_key =
+                    kdf::compressed_public_key(&self.derived_public_key(&chain.treasury_path));
+                bech32::encode_segwit_v0(hrp, &bitcoin::pubkey_hash(&public_key))
+            }
+        }
+    }
+
+    /// Checksummed address the MPC signer derives for this contract and `path`.
+    pub fn get_derived_address(&self, path: String) -> String {
+        kdf::to_checksum_address(&self.derived_address(&path))
+    }
+
+    pub fn get_chains(&self, from_index: Option<u32>, limit: Option<u32>) -> Vec<ChainConfig> {
+        self.chains
+            .values()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u32::MAX) as usize)
+            .cloned()
+            .collect()
+    }
+}
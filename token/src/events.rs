@@ -0,0 +1,104 @@
+use near_sdk::json_types::U128;
+use near_sdk::{near, AccountId};
+
+use crate::ledger::SignedTxStatus;
+use crate::twap::PricingMode;
+
+/// NEP-297 events emitted by the fund on top of the NEP-141 share events.
+#[near(event_json(standard = "nexusfi"))]
+pub enum FundEvent {
+    #[event_version("1.0.0")]
+    UsdcContractUpdated {
+        old_usdc_contract: AccountId,
+        new_usdc_contract: AccountId,
+    },
+    #[event_version("1.0.0")]
+    OracleAdded { account_id: AccountId },
+    #[event_version("1.0.0")]
+    OracleRemoved { account_id: AccountId },
+    #[event_version("1.0.0")]
+    OracleQuorumUpdated { quorum: u8 },
+    #[event_version("1.0.0")]
+    PriceMaxAgeUpdated { max_age_sec: u64 },
+    #[event_version("1.0.0")]
+    OracleMaxAgeUpdated { max_age_sec: u64 },
+    #[event_version("1.0.0")]
+    PricingModeUpdated { mode: PricingMode },
+    #[event_version("1.0.0")]
+    GuardianAdded { account_id: AccountId },
+    #[event_version("1.0.0")]
+    GuardianRemoved { account_id: AccountId },
+    #[event_version("1.0.0")]
+    PriceGuardUpdated {
+        max_deviation_bps: u32,
+        window_sec: u64,
+        max_windows: u32,
+    },
+    #[event_version("1.0.0")]
+    PricesHalted { asset_id: String },
+    #[event_version("1.0.0")]
+    PricesResumed {
+        account_id: AccountId,
+        price_accepted: bool,
+    },
+    #[event_version("1.0.0")]
+    AssetAdded { asset_id: String },
+    #[event_version("1.0.0")]
+    AssetUpdated { asset_id: String },
+    #[event_version("1.0.0")]
+    AssetRemoved { asset_id: String },
+    #[event_version("1.0.0")]
+    WithdrawalCreated {
+        withdrawal_id: u64,
+        account_id: AccountId,
+    },
+    #[event_version("1.0.0")]
+    WithdrawalLegSigned { withdrawal_id: u64, leg_index: u32 },
+    #[event_version("1.0.0")]
+    WithdrawalLegConfirmed { withdrawal_id: u64, leg_index: u32 },
+    #[event_version("1.0.0")]
+    WithdrawalLegFailed { withdrawal_id: u64, leg_index: u32 },
+    #[event_version("1.0.0")]
+    WithdrawalLegRefunded { withdrawal_id: u64, leg_index: u32 },
+    #[event_version("1.0.0")]
+    WithdrawalFinalized { withdrawal_id: u64 },
+    #[event_version("1.0.0")]
+    RelayerAdded { account_id: AccountId },
+    #[event_version("1.0.0")]
+    RelayerRemoved { account_id: AccountId },
+    #[event_version("1.0.0")]
+    NonceResynced {
+        chain_id: u64,
+        path: String,
+        old_nonce: u64,
+        new_nonce: u64,
+    },
+    #[event_version("1.0.0")]
+    FeePolicyUpdated { chain_id: u64 },
+    #[event_version("1.0.0")]
+    FeeQuoteUpdated {
+        chain_id: u64,
+        max_fee_per_gas: U128,
+        max_priority_fee_per_gas: U128,
+    },
+    #[event_version("1.0.0")]
+    ChainAdded { chain_id: u64 },
+    #[event_version("1.0.0")]
+    ChainUpdated { chain_id: u64 },
+    #[event_version("1.0.0")]
+    ChainRemoved { chain_id: u64 },
+    #[event_version("1.0.0")]
+    UtxosReported {
+        chain_id: u64,
+        path: String,
+        count: u32,
+    },
+    #[event_version("1.0.0")]
+    TransactionSigned {
+        tx_id: u64,
+        withdrawal_id: u64,
+        leg_index: u32,
+    },
+    #[event_version("1.0.0")]
+    TransactionStatusUpdated { tx_id: u64, status: SignedTxStatus },
+}
@@ -0,0 +1,223 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen};
+
+use crate::events::FundEvent;
+use crate::models::AccessList;
+use crate::{Contract, ContractExt, NetworkDetails};
+
+/// Most addresses and storage keys, counted together, the access list of a withdrawal may hold.
+pub const MAX_ACCESS_LIST_ITEMS: usize = 16;
+/// Intrinsic gas of an address in an access list, per EIP-2930
+const ACCESS_LIST_ADDRESS_GAS: u128 = 2_400;
+/// Intrinsic gas of a storage key in an access list, per EIP-2930
+const ACCESS_LIST_STORAGE_KEY_GAS: u128 = 1_900;
+
+/// Gas `access_list` adds to a transaction before it runs.
+fn access_list_gas(access_list: &AccessList) -> u128 {
+    let items = access_list.len()
+        + access_list
+            .iter()
+            .map(|(_, keys)| keys.len())
+            .sum::<usize>();
+    assert!(
+        items <= MAX_ACCESS_LIST_ITEMS,
+        "Access list can't hold more than {} addresses and storage keys",
+        MAX_ACCESS_LIST_ITEMS
+    );
+    access_list
+        .iter()
+        .map(|(_, keys)| ACCESS_LIST_ADDRESS_GAS + ACCESS_LIST_STORAGE_KEY_GAS * keys.len() as u128)
+        .sum()
+}
+
+/// Kind of transaction the treasury signs, each with its own default gas limit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CallType {
+    Erc20Transfer,
+    NativeTransfer,
+}
+
+/// Bounds on the fees of every transaction the treasury signs on a chain.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeePolicy {
+    pub max_fee_per_gas_cap: U128,
+    pub max_priority_fee_per_gas_cap: U128,
+    pub gas_limit_cap: U128,
+    /// Gas limit of an ERC-20 `transfer`, also the lowest one a withdrawal may ask for
+    pub erc20_transfer_gas_limit: U128,
+    /// Gas limit of a plain value transfer, 21_000 on most chains
+    pub native_transfer_gas_limit: U128,
+    /// How long a fee quote can be used for after it was posted
+    pub quote_max_age_sec: u64,
+}
+
+impl FeePolicy {
+    pub(crate) fn assert_valid(&self) {
+        assert!(
+            self.max_priority_fee_per_gas_cap.0 <= self.max_fee_per_gas_cap.0,
+            "Priority fee cap can't exceed the max fee cap"
+        );
+        assert!(
+            self.erc20_transfer_gas_limit.0 <= self.gas_limit_cap.0
+                && self.native_transfer_gas_limit.0 <= self.gas_limit_cap.0,
+            "Default gas limits can't exceed the gas limit cap"
+        );
+    }
+
+    fn default_gas_limit(&self, call_type: CallType) -> u128 {
+        match call_type {
+            CallType::Erc20Transfer => self.erc20_transfer_gas_limit.0,
+            CallType::NativeTransfer => self.native_transfer_gas_limit.0,
+        }
+    }
+}
+
+/// Current market fees of a chain, posted by a relayer.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct FeeQuote {
+    pub max_fee_per_gas: U128,
+    pub max_priority_fee_per_gas: U128,
+    /// Block timestamp of the update, in nanoseconds
+    pub updated_at: u64,
+}
+
+/// Fees a transaction is signed with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GasFees {
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+    pub gas_limit: u128,
+}
+
+impl Contract {
+    /// Fees for a `call_type` transaction on `network_details.chain_id`.
+    ///
+    /// Fees the caller leaves out come from the relayer quote, which must be fresh. Fees the
+    /// caller sets may outbid the quote up to the policy caps but never undercut it, so every
+    /// signed transaction can be mined and none can drain the treasury on fees. The gas of the
+    /// access list is added to the default gas limit, and counts against the cap like the rest.
+    pub(crate) fn internal_gas_fees(
+        &self,
+        network_details: &NetworkDetails,
+        call_type: CallType,
+    ) -> GasFees {
+        let chain_id = network_details.chain_id;
+        let policy = &self.internal_chain(chain_id).fee_policy;
+        let quote = self
+            .fee_quotes
+            .get(&chain_id)
+            .filter(|quote| {
+                env::block_timestamp().saturating_sub(quote.updated_at)
+                    <= policy.quote_max_age_sec.saturating_mul(1_000_000_000)
+            })
+            .unwrap_or_else(|| {
+                env::panic_str(&format!("Fee quote for chain {} is stale", chain_id))
+            });
+
+        let max_fee_per_gas = network_details
+            .max_fee_per_gas
+            .unwrap_or(quote.max_fee_per_gas.0);
+        assert!(
+            max_fee_per_gas >= quote.max_fee_per_gas.0,
+            "Max fee per gas is below the current quote"
+        );
+        assert!(
+            max_fee_per_gas <= policy.max_fee_per_gas_cap.0,
+            "Max fee per gas exceeds the fee policy"
+        );
+
+        let max_priority_fee_per_gas = network_details
+            .max_priority_fee_per_gas
+            .unwrap_or(quote.max_priority_fee_per_gas.0);
+        assert!(
+            max_priority_fee_per_gas >= quote.max_priority_fee_per_gas.0,
+            "Max priority fee per gas is below the current quote"
+        );
+        assert!(
+            max_priority_fee_per_gas <= policy.max_priority_fee_per_gas_cap.0
+                && max_priority_fee_per_gas <= max_fee_per_gas,
+            "Max priority fee per gas exceeds the fee policy"
+        );
+
+        let default_gas_limit =
+            policy.default_gas_limit(call_type) + access_list_gas(&network_details.access_list);
+        let gas_limit = network_details.gas_limit.unwrap_or(default_gas_limit);
+        assert!(
+            gas_limit >= default_gas_limit,
+            "Gas limit is too low for the transaction"
+        );
+        assert!(
+            gas_limit <= policy.gas_limit_cap.0,
+            "Gas limit exceeds the fee policy"
+        );
+
+        GasFees {
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+            gas_limit,
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn set_fee_policy(&mut self, chain_id: u64, policy: FeePolicy) {
+        self.assert_owner();
+        policy.assert_valid();
+        let chain = self
+            .chains
+            .get_mut(&chain_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Chain {} is not registered", chain_id)));
+        chain.fee_policy = policy;
+        FundEvent::FeePolicyUpdated { chain_id }.emit();
+    }
+
+    pub fn get_fee_policy(&self, chain_id: u64) -> Option<FeePolicy> {
+        self.chains
+            .get(&chain_id)
+            .map(|chain| chain.fee_policy.clone())
+    }
+
+    /// Posts the current fees of a chain, which must stay within its policy.
+    pub fn update_fee_quote(
+        &mut self,
+        chain_id: u64,
+        max_fee_per_gas: U128,
+        max_priority_fee_per_gas: U128,
+    ) {
+        self.assert_owner_or_relayer();
+        let policy = &self.internal_chain(chain_id).fee_policy;
+        assert!(
+            max_fee_per_gas.0 <= policy.max_fee_per_gas_cap.0
+                && max_priority_fee_per_gas.0 <= policy.max_priority_fee_per_gas_cap.0,
+            "Fee quote exceeds the fee policy"
+        );
+        assert!(
+            max_priority_fee_per_gas.0 <= max_fee_per_gas.0,
+            "Priority fee can't exceed the max fee"
+        );
+
+        self.fee_quotes.insert(
+            chain_id,
+            FeeQuote {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+                updated_at: env::block_timestamp(),
+            },
+        );
+        FundEvent::FeeQuoteUpdated {
+            chain_id,
+            max_fee_per_gas,
+            max_priority_fee_per_gas,
+        }
+        .emit();
+    }
+
+    pub fn get_fee_quote(&self, chain_id: u64) -> Option<FeeQuote> {
+        self.fee_quotes.get(&chain_id).cloned()
+    }
+}
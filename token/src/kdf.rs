@@ -0,0 +1,130 @@
+//! NEAR chain signatures key derivation.
+//!
+//! The MPC network holds a single root secp256k1 key. The key it signs with for a given
+//! `(predecessor, path)` is the root key tweaked by `epsilon * G`, where `epsilon` is the SHA3-256
+//! hash of the predecessor and path. Deriving the public key here lets the fund expose the
+//! addresses of its treasuries without anyone computing them off-chain.
+
+use k256::elliptic_curve::sec1::{FromEncodedPoint, ToEncodedPoint};
+use k256::elliptic_curve::PrimeField;
+use k256::{AffinePoint, EncodedPoint, ProjectivePoint, Scalar};
+use near_sdk::{env, AccountId, CurveType, PublicKey};
+use sha3::{Digest, Sha3_256};
+
+const EPSILON_DERIVATION_PREFIX: &str = "near-mpc-recovery v0.1.0 epsilon derivation:";
+
+/// Tweak the MPC network applies to its root key for `path` requested by `predecessor_id`.
+pub fn derive_epsilon(predecessor_id: &AccountId, path: &str) -> Scalar {
+    let derivation_path = format!("{}{},{}", EPSILON_DERIVATION_PREFIX, predecessor_id, path);
+    let hash: [u8; 32] = Sha3_256::digest(derivation_path.as_bytes()).into();
+    // Hashes outside of the field are about as likely as a collision, the MPC network rejects
+    // them just the same
+    Option::from(Scalar::from_repr(hash.into()))
+        .unwrap_or_else(|| env::panic_str("Derived epsilon falls outside of the field"))
+}
+
+/// Parses the MPC root key, a `secp256k1:` public key holding the uncompressed point.
+pub fn parse_root_key(public_key: &PublicKey) -> AffinePoint {
+    assert!(
+        public_key.curve_type() == CurveType::SECP256K1,
+        "MPC public key must be a secp256k1 key"
+    );
+    // NEAR keys drop the SEC1 tag of the uncompressed point, which is always 0x04
+    let mut sec1 = vec![0x04];
+    sec1.extend_from_slice(&public_key.as_bytes()[1..]);
+    EncodedPoint::from_bytes(&sec1)
+        .ok()
+        .and_then(|point| Option::from(AffinePoint::from_encoded_point(&point)))
+        .unwrap_or_else(|| env::panic_str("MPC public key is not a valid curve point"))
+}
+
+pub fn derive_public_key(root_key: &AffinePoint, epsilon: Scalar) -> AffinePoint {
+    AffinePoint::from(ProjectivePoint::GENERATOR * epsilon + ProjectivePoint::from(*root_key))
+}
+
+/// Last 20 bytes of the Keccak-256 hash of the uncompressed public key.
+pub fn evm_address(public_key: &AffinePoint) -> [u8; 20] {
+    let point = public_key.to_encoded_point(false);
+    let hash = env::keccak256_array(&point.as_bytes()[1..]);
+    let mut address = [0; 20];
+    address.copy_from_slice(&hash[12..]);
+    address
+}
+
+/// SEC1 compressed encoding of the public key, as Bitcoin uses it.
+pub fn compressed_public_key(public_key: &AffinePoint) -> Vec<u8> {
+    public_key.to_encoded_point(true).as_bytes().to_vec()
+}
+
+/// `0x`-prefixed EIP-55 mixed-case checksum encoding of `address`.
+pub fn to_checksum_address(address: &[u8; 20]) -> String {
+    let hex_address = hex::encode(address);
+    let hash = env::keccak256_array(hex_address.as_bytes());
+    let checksummed: String = hex_address
+        .chars()
+        .enumerate()
+        .map(|(i, c)| {
+            let nibble = (hash[i / 2] >> (if i % 2 == 0 { 4 } else { 0 })) & 0x0f;
+            if nibble >= 8 {
+                c.to_ascii_uppercase()
+            } else {
+                c
+            }
+        })
+        .collect();
+    format!("0x{}", checksummed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TESTNET_ROOT_KEY: &str = "secp256k1:4NfTiv3UsGahebgTaHyD9vF8KYKMBnfd6kh94mK6xv8fGBiJB8TBtFMP5WWXz6B89Ac1fbpzPwAvoyQebemHFwx3";
+
+    #[test]
+    fn test_address_of_private_key_one() {
+        let generator = AffinePoint::GENERATOR;
+        assert_eq!(
+            to_checksum_address(&evm_address(&generator)),
+            "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+        );
+    }
+
+    #[test]
+    fn test_derive_epsilon() {
+        let epsilon = derive_epsilon(&"fund.testnet".parse().unwrap(), "eth-treasury");
+        assert_eq!(
+            hex::encode(epsilon.to_repr()),
+            "2a73eb3581e37805be9f28feffba0da2a9ef233dde81ed60b157d0df7aca9462"
+        );
+    }
+
+    #[test]
+    fn test_derive_treasury_addresses() {
+        let root_key = parse_root_key(&TESTNET_ROOT_KEY.parse().unwrap());
+        let fund: AccountId = "fund.testnet".parse().unwrap();
+        let address = |path| {
+            let derived = derive_public_key(&root_key, derive_epsilon(&fund, path));
+            to_checksum_address(&evm_address(&derived))
+        };
+
+        assert_eq!(
+            address("eth-treasury"),
+            "0x20846Bf17144F7d6aE9f94be743259E216db2135"
+        );
+        assert_eq!(
+            address("aurora-treasury"),
+            "0x8965DfAC66e47E22a6eD8F7D4544FCe341C93728"
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "MPC public key must be a secp256k1 key")]
+    fn test_parse_root_key_rejects_ed25519() {
+        parse_root_key(
+            &"ed25519:6E8sCci9badyRkXb3JoRpBj5p8C6Tw41ELDZoiihKEtp"
+                .parse()
+                .unwrap(),
+        );
+    }
+}
@@ -0,0 +1,398 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::store::IterableSet;
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::events::FundEvent;
+use crate::withdrawal::WithdrawalStatus;
+use crate::{Contract, ContractExt, StorageKey};
+
+#[derive(
+    BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum SignedTxStatus {
+    /// Signed, waiting to be broadcast by a relayer
+    Pending,
+    /// Sent to the chain, waiting to be mined
+    Broadcast,
+    /// Mined successfully
+    Confirmed,
+    /// Mined but reverted, its nonce is used up
+    Reverted,
+    /// Left the mempool unmined, it can still be mined for as long as its nonce is unused
+    Dropped,
+}
+
+impl SignedTxStatus {
+    fn can_become(self, status: SignedTxStatus) -> bool {
+        use SignedTxStatus::*;
+        matches!(
+            (self, status),
+            (Pending, Broadcast)
+                | (Pending, Dropped)
+                | (Broadcast, Confirmed)
+                | (Broadcast, Reverted)
+                | (Broadcast, Dropped)
+                | (Dropped, Confirmed)
+                | (Dropped, Reverted)
+        )
+    }
+}
+
+/// Ids of the ledger's transactions by status, so that listing or pruning the transactions of
+/// one status never reads the others.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct SignedTxIndex {
+    pending: IterableSet<u64>,
+    broadcast: IterableSet<u64>,
+    confirmed: IterableSet<u64>,
+    reverted: IterableSet<u64>,
+    dropped: IterableSet<u64>,
+}
+
+impl SignedTxIndex {
+    pub(crate) fn new() -> Self {
+        let ids = |status| IterableSet::new(StorageKey::SignedTxsByStatus { status });
+        Self {
+            pending: ids(SignedTxStatus::Pending),
+            broadcast: ids(SignedTxStatus::Broadcast),
+            confirmed: ids(SignedTxStatus::Confirmed),
+            reverted: ids(SignedTxStatus::Reverted),
+            dropped: ids(SignedTxStatus::Dropped),
+        }
+    }
+
+    fn ids(&self, status: SignedTxStatus) -> &IterableSet<u64> {
+        match status {
+            SignedTxStatus::Pending => &self.pending,
+            SignedTxStatus::Broadcast => &self.broadcast,
+            SignedTxStatus::Confirmed => &self.confirmed,
+            SignedTxStatus::Reverted => &self.reverted,
+            SignedTxStatus::Dropped => &self.dropped,
+        }
+    }
+
+    fn ids_mut(&mut self, status: SignedTxStatus) -> &mut IterableSet<u64> {
+        match status {
+            SignedTxStatus::Pending => &mut self.pending,
+            SignedTxStatus::Broadcast => &mut self.broadcast,
+            SignedTxStatus::Confirmed => &mut self.confirmed,
+            SignedTxStatus::Reverted => &mut self.reverted,
+            SignedTxStatus::Dropped => &mut self.dropped,
+        }
+    }
+}
+
+/// A transaction signed by the MPC signer on behalf of a treasury.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct SignedTx {
+    pub id: u64,
+    /// Withdrawal leg the transaction pays, unknown for transactions signed before the ledger
+    pub withdrawal_id: Option<u64>,
+    pub leg_index: Option<u32>,
+    pub account_id: Option<AccountId>,
+    pub chain_id: u64,
+    /// Nonce of the treasury address, unset on chains without account nonces
+    pub nonce: Option<u64>,
+    /// Hash the transaction is known by on its chain, `0x`-prefixed on EVM chains and the txid
+    /// on Bitcoin
+    pub tx_hash: String,
+    /// Signed transaction, ready to be broadcast. Cleared once reverted or dropped, so that
+    /// relayers don't broadcast it again
+    pub raw_tx: Vec<u8>,
+    pub status: SignedTxStatus,
+    /// Block the transaction was mined in, once reported
+    pub block_number: Option<u64>,
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+impl Contract {
+    /// Adds the signed transaction of a withdrawal leg, known on its chain as `tx_hash`, to the
+    /// ledger and returns its id.
+    pub(crate) fn internal_record_signed_tx(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        raw_tx: Vec<u8>,
+        tx_hash: String,
+    ) -> u64 {
+        let account_id = self
+            .withdrawals
+            .get(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+            .account_id
+            .clone();
+        let leg = &mut self
+            .withdrawals
+            .get_mut(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+            .legs[leg_index as usize];
+        let (chain_id, nonce) = (leg.chain_id, leg.nonce);
+
+        let id = self.next_signed_tx_id;
+        self.next_signed_tx_id += 1;
+        leg.tx_id = Some(id);
+        let now = env::block_timestamp();
+        self.signed_txs.insert(
+            id,
+            SignedTx {
+                id,
+                withdrawal_id: Some(withdrawal_id),
+                leg_index: Some(leg_index),
+                account_id: Some(account_id),
+                chain_id,
+                nonce,
+                tx_hash,
+                raw_tx,
+                status: SignedTxStatus::Pending,
+                block_number: None,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+        self.signed_tx_index
+            .ids_mut(SignedTxStatus::Pending)
+            .insert(id);
+        FundEvent::TransactionSigned {
+            tx_id: id,
+            withdrawal_id,
+            leg_index,
+        }
+        .emit();
+        id
+    }
+
+    /// Adds a transaction signed before the ledger existed, with no withdrawal leg to settle,
+    /// and returns its id.
+    pub(crate) fn internal_import_signed_tx(
+        &mut self,
+        chain_id: u64,
+        nonce: u64,
+        raw_tx: Vec<u8>,
+    ) -> u64 {
+        let id = self.next_signed_tx_id;
+        self.next_signed_tx_id += 1;
+        let tx_hash = format!("0x{}", hex::encode(env::keccak256_array(&raw_tx)));
+        env::log_str(&format!(
+            "Imported signed transaction {} as {}",
+            tx_hash, id
+        ));
+        let now = env::block_timestamp();
+        self.signed_txs.insert(
+            id,
+            SignedTx {
+                id,
+                withdrawal_id: None,
+                leg_index: None,
+                account_id: None,
+                chain_id,
+                nonce: Some(nonce),
+                tx_hash,
+                raw_tx,
+                status: SignedTxStatus::Pending,
+                block_number: None,
+                created_at: now,
+                updated_at: now,
+            },
+        );
+        self.signed_tx_index
+            .ids_mut(SignedTxStatus::Pending)
+            .insert(id);
+        id
+    }
+
+    fn internal_set_signed_tx_status(
+        &mut self,
+        tx_id: u64,
+        status: SignedTxStatus,
+    ) -> &mut SignedTx {
+        let signed_tx = self
+            .signed_txs
+            .get_mut(&tx_id)
+            .unwrap_or_else(|| env::panic_str("Signed transaction not found"));
+        assert!(
+            signed_tx.status.can_become(status),
+            "Signed transaction can't go from {:?} to {:?}",
+            signed_tx.status,
+            status
+        );
+
+        self.signed_tx_index
+            .ids_mut(signed_tx.status)
+            .remove(&tx_id);
+        self.signed_tx_index.ids_mut(status).insert(tx_id);
+        signed_tx.status = status;
+        signed_tx.updated_at = env::block_timestamp();
+        if matches!(status, SignedTxStatus::Reverted | SignedTxStatus::Dropped) {
+            signed_tx.raw_tx.clear();
+        }
+        FundEvent::TransactionStatusUpdated { tx_id, status }.emit();
+        signed_tx
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Records that a relayer sent the transaction to its chain under `evm_tx_hash`.
+    pub fn report_broadcast(&mut self, tx_id: u64, evm_tx_hash: String) {
+        self.assert_owner_or_relayer();
+        let signed_tx = self.internal_set_signed_tx_status(tx_id, SignedTxStatus::Broadcast);
+        assert!(
+            signed_tx.tx_hash.eq_ignore_ascii_case(&evm_tx_hash),
+            "Transaction hash doesn't match the signed transaction"
+        );
+    }
+
+    /// Records a broadcast transaction that was mined in `block_number`.
+    ///
+    /// A successful transfer confirms its withdrawal leg. A reverted one used up its nonce and
+    /// reopens the leg, so it can be signed again at a new nonce with `retry_withdrawal_leg`.
+    /// Imported transactions have no leg and only change status.
+    ///
+    /// A dropped transaction may still be mined. It then settles its leg in place of the
+    /// transaction signed again at its nonce, which can no longer be mined and is dropped.
+    pub fn report_confirmation(&mut self, tx_id: u64, block_number: u64, success: bool) {
+        self.assert_owner_or_relayer();
+        let status = if success {
+            SignedTxStatus::Confirmed
+        } else {
+            SignedTxStatus::Reverted
+        };
+        let signed_tx = self.internal_set_signed_tx_status(tx_id, status);
+        signed_tx.block_number = Some(block_number);
+        let nonce = signed_tx.nonce;
+        let Some((withdrawal_id, leg_index)) = signed_tx.withdrawal_id.zip(signed_tx.leg_index)
+        else {
+            return;
+        };
+
+        let leg = self.internal_leg(withdrawal_id, leg_index);
+        let (leg_status, leg_tx_id) = (leg.status.clone(), leg.tx_id);
+        assert_ne!(
+            leg_status,
+            WithdrawalStatus::Pending,
+            "Withdrawal leg is being signed again, report once it is settled"
+        );
+        if leg_tx_id != Some(tx_id) {
+            if leg.nonce != nonce {
+                env::log_str(&format!(
+                    "Leg {} of withdrawal {} was signed again at another nonce",
+                    leg_index, withdrawal_id
+                ));
+                return;
+            }
+            if let Some(replaced_id) = leg_tx_id {
+                let replaced = self.signed_txs.get(&replaced_id).map(|tx| tx.status);
+                if replaced.is_some_and(|status| status.can_become(SignedTxStatus::Dropped)) {
+                    self.internal_set_signed_tx_status(replaced_id, SignedTxStatus::Dropped);
+                }
+            }
+            self.withdrawals
+                .get_mut(&withdrawal_id)
+                .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+                .legs[leg_index as usize]
+                .tx_id = Some(tx_id);
+        }
+
+        match (success, leg_status) {
+            (true, from @ (WithdrawalStatus::Signed | WithdrawalStatus::Failed)) => {
+                self.internal_confirm_leg(withdrawal_id, leg_index, from)
+            }
+            (false, WithdrawalStatus::Signed) => {
+                self.internal_fail_leg(withdrawal_id, leg_index, WithdrawalStatus::Signed)
+            }
+            // A failed leg stays failed, and takes a new nonce when it is signed again
+            (false, WithdrawalStatus::Failed) => {}
+            (_, status) => env::panic_str(&format!("Withdrawal leg is {:?}", status)),
+        }
+    }
+
+    /// Records a transaction that left the mempool without being mined.
+    ///
+    /// It reopens the withdrawal leg, which `retry_withdrawal_leg` signs again in its place:
+    /// at the same nonce, or spending the same outputs on Bitcoin, so that at most one of the
+    /// two is ever mined.
+    pub fn report_dropped(&mut self, tx_id: u64) {
+        self.assert_owner_or_relayer();
+        let signed_tx = self.internal_set_signed_tx_status(tx_id, SignedTxStatus::Dropped);
+        let Some((withdrawal_id, leg_index)) = signed_tx.withdrawal_id.zip(signed_tx.leg_index)
+        else {
+            return;
+        };
+        self.internal_fail_leg(withdrawal_id, leg_index, WithdrawalStatus::Signed);
+    }
+
+    /// Removes up to `limit` confirmed transactions from the ledger and returns how many were
+    /// removed. Confirmed transactions are final and only take up storage.
+    pub fn prune_signed_txs(&mut self, limit: Option<u32>) -> u32 {
+        self.assert_owner_or_relayer();
+        let confirmed: Vec<u64> = self
+            .signed_tx_index
+            .ids(SignedTxStatus::Confirmed)
+            .iter()
+            .take(limit.unwrap_or(u32::MAX) as usize)
+            .copied()
+            .collect();
+        for tx_id in &confirmed {
+            self.signed_txs.remove(tx_id);
+            self.signed_tx_index
+                .ids_mut(SignedTxStatus::Confirmed)
+                .remove(tx_id);
+        }
+        confirmed.len() as u32
+    }
+
+    pub fn get_signed_tx(&self, tx_id: u64) -> Option<SignedTx> {
+        self.signed_txs.get(&tx_id).cloned()
+    }
+
+    /// Signed transactions matching every filter given.
+    ///
+    /// A page reads at most `limit` transactions from `from_index` on: those of `status` when
+    /// it is given, every transaction otherwise. The account and chain filters only apply to
+    /// what was read, so a page may hold fewer than `limit` and the next one starts at
+    /// `from_index + limit` regardless.
+    pub fn get_signed_txs(
+        &self,
+        account_id: Option<AccountId>,
+        status: Option<SignedTxStatus>,
+        chain_id: Option<u64>,
+        from_index: Option<u32>,
+        limit: Option<u32>,
+    ) -> Vec<SignedTx> {
+        let (from_index, limit) = (
+            from_index.unwrap_or(0) as usize,
+            limit.unwrap_or(u32::MAX) as usize,
+        );
+        let ids: Vec<u64> = match status {
+            Some(status) => self
+                .signed_tx_index
+                .ids(status)
+                .iter()
+                .skip(from_index)
+                .take(limit)
+                .copied()
+                .collect(),
+            None => self
+                .signed_txs
+                .keys()
+                .skip(from_index)
+                .take(limit)
+                .copied()
+                .collect(),
+        };
+        ids.iter()
+            .filter_map(|tx_id| self.signed_txs.get(tx_id))
+            .filter(|signed_tx| {
+                account_id
+                    .as_ref()
+                    .is_none_or(|id| signed_tx.account_id.as_ref() == Some(id))
+                    && chain_id.is_none_or(|chain_id| chain_id == signed_tx.chain_id)
+            })
+            .cloned()
+            .collect()
+    }
+}
@@ -1,87 +1,187 @@
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
 use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::fungible_token::FungibleToken;
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LazyOption;
+use near_sdk::store::{IterableMap, IterableSet, LookupMap, Vector};
 use near_sdk::json_types::U128;
 use near_sdk::serde::{Deserialize, Serialize};
 use near_sdk::{
-    env, near_bindgen, AccountId, Gas, NearToken, PanicOnDefault, Promise, PromiseError,
-    PromiseOrValue,
+    env, log, near_bindgen, AccountId, BorshStorageKey, Gas, PanicOnDefault, Promise, PromiseError,
+    PromiseOrValue, PublicKey,
 };
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use crate::signer::mpc;
 
+pub mod abi;
+mod aurora;
+mod bech32;
+mod bitcoin;
+mod breaker;
+mod chains;
+mod events;
+mod fees;
+mod kdf;
+mod ledger;
+mod math;
+mod migrate;
 mod models;
+mod nonce;
+mod oracle;
+mod registry;
+mod rlp;
+mod shares;
 mod signer;
-
-use models::EVMTransactionWrapper;
+mod storage;
+mod transaction;
+mod twap;
+mod withdrawal;
+
+use bitcoin::Utxo;
+use breaker::{PriceGuard, PriceHalt};
+use chains::{ChainConfig, ChainKind, TreasuryKey};
+use events::FundEvent;
+use ledger::{SignedTx, SignedTxIndex, SignedTxStatus};
+use fees::{CallType, FeeQuote};
+use models::{AccessList, EVMTransactionWrapper};
+use registry::RegisteredAsset;
+use transaction::TxType;
+use twap::{PricingMode, TwapAccumulator};
+use withdrawal::{WithdrawAmount, Withdrawal, WithdrawalLeg, WithdrawalStatus};
 use omni_transaction::evm::evm_transaction::EVMTransaction;
-use omni_transaction::evm::types::Signature as OmniSignature;
 use omni_transaction::evm::utils::parse_eth_address;
 use omni_transaction::transaction_builder::{TransactionBuilder, TxBuilder};
 use omni_transaction::types::EVM;
 use signer::{ SignResult, SignRequest };
 
 // Constants
+/// Placeholder address of a chain's native coin, per EIP-7528
+pub const NATIVE_ASSET_ADDRESS: &str = "0xEeeeeEeeeEeEeeEeEeEeeEEEeeeeEeeeeeeeEEeE";
 const MPC_CONTRACT_ACCOUNT_ID: &str = "v1.signer-prod.testnet";
-const ETH_TREASURY_PATH: &str = "eth-treasury";
-const AURORA_TREASURY_PATH: &str = "aurora-treasury";
-
-pub static TOKEN_ADDRESSES: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
-    let mut m = HashMap::new();
-    m.insert(
-        "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6",
-        "aurora.fakes.testnet",
-    );
-    m.insert(
-        "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87",
-        "weth.fakes.testnet",
-    );
-    m.insert(
-        "0xf08a50178dfcde18524640ea6618a1f965821715",
-        "usdc.fakes.testnet",
-    );
-    m
-});
-
-#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone)]
+/// How old accepted prices may be for the cached views, until the owner sets otherwise
+const DEFAULT_PRICE_MAX_AGE_SEC: u64 = 900;
+/// How old the prices of an oracle may be to be aggregated, until the owner sets otherwise
+const DEFAULT_ORACLE_MAX_AGE_SEC: u64 = 90;
+/// Root key of `MPC_CONTRACT_ACCOUNT_ID`, all treasury keys are derived from it
+const MPC_ROOT_PUBLIC_KEY: &str = "secp256k1:4NfTiv3UsGahebgTaHyD9vF8KYKMBnfd6kh94mK6xv8fGBiJB8TBtFMP5WWXz6B89Ac1fbpzPwAvoyQebemHFwx3";
+
+#[derive(BorshSerialize, BorshStorageKey)]
+pub enum StorageKey {
+    FungibleToken,
+    Metadata,
+    UserBalances,
+    SignedTxs,
+    AssetRegistry,
+    Withdrawals,
+    Nonces,
+    Relayers,
+    Chains,
+    FeeQuotes,
+    Utxos,
+    Oracles,
+    AcceptedPrices,
+    Guardians,
+    Twaps,
+    LegacyBalances,
+    TreasuryKeys,
+    SyncedNonces,
+    HeldUtxos,
+    SpentUtxos,
+    SignedTxsByStatus { status: SignedTxStatus },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct AssetInfo {
     pub name: String,
+    /// Registry id of the asset, which positions and prices are keyed by
+    pub asset_id: String,
     pub contract_address: String,
     pub weight: u8,
+    #[serde(default)]
+    pub kind: AssetKind,
+}
+
+/// How the treasury holds an asset.
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum AssetKind {
+    /// ERC-20 token at `contract_address`
+    #[default]
+    Erc20,
+    /// The chain's own coin, sent as the transaction value. `contract_address` is only the
+    /// address it is registered under, conventionally `NATIVE_ASSET_ADDRESS`
+    Native,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct WithdrawRequest {
-    pub eth_destination: String,
-    pub aurora_destination: String,
-    pub network_details: NetworkDetails,
+    /// Chain id -> address receiving the assets withdrawn on that chain
+    pub destinations: HashMap<u64, String>,
+    /// Fee overrides, for the chains that need them
+    #[serde(default)]
+    pub network_details: Vec<NetworkDetails>,
+    /// Part of the position to redeem, all of it when omitted
+    #[serde(default)]
+    pub amount: Option<WithdrawAmount>,
 }
 
+/// Fees left out are taken from the chain's fee policy and quote.
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(crate = "near_sdk::serde")]
 pub struct NetworkDetails {
     pub chain_id: u64,
-    pub eth_nonce: u64,
-    pub max_priority_fee_per_gas: u128,
-    pub max_fee_per_gas: u128,
-    pub gas_limit: u128,
+    #[serde(default)]
+    pub max_priority_fee_per_gas: Option<u128>,
+    #[serde(default)]
+    pub max_fee_per_gas: Option<u128>,
+    #[serde(default)]
+    pub gas_limit: Option<u128>,
+    /// Addresses and storage slots the transaction will touch, not accepted by legacy chains
+    #[serde(default)]
+    pub access_list: AccessList,
 }
 
-#[derive(Serialize, Deserialize)]
+impl NetworkDetails {
+    pub(crate) fn defaults(chain_id: u64) -> Self {
+        Self {
+            chain_id,
+            max_priority_fee_per_gas: None,
+            max_fee_per_gas: None,
+            gas_limit: None,
+            access_list: Vec::new(),
+        }
+    }
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
 #[serde(crate = "near_sdk::serde")]
 pub struct PriceFeedInfo {
-    pub asset_address: String,
+    /// Registry id of the priced asset
+    pub asset_id: String,
     pub price: U128,
     pub decimals: u8,
     pub last_updated: u64,
+    /// Oracles whose quotes the price is the median of
+    pub sources: Vec<AccountId>,
 }
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
 pub struct OraclePriceData {
     pub timestamp: String,
+    /// Ignored, the fund judges freshness by `oracle_max_age_sec` whatever the oracle claims
     pub recency_duration_sec: u64,
     pub prices: Vec<AssetPrice>,
 }
@@ -106,10 +206,67 @@ pub struct Contract {
     pub total_assets: U128,
     pub assets: Vec<AssetInfo>,
     pub owner_id: AccountId,
-    pub user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    /// Account -> registry id -> balance of the asset backing the account's shares
+    pub user_balances: LookupMap<AccountId, HashMap<String, U128>>,
     pub usdc_contract: AccountId,
-    pub oracle_contract: AccountId,
-    pub latest_signed_txs: Vec<Vec<u8>>,
+    /// Contracts the fund takes prices from
+    pub oracles: IterableSet<AccountId>,
+    /// How many oracles must quote an asset for it to be priced
+    pub oracle_quorum: u8,
+    /// How old the prices an oracle returns may be for them to be aggregated
+    pub oracle_max_age_sec: u64,
+    /// Registry id -> last price the circuit breaker accepted
+    pub accepted_prices: IterableMap<String, PriceFeedInfo>,
+    pub price_guard: PriceGuard,
+    /// Set while the circuit breaker halts prices
+    pub price_halt: Option<PriceHalt>,
+    /// How old accepted prices may be for the cached views to use them
+    pub price_max_age_sec: u64,
+    /// Accounts allowed to acknowledge a price halt besides the owner
+    pub guardians: IterableSet<AccountId>,
+    /// Registry id -> time weighted average of its accepted prices
+    pub twaps: LookupMap<String, TwapAccumulator>,
+    pub pricing_mode: PricingMode,
+    /// Transaction id -> transaction signed for a withdrawal
+    pub signed_txs: IterableMap<u64, SignedTx>,
+    pub signed_tx_index: SignedTxIndex,
+    pub next_signed_tx_id: u64,
+    pub token: FungibleToken,
+    /// Deposit remainders left by rounding the per-asset split down, owned by the fund
+    pub dust: U128,
+    pub metadata: LazyOption<FungibleTokenMetadata>,
+    /// Oracle asset id -> asset details
+    pub asset_registry: IterableMap<String, RegisteredAsset>,
+    pub withdrawals: LookupMap<u64, Withdrawal>,
+    pub next_withdrawal_id: u64,
+    /// `"{chain_id}:{path}"` -> next nonce of the treasury address derived from `path`
+    pub nonces: LookupMap<String, u64>,
+    /// `"{chain_id}:{path}"` -> on-chain transaction count of the treasury address, as of the
+    /// last `resync_nonce`. Every nonce below it is used up
+    pub synced_nonces: LookupMap<String, u64>,
+    /// Accounts allowed to resync nonces besides the owner
+    pub relayers: IterableSet<AccountId>,
+    /// Chain id -> chain the fund withdraws assets on
+    pub chains: IterableMap<u64, ChainConfig>,
+    /// Chain id -> latest fees posted by a relayer
+    pub fee_quotes: LookupMap<u64, FeeQuote>,
+    /// Root public key of the MPC signer
+    pub mpc_public_key: PublicKey,
+    /// Treasury path -> key the MPC signer derives for it
+    pub treasury_keys: LookupMap<String, TreasuryKey>,
+    /// `"{chain_id}:{path}"` -> unspent outputs of a Bitcoin treasury, oldest first
+    pub utxos: LookupMap<String, Vec<Utxo>>,
+    /// `"{chain_id}:{path}"` -> outputs of a Bitcoin treasury picked by legs that are neither
+    /// confirmed nor refunded, kept out of the set `report_utxos` replaces
+    pub held_utxos: LookupMap<String, Vec<Utxo>>,
+    /// `"{chain_id}:{path}"` -> held outputs the last `report_utxos` left out, as they were
+    /// spent by a transaction in the mempool or on chain
+    pub spent_utxos: LookupMap<String, Vec<Utxo>>,
+    /// Balances of the old layout still to be converted by `migrate_holders`, until it has
+    /// converted them all
+    pub legacy_balances: Option<Vector<(AccountId, HashMap<String, U128>)>>,
+    /// How many holders `migrate_holders` converted
+    pub migrated_holders: u32,
 }
 
 #[near_bindgen]
@@ -120,108 +277,184 @@ impl Contract {
         assets: Vec<AssetInfo>,
         usdc_contract: AccountId,
         oracle_contract: AccountId,
+        metadata: FungibleTokenMetadata,
+        registry: Option<Vec<RegisteredAsset>>,
     ) -> Self {
         assert!(!env::state_exists(), "Contract is already initialized");
         let total_weight: u8 = assets.iter().map(|a| a.weight).sum();
         assert_eq!(total_weight, 100, "Total weight of assets must equal 100%");
+        let asset_ids: HashSet<&String> = assets.iter().map(|asset| &asset.asset_id).collect();
+        assert_eq!(asset_ids.len(), assets.len(), "Assets must have distinct ids");
+        metadata.assert_valid();
+        Self::assert_valid_dependency(&usdc_contract);
+        Self::assert_valid_dependency(&oracle_contract);
+        assert_ne!(
+            usdc_contract, oracle_contract,
+            "USDC and oracle contracts must differ"
+        );
 
-        Self {
+        let mut token = FungibleToken::new(StorageKey::FungibleToken);
+        token.account_storage_usage += storage::position_storage_usage(&assets);
+
+        let mut oracles = IterableSet::new(StorageKey::Oracles);
+        oracles.insert(oracle_contract);
+
+        let mut contract = Self {
             total_assets: U128(0),
             assets,
             owner_id,
-            user_balances: HashMap::new(),
-            usdc_contract: "3e2210e1184b45b64c8a434c0a7e7b23cc04ea7eb7a6c3c32520d03d4afcb8af".parse::<AccountId>().unwrap(),
-            oracle_contract: "priceoracle.testnet".parse::<AccountId>().unwrap(),
-            latest_signed_txs: Vec::new(),
+            user_balances: LookupMap::new(StorageKey::UserBalances),
+            usdc_contract,
+            oracles,
+            oracle_quorum: 1,
+            oracle_max_age_sec: DEFAULT_ORACLE_MAX_AGE_SEC,
+            accepted_prices: IterableMap::new(StorageKey::AcceptedPrices),
+            price_guard: PriceGuard::default(),
+            price_halt: None,
+            price_max_age_sec: DEFAULT_PRICE_MAX_AGE_SEC,
+            guardians: IterableSet::new(StorageKey::Guardians),
+            twaps: LookupMap::new(StorageKey::Twaps),
+            pricing_mode: PricingMode::Spot,
+            signed_txs: IterableMap::new(StorageKey::SignedTxs),
+            signed_tx_index: SignedTxIndex::new(),
+            next_signed_tx_id: 0,
+            token,
+            dust: U128(0),
+            metadata: LazyOption::new(StorageKey::Metadata, Some(&metadata)),
+            asset_registry: IterableMap::new(StorageKey::AssetRegistry),
+            withdrawals: LookupMap::new(StorageKey::Withdrawals),
+            next_withdrawal_id: 0,
+            nonces: LookupMap::new(StorageKey::Nonces),
+            synced_nonces: LookupMap::new(StorageKey::SyncedNonces),
+            relayers: IterableSet::new(StorageKey::Relayers),
+            chains: IterableMap::new(StorageKey::Chains),
+            fee_quotes: LookupMap::new(StorageKey::FeeQuotes),
+            mpc_public_key: MPC_ROOT_PUBLIC_KEY.parse().unwrap(),
+            treasury_keys: LookupMap::new(StorageKey::TreasuryKeys),
+            utxos: LookupMap::new(StorageKey::Utxos),
+            held_utxos: LookupMap::new(StorageKey::HeldUtxos),
+            spent_utxos: LookupMap::new(StorageKey::SpentUtxos),
+            legacy_balances: None,
+            migrated_holders: 0,
+        };
+        for asset in registry.unwrap_or_default() {
+            contract.internal_register_asset(asset);
         }
+        contract
     }
 
-    pub fn get_assets(&self) -> Vec<AssetInfo> {
-        self.assets.clone()
+    pub fn set_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        self.assert_owner();
+        metadata.assert_valid();
+        self.metadata.set(&metadata);
+        env::log_str(&format!(
+            "Updated share metadata to {} ({})",
+            metadata.name, metadata.symbol
+        ));
     }
 
-    pub fn get_total_assets(&self) -> U128 {
-        self.total_assets
+    pub fn set_usdc_contract(&mut self, usdc_contract: AccountId) {
+        self.assert_owner();
+        Self::assert_valid_dependency(&usdc_contract);
+        assert!(
+            !self.oracles.contains(&usdc_contract),
+            "USDC and oracle contracts must differ"
+        );
+
+        let old_usdc_contract = std::mem::replace(&mut self.usdc_contract, usdc_contract);
+        FundEvent::UsdcContractUpdated {
+            old_usdc_contract,
+            new_usdc_contract: self.usdc_contract.clone(),
+        }
+        .emit();
     }
 
-    pub fn get_user_balance(&self, account_id: &AccountId) -> Option<&HashMap<String, U128>> {
-        self.user_balances.get(account_id)
+    pub fn set_mpc_public_key(&mut self, mpc_public_key: PublicKey) {
+        self.assert_owner();
+        kdf::parse_root_key(&mpc_public_key);
+        env::log_str(&format!(
+            "Updated MPC public key to {}",
+            String::from(&mpc_public_key)
+        ));
+        self.mpc_public_key = mpc_public_key;
+
+        // Every treasury key is derived from the root key
+        let paths: Vec<String> = self
+            .chains
+            .values()
+            .map(|chain| chain.treasury_path.clone())
+            .collect();
+        for path in paths {
+            self.internal_cache_treasury_key(&path);
+        }
     }
 
-    // Price Feed Functions
-    pub fn get_current_prices(&self) -> Promise {
-        Promise::new(self.oracle_contract.clone())
-            .function_call(
-                "get_price_data".to_string(),
-                Vec::new(),
-                NearToken::from_near(0),
-                Gas::from_tgas(100),
-            )
-            .then(
-                Self::ext(env::current_account_id())
-                    .with_static_gas(Gas::from_tgas(50))
-                    .get_prices_callback(),
-            )
+    /// External contracts the fund relies on can't be the fund itself.
+    fn assert_valid_dependency(account_id: &AccountId) {
+        assert_ne!(
+            *account_id,
+            env::current_account_id(),
+            "The fund can't depend on itself"
+        );
     }
 
-    pub fn get_prices_callback(
-        &self,
-        #[callback_result] call_result: Result<OraclePriceData, PromiseError>,
-    ) -> Vec<PriceFeedInfo> {
-        let price_data = match call_result {
-            Ok(data) => data,
-            Err(_) => env::panic_str("Failed to fetch price data from oracle"),
-        };
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
 
-        let mut price_feeds = Vec::new();
+    pub fn get_assets(&self) -> Vec<AssetInfo> {
+        self.assets.clone()
+    }
 
-        let timestamp = price_data.timestamp.parse::<u64>().unwrap_or(0);
-        let current_time = env::block_timestamp();
+    pub fn get_number_of_assets(&self) -> u32 {
+        self.assets.len() as u32
+    }
 
-        if current_time - timestamp > price_data.recency_duration_sec * 1_000_000_000 {
-            env::panic_str("Price data is too old");
-        }
+    pub fn get_total_assets(&self) -> U128 {
+        self.total_assets
+    }
 
-        for price in price_data.prices {
-            if let Some(price_info) = price.price {
-                if let Some(&near_address) = TOKEN_ADDRESSES.get(price.asset_id.as_str()) {
-                    price_feeds.push(PriceFeedInfo {
-                        asset_address: near_address.to_string(),
-                        price: U128(price_info.multiplier.parse().unwrap_or(0)),
-                        decimals: price_info.decimals as u8,
-                        last_updated: timestamp,
-                    });
-                }
-            }
-        }
+    pub fn get_dust(&self) -> U128 {
+        self.dust
+    }
 
-        price_feeds
+    pub fn get_user_balance(&self, account_id: &AccountId) -> Option<&HashMap<String, U128>> {
+        self.user_balances.get(account_id)
     }
 
-    pub fn get_asset_price(&self, asset_address: String) -> Promise {
+    // Price Feed Functions
+    pub fn get_asset_price(&self, asset_id: String) -> Promise {
+        self.assert_prices_not_halted();
         self.get_current_prices().then(
             Self::ext(env::current_account_id())
                 .with_static_gas(Gas::from_tgas(50))
-                .get_single_price_callback(asset_address),
+                .get_single_price_callback(asset_id),
         )
     }
 
     pub fn get_single_price_callback(
         &self,
-        asset_address: String,
+        asset_id: String,
         #[callback_result] price_feeds_result: Result<Vec<PriceFeedInfo>, PromiseError>,
     ) -> Option<PriceFeedInfo> {
         let price_feeds = match price_feeds_result {
             Ok(feeds) => feeds,
             Err(_) => env::panic_str("Failed to fetch price feeds"),
         };
+        // The breaker may have tripped on the prices just fetched
+        self.assert_prices_not_halted();
 
         price_feeds
             .into_iter()
-            .find(|feed| feed.asset_address == asset_address)
+            .find(|feed| feed.asset_id == asset_id)
     }
 
     pub fn get_portfolio_value(&self, account_id: AccountId) -> Promise {
+        self.assert_prices_not_halted();
         let balances = self
             .get_user_balance(&account_id)
             .expect("No balance found for user");
@@ -242,18 +475,30 @@ impl Contract {
             Ok(feeds) => feeds,
             Err(_) => env::panic_str("Failed to fetch price feeds"),
         };
+        self.assert_prices_not_halted();
 
         let mut total_value: u128 = 0;
 
-        for (asset_address, balance) in balances {
-            if let Some(price_feed) = price_feeds
-                .iter()
-                .find(|feed| feed.asset_address == asset_address)
-            {
-                let asset_value = (balance.0 as f64
-                    * (price_feed.price.0 as f64 / 10u64.pow(price_feed.decimals as u32) as f64))
-                    as u128;
-                total_value += asset_value;
+        for (asset_id, balance) in balances {
+            let price_feed = match self.pricing_mode {
+                PricingMode::Spot => price_feeds
+                    .iter()
+                    .find(|feed| feed.asset_id == asset_id)
+                    .cloned(),
+                // The averages already include the prices just fetched. Like the cached value,
+                // a position without one can't be left out of the total
+                PricingMode::Twap { .. } => Some(
+                    self.internal_valuation_price(&asset_id).unwrap_or_else(|| {
+                        env::panic_str(&format!("No fresh price for {}", asset_id))
+                    }),
+                ),
+            };
+            if let Some(price_feed) = price_feed {
+                let asset_value =
+                    math::value_at_price(balance.0, price_feed.price.0, price_feed.decimals);
+                total_value = total_value
+                    .checked_add(asset_value)
+                    .unwrap_or_else(|| env::panic_str("Portfolio value overflow"));
             }
         }
 
@@ -265,52 +510,77 @@ impl Contract {
     pub fn withdraw_underlying_assets(&mut self, request: WithdrawRequest) -> Promise {
         let sender_id = env::predecessor_account_id();
 
-        // Clone balances to avoid borrowing issues
-        let balances = self
-            .user_balances
-            .get(&sender_id)
-            .expect("No balance found for user")
-            .clone();
+        // Debit the redeemed position up front so it can't be withdrawn again while the
+        // signatures are pending; legs that fail to be signed are credited back
+        let shares = self.shares_to_redeem(&sender_id, request.amount);
+        let slice = self.internal_take_position(&sender_id, shares);
+        self.internal_burn_shares(&sender_id, shares);
+        self.total_assets = U128(self.total_assets.0 - shares);
+
+        let mut legs: Vec<WithdrawalLeg> = Vec::new();
+        for (asset_id, amount) in slice {
+            if amount == 0 {
+                continue;
+            }
+            let chain = self.withdrawal_chain(&asset_id);
+            let (chain_id, treasury_path) = (chain.chain_id, chain.treasury_path.clone());
+            let destination = request
+                .destinations
+                .get(&chain_id)
+                .unwrap_or_else(|| {
+                    env::panic_str(&format!("No destination for chain {}", chain_id))
+                })
+                .clone();
+            let asset = self.internal_held_asset(&asset_id);
+            legs.push(WithdrawalLeg {
+                kind: asset.kind,
+                asset: asset.contract_address.clone(),
+                asset_id,
+                amount: U128(amount),
+                destination,
+                nonce: None,
+                treasury_path,
+                chain_id,
+                status: WithdrawalStatus::Queued,
+                sent_at: 0,
+                tx_id: None,
+                inputs: Vec::new(),
+            });
+        }
+        assert!(!legs.is_empty(), "Nothing to withdraw");
 
-        // Collect the required data into a temporary vector
-        let withdrawals: Vec<_> = self
-            .assets
+        // Legs beyond the gas of this call are left queued for `sign_queued_legs`
+        let withdrawal_id = self.internal_create_withdrawal(sender_id, legs);
+        self.internal_send_queued_legs(withdrawal_id, &request.network_details)
+    }
+
+    fn internal_held_asset(&self, asset_id: &str) -> &AssetInfo {
+        self.assets
             .iter()
-            .filter_map(|asset| {
-                balances.get(&asset.contract_address).map(|balance| {
-                    let destination = if asset.name == "ETH" {
-                        request.eth_destination.clone()
-                    } else {
-                        request.aurora_destination.clone()
-                    };
-                    (asset.contract_address.clone(), destination, balance.0)
-                })
-            })
-            .collect();
+            .find(|asset| asset.asset_id == asset_id)
+            .unwrap_or_else(|| env::panic_str(&format!("Asset {} is not held", asset_id)))
+    }
 
-        // Create promises
-        let promises: Vec<Promise> = withdrawals
-            .into_iter()
-            .map(|(contract_address, destination, amount)| {
-                self.create_and_sign_withdrawal(
-                    &contract_address, // Pass as &str
-                    destination,
-                    amount,
-                    request.network_details.clone(),
-                    if contract_address == ETH_TREASURY_PATH {
-                        ETH_TREASURY_PATH
-                    } else {
-                        AURORA_TREASURY_PATH
-                    },
-                )
-            })
-            .collect();
+    fn construct_native_transfer_tx(
+        &self,
+        recipient_address: String,
+        amount: u128,
+        nonce: u64,
+        network_details: NetworkDetails,
+    ) -> EVMTransaction {
+        let recipient_address = parse_eth_address(&recipient_address);
+        let fees = self.internal_gas_fees(&network_details, CallType::NativeTransfer);
 
-        // Combine promises
-        promises
-            .into_iter()
-            .reduce(|acc, promise| acc.and(promise))
-            .unwrap_or_else(|| Promise::new(env::current_account_id()))
+        TransactionBuilder::new::<EVM>()
+            .nonce(nonce)
+            .to(recipient_address)
+            .value(amount)
+            .input(Vec::new())
+            .max_priority_fee_per_gas(fees.max_priority_fee_per_gas)
+            .max_fee_per_gas(fees.max_fee_per_gas)
+            .gas_limit(fees.gas_limit)
+            .chain_id(network_details.chain_id)
+            .build()
     }
 
     fn construct_erc20_transfer_tx(
@@ -318,120 +588,162 @@ impl Contract {
         token_address: String,
         recipient_address: String,
         amount: u128,
+        nonce: u64,
         network_details: NetworkDetails,
     ) -> EVMTransaction {
         let token_address = parse_eth_address(&token_address);
         let recipient_address = parse_eth_address(&recipient_address);
 
-        let data = self.construct_erc20_transfer_data(recipient_address, amount);
+        let data = abi::erc20_transfer(recipient_address, abi::U256::from(amount));
+        let fees = self.internal_gas_fees(&network_details, CallType::Erc20Transfer);
 
         TransactionBuilder::new::<EVM>()
-            .nonce(network_details.eth_nonce)
+            .nonce(nonce)
             .to(token_address)
             .value(0)
             .input(data)
-            .max_priority_fee_per_gas(network_details.max_priority_fee_per_gas)
-            .max_fee_per_gas(network_details.max_fee_per_gas)
-            .gas_limit(network_details.gas_limit)
+            .max_priority_fee_per_gas(fees.max_priority_fee_per_gas)
+            .max_fee_per_gas(fees.max_fee_per_gas)
+            .gas_limit(fees.gas_limit)
             .chain_id(network_details.chain_id)
             .build()
     }
 
-    fn construct_erc20_transfer_data(&self, to: [u8; 20], amount: u128) -> Vec<u8> {
-        let mut data = Vec::new();
-        data.extend_from_slice(&[0xa9, 0x05, 0x9c, 0xbb]); // Function selector for "transfer(address,uint256)"
-        data.extend_from_slice(&[0; 12]);
-        data.extend_from_slice(&to);
-        data.extend_from_slice(&[0; 16]);
-        data.extend_from_slice(&amount.to_be_bytes());
-        data
-    }
-
+    /// Stores the signed transaction of a withdrawal leg, or settles the leg as unsigned when the
+    /// MPC call failed or returned a signature that isn't from the leg's treasury.
+    #[private]
     pub fn sign_callback(
         &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
         evm_tx_wrapper: EVMTransactionWrapper,
         #[callback_result] result: Result<SignResult, PromiseError>,
-    ) -> Vec<u8> {
-        let mpc_signature = result.unwrap();
-        let big_r = &mpc_signature.big_r.affine_point;
-        let s = &mpc_signature.s.scalar;
-
-        let r = &big_r[2..];
-        let v = mpc_signature.recovery_id;
-        let signature_omni = OmniSignature {
-            v,
-            r: hex::decode(r).unwrap(),
-            s: hex::decode(s).unwrap(),
+    ) -> Option<Vec<u8>> {
+        let payload = env::keccak256_array(&evm_tx_wrapper.build_for_signing());
+        let treasury_path = &self.internal_leg(withdrawal_id, leg_index).treasury_path;
+        let treasury = self.internal_treasury_key(treasury_path).evm_address;
+
+        let signature = match result {
+            Ok(sign_result) => signer::verify_signature(&sign_result, &payload, &treasury),
+            Err(_) => Err("MPC call failed".to_string()),
+        };
+        let signature_omni = match signature {
+            Ok(signature) => signature,
+            Err(reason) => {
+                env::log_str(&format!(
+                    "Signing failed for withdrawal {} leg {}: {}",
+                    withdrawal_id, leg_index, reason
+                ));
+                self.internal_settle_unsigned_leg(withdrawal_id, leg_index);
+                return None;
+            }
         };
 
-        let evm_tx = evm_tx_wrapper.to_evm_transaction();
-        let signed_tx = evm_tx.build_with_signature(&signature_omni);
+        let signed_tx = evm_tx_wrapper.build_with_signature(&signature_omni);
 
-        self.latest_signed_txs.push(signed_tx.clone());
-        signed_tx
+        let tx_hash = format!("0x{}", hex::encode(env::keccak256_array(&signed_tx)));
+        self.internal_record_signed_tx(withdrawal_id, leg_index, signed_tx.clone(), tx_hash);
+        self.internal_sign_leg(withdrawal_id, leg_index);
+        Some(signed_tx)
     }
 
     fn create_and_sign_withdrawal(
         &mut self,
-        token_address: &str,
-        recipient: String,
-        amount: u128,
+        withdrawal_id: u64,
+        leg_index: u32,
+        leg: &WithdrawalLeg,
         network_details: NetworkDetails,
-        treasury_path: &str,
     ) -> Promise {
-        // Use the construct_erc20_transfer_tx method if you want to keep it
-        let omni_tx = self.construct_erc20_transfer_tx(
-            token_address.to_string(),
-            recipient,
-            amount,
-            network_details.clone(),
-        );
+        let chain = self.internal_chain(leg.chain_id);
+        match chain.kind.clone() {
+            ChainKind::Evm => {}
+            ChainKind::Bitcoin { hrp } => {
+                return self.create_and_sign_bitcoin_withdrawal(
+                    withdrawal_id,
+                    leg_index,
+                    leg,
+                    &hrp,
+                    network_details,
+                );
+            }
+            ChainKind::AuroraEngine { engine } => {
+                return self.create_aurora_engine_withdrawal(withdrawal_id, leg_index, leg, engine);
+            }
+        }
+        let tx_type = chain.tx_type;
+        let nonce = leg
+            .nonce
+            .unwrap_or_else(|| env::panic_str("EVM withdrawal leg has no nonce"));
+        let access_list = network_details.access_list.clone();
+        let omni_tx = match leg.kind {
+            AssetKind::Erc20 => self.construct_erc20_transfer_tx(
+                leg.asset.clone(),
+                leg.destination.clone(),
+                leg.amount.0,
+                nonce,
+                network_details,
+            ),
+            AssetKind::Native => self.construct_native_transfer_tx(
+                leg.destination.clone(),
+                leg.amount.0,
+                nonce,
+                network_details,
+            ),
+        };
 
-        // Rest of the implementation remains the same
-        let encoded_tx = omni_tx.build_for_signing();
+        let evm_tx = EVMTransactionWrapper {
+            access_list,
+            ..EVMTransactionWrapper::from_evm_transaction(&omni_tx, tx_type)
+        };
+        let encoded_tx = evm_tx.build_for_signing();
         let tx_hash = env::keccak256(&encoded_tx);
 
         let sign_request = SignRequest {
             payload: tx_hash.to_vec(),
-            path: treasury_path.to_string(),
+            path: leg.treasury_path.clone(),
             key_version: 0,
         };
 
         mpc::ext(MPC_CONTRACT_ACCOUNT_ID.parse().unwrap())
-            .with_static_gas(Gas::from_tgas(100))
+            .with_static_gas(withdrawal::LEG_CALL_GAS)
             .sign(sign_request)
             .then(
                 Self::ext(env::current_account_id())
-                    .with_static_gas(Gas::from_tgas(10))
-                    .sign_callback(EVMTransactionWrapper::from_evm_transaction(&omni_tx)),
+                    .with_static_gas(withdrawal::LEG_CALLBACK_GAS)
+                    .sign_callback(withdrawal_id, leg_index, evm_tx),
             )
     }
 
     // View functions
-    pub fn get_latest_signed_txs(&self) -> Vec<Vec<u8>> {
-        self.latest_signed_txs.clone()
+    pub fn get_usdc_contract(&self) -> AccountId {
+        self.usdc_contract.clone()
     }
 
-    pub fn get_oracle_contract(&self) -> AccountId {
-        self.oracle_contract.clone()
+    pub fn get_mpc_public_key(&self) -> PublicKey {
+        self.mpc_public_key.clone()
     }
 
-    pub fn process_deposit(&mut self, sender_id: AccountId, amount: U128) {
+    fn process_deposit(&mut self, sender_id: AccountId, amount: U128) {
+        let weights: Vec<u8> = self.assets.iter().map(|asset| asset.weight).collect();
+        let (parts, dust) = math::split_by_weights(amount.0, &weights);
+
         let user_balance = self
             .user_balances
             .entry(sender_id.clone())
             .or_insert_with(HashMap::new);
 
-        for asset in &self.assets {
-            let weight_fraction = f64::from(asset.weight) / 100.0;
-            let asset_amount = (amount.0 as f64 * weight_fraction) as u128;
-
+        for (asset, asset_amount) in self.assets.iter().zip(parts) {
             user_balance
-                .entry(asset.contract_address.clone())
+                .entry(asset.asset_id.clone())
                 .and_modify(|balance| *balance = U128(balance.0 + asset_amount))
                 .or_insert(U128(asset_amount));
         }
 
+        // One share is minted per unit credited to the underlying assets, the rounding
+        // remainder stays with the fund so credited amounts and dust add up to the deposit
+        let credited = amount.0 - dust;
+        self.internal_mint_shares(&sender_id, credited, "deposit");
+        self.dust = U128(self.dust.0 + dust);
         self.total_assets = U128(self.total_assets.0 + amount.0);
 
         env::log_str(&format!(
@@ -455,6 +767,15 @@ impl FungibleTokenReceiver for Contract {
             "Only USDC token is accepted"
         );
 
+        if !self.token.accounts.contains_key(&sender_id) {
+            log!("{} is not registered for fund shares, refunding", sender_id);
+            return PromiseOrValue::Value(amount);
+        }
+        if self.price_halt.is_some() {
+            log!("Prices are halted, refunding the deposit of {}", sender_id);
+            return PromiseOrValue::Value(amount);
+        }
+
         if msg.is_empty() {
             self.process_deposit(sender_id, amount);
             PromiseOrValue::Value(U128(0))
@@ -468,8 +789,20 @@ impl FungibleTokenReceiver for Contract {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::metadata::{
+        FungibleTokenMetadataProvider, FT_METADATA_SPEC,
+    };
+    use near_contract_standards::storage_management::StorageManagement;
     use near_sdk::test_utils::{accounts, VMContextBuilder};
-    use near_sdk::testing_env;
+    use near_sdk::{testing_env, NearToken};
+    use fees::FeePolicy;
+    use withdrawal::STUCK_LEG_TIMEOUT_SEC;
+
+    const SEPOLIA: u64 = 11155111;
+    const AURORA_TESTNET: u64 = 1313161555;
+    const ETH_TREASURY_PATH: &str = "eth-treasury";
+    const AURORA_TREASURY_PATH: &str = "aurora-treasury";
 
     fn get_context(predecessor: AccountId) -> VMContextBuilder {
         let mut builder = VMContextBuilder::new();
@@ -477,6 +810,109 @@ mod tests {
         builder
     }
 
+    fn test_assets() -> Vec<AssetInfo> {
+        vec![
+            AssetInfo {
+                name: "ETH".to_string(),
+                asset_id: "weth.fakes.testnet".to_string(),
+                contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
+                weight: 70,
+                kind: AssetKind::Erc20,
+            },
+            AssetInfo {
+                name: "AURORA".to_string(),
+                asset_id: "aurora.fakes.testnet".to_string(),
+                contract_address: "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".to_string(),
+                weight: 30,
+                kind: AssetKind::Erc20,
+            },
+        ]
+    }
+
+    fn setup_contract() -> Contract {
+        Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
+        )
+    }
+
+    fn register(contract: &mut Contract, account_id: AccountId) {
+        let mut context = get_context(account_id);
+        context.attached_deposit(contract.storage_balance_bounds().min);
+        testing_env!(context.build());
+        contract.storage_deposit(None, None);
+    }
+
+    fn add_test_chains(contract: &mut Contract) {
+        testing_env!(get_context(accounts(1)).build());
+        let chains = [
+            (SEPOLIA, "Sepolia", ETH_TREASURY_PATH, "weth.fakes.testnet"),
+            (
+                AURORA_TESTNET,
+                "Aurora Testnet",
+                AURORA_TREASURY_PATH,
+                "aurora.fakes.testnet",
+            ),
+        ];
+        for ((chain_id, name, treasury_path, asset_id), asset) in
+            chains.into_iter().zip(test_assets())
+        {
+            contract.add_asset(RegisteredAsset {
+                asset_id: asset_id.to_string(),
+                chain_id,
+                evm_address: asset.contract_address,
+                near_token: asset_id.parse().unwrap(),
+                decimals: 18,
+            });
+            contract.add_chain(ChainConfig {
+                chain_id,
+                name: name.to_string(),
+                treasury_path: treasury_path.to_string(),
+                fee_policy: FeePolicy {
+                    max_fee_per_gas_cap: U128(100_000_000_000),
+                    max_priority_fee_per_gas_cap: U128(10_000_000_000),
+                    gas_limit_cap: U128(200_000),
+                    erc20_transfer_gas_limit: U128(65_000),
+                    native_transfer_gas_limit: U128(21_000),
+                    quote_max_age_sec: 300,
+                },
+                kind: ChainKind::Evm,
+                tx_type: TxType::DynamicFee,
+                supported_assets: vec![asset_id.to_string()],
+            });
+            contract.update_fee_quote(chain_id, U128(2_000_000_000), U128(1_000_000_000));
+        }
+    }
+
+    fn test_destinations() -> HashMap<u64, String> {
+        HashMap::from([
+            (SEPOLIA, "0x1234567890123456789012345678901234567890".to_string()),
+            (AURORA_TESTNET, "0x5678901234567890123456789012345678901234".to_string()),
+        ])
+    }
+
+    /// Records `raw_tx` as the signed transaction of leg `leg_index` of the first withdrawal.
+    fn record_signed_tx(contract: &mut Contract, leg_index: u32, raw_tx: Vec<u8>) -> u64 {
+        let tx_hash = format!("0x{}", hex::encode(env::keccak256_array(&raw_tx)));
+        contract.internal_record_signed_tx(0, leg_index, raw_tx, tx_hash)
+    }
+
+    fn fund_metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Nexus Index".to_string(),
+            symbol: "NXI".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 6,
+        }
+    }
+
     #[test]
     fn test_new() {
         let context = get_context(accounts(1));
@@ -485,13 +921,17 @@ mod tests {
         let assets = vec![
             AssetInfo {
                 name: "ETH".to_string(),
+                asset_id: "weth.fakes.testnet".to_string(),
                 contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
                 weight: 70,
+                kind: AssetKind::Erc20,
             },
             AssetInfo {
                 name: "AURORA".to_string(),
+                asset_id: "aurora.fakes.testnet".to_string(),
                 contract_address: "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".to_string(),
                 weight: 30,
+                kind: AssetKind::Erc20,
             },
         ];
 
@@ -500,6 +940,8 @@ mod tests {
             assets.clone(),
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
         );
 
         assert_eq!(contract.get_number_of_assets(), 2);
@@ -516,35 +958,45 @@ mod tests {
             vec![
                 AssetInfo {
                     name: "ETH".to_string(),
+                    asset_id: "weth.fakes.testnet".to_string(),
                     contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
                     weight: 70,
+                    kind: AssetKind::Erc20,
                 },
                 AssetInfo {
                     name: "AURORA".to_string(),
+                    asset_id: "aurora.fakes.testnet".to_string(),
                     contract_address: "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".to_string(),
                     weight: 30,
+                    kind: AssetKind::Erc20,
                 },
             ],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
         );
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
 
         // Test deposit
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
         let amount = U128(1000);
         let result = contract.ft_on_transfer(accounts(2), amount, "".to_string());
         assert!(matches!(result, PromiseOrValue::Value(U128(0))));
 
         // Test withdrawal request
+        testing_env!(get_context(accounts(2)).build());
         let withdraw_request = WithdrawRequest {
-            eth_destination: "0x1234...".to_string(),
-            aurora_destination: "0x5678...".to_string(),
-            network_details: NetworkDetails {
-                chain_id: 1,
-                eth_nonce: 0,
-                max_priority_fee_per_gas: 1000000000,
-                max_fee_per_gas: 2000000000,
-                gas_limit: 21000,
-            },
+            destinations: test_destinations(),
+            network_details: vec![NetworkDetails {
+                chain_id: SEPOLIA,
+                max_priority_fee_per_gas: Some(1000000000),
+                max_fee_per_gas: Some(2000000000),
+                gas_limit: None,
+                access_list: vec![],
+            }],
+            amount: None,
         };
 
         let _withdrawal = contract.withdraw_underlying_assets(withdraw_request);
@@ -560,17 +1012,386 @@ mod tests {
             accounts(1),
             vec![AssetInfo {
                 name: "ETH".to_string(),
+                asset_id: "weth.fakes.testnet".to_string(),
                 contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
                 weight: 70,
+                kind: AssetKind::Erc20,
             }],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
         );
 
         let _prices = contract.get_current_prices();
         // Note: Can't fully test price feeds in unit tests due to cross-contract calls
     }
 
+    /// Result of an oracle's `get_price_data` at `timestamp_sec`, valid for 90 seconds.
+    fn price_data(timestamp_sec: u64, prices: &[(&str, &str, u32)]) -> near_sdk::PromiseResult {
+        let prices: Vec<_> = prices
+            .iter()
+            .map(|(asset_id, multiplier, decimals)| {
+                near_sdk::serde_json::json!({
+                    "asset_id": asset_id,
+                    "price": { "multiplier": multiplier, "decimals": decimals },
+                })
+            })
+            .collect();
+        let price_data = near_sdk::serde_json::json!({
+            "timestamp": (timestamp_sec * 1_000_000_000).to_string(),
+            "recency_duration_sec": 90,
+            "prices": prices,
+        });
+        near_sdk::PromiseResult::Successful(near_sdk::serde_json::to_vec(&price_data).unwrap())
+    }
+
+    fn contract_with_oracles(oracles: &[&str], quorum: u8) -> Contract {
+        testing_env!(get_context(accounts(1)).build());
+        let mut contract = Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.testnet".parse().unwrap(),
+            oracles[0].parse().unwrap(),
+            fund_metadata(),
+            None,
+        );
+        add_test_chains(&mut contract);
+        for oracle in &oracles[1..] {
+            contract.add_oracle(oracle.parse().unwrap());
+        }
+        contract.set_oracle_quorum(quorum);
+        contract
+    }
+
+    /// Sets up the callback of `get_current_prices` receiving `results` at `timestamp_sec`.
+    fn oracle_callback_context(timestamp_sec: u64, results: Vec<near_sdk::PromiseResult>) {
+        let mut context = get_context(env::current_account_id());
+        context.block_timestamp(timestamp_sec * 1_000_000_000);
+        testing_env!(
+            context.build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            results
+        );
+    }
+
+    #[test]
+    fn test_prices_are_median_of_fresh_oracles() {
+        let oracles = ["priceoracle.testnet", "stale.testnet", "pyth-adapter.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 2);
+
+        oracle_callback_context(
+            100,
+            vec![
+                price_data(
+                    100,
+                    &[
+                        ("weth.fakes.testnet", "300000", 2),
+                        ("aurora.fakes.testnet", "15", 2),
+                    ],
+                ),
+                price_data(0, &[("weth.fakes.testnet", "1", 2)]),
+                price_data(95, &[("weth.fakes.testnet", "3001000", 3)]),
+            ],
+        );
+        let price_feeds =
+            contract.get_prices_callback(oracles.iter().map(|o| o.parse().unwrap()).collect());
+
+        // The stale quote is ignored and AURORA has a single quote, short of the quorum
+        assert_eq!(price_feeds.len(), 1);
+        let feed = &price_feeds[0];
+        assert_eq!(feed.asset_id, test_assets()[0].asset_id);
+        assert_eq!(feed.price, U128(3_000_500));
+        assert_eq!(feed.decimals, 3);
+        assert_eq!(feed.last_updated, 95 * 1_000_000_000);
+        assert_eq!(
+            feed.sources,
+            vec![
+                "priceoracle.testnet".parse::<AccountId>().unwrap(),
+                "pyth-adapter.testnet".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_each_oracle_quotes_an_asset_once() {
+        let oracles = ["priceoracle.testnet", "stale.testnet", "pyth-adapter.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 2);
+        contract.set_oracle_max_age(30);
+        assert_eq!(contract.get_oracle_max_age(), 30);
+
+        oracle_callback_context(
+            100,
+            vec![
+                price_data(
+                    100,
+                    &[
+                        ("weth.fakes.testnet", "300000", 2),
+                        ("weth.fakes.testnet", "1", 2),
+                        ("aurora.fakes.testnet", "15", 2),
+                    ],
+                ),
+                // Within the 90 seconds the oracle allows itself, past the fund's max age
+                price_data(60, &[("weth.fakes.testnet", "1", 2)]),
+                price_data(
+                    80,
+                    &[
+                        ("weth.fakes.testnet", "300100", 2),
+                        // u128::MAX, which overflows once scaled to two decimals
+                        (
+                            "aurora.fakes.testnet",
+                            "340282366920938463463374607431768211455",
+                            0,
+                        ),
+                    ],
+                ),
+            ],
+        );
+        let price_feeds =
+            contract.get_prices_callback(oracles.iter().map(|o| o.parse().unwrap()).collect());
+
+        // AURORA is left with a single quote once the one that can't be scaled is dropped
+        assert_eq!(price_feeds.len(), 1);
+        assert_eq!(price_feeds[0].asset_id, "weth.fakes.testnet");
+        assert_eq!(price_feeds[0].price, U128(300_050));
+        assert_eq!(price_feeds[0].sources.len(), 2);
+        let logs = near_sdk::test_utils::get_logs();
+        assert!(logs.contains(
+            &"Oracle priceoracle.testnet quoted weth.fakes.testnet more than once".to_string()
+        ));
+        assert!(logs.contains(&"Price data of oracle stale.testnet is too old".to_string()));
+        assert!(logs.contains(
+            &"Only 1 quotes of aurora.fakes.testnet could be scaled, 2 required".to_string()
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only 1 oracles returned fresh prices, 2 required")]
+    fn test_prices_require_oracle_quorum() {
+        let oracles = ["priceoracle.testnet", "pyth-adapter.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 2);
+
+        oracle_callback_context(
+            0,
+            vec![
+                near_sdk::PromiseResult::Failed,
+                price_data(0, &[("weth.fakes.testnet", "300000", 2)]),
+            ],
+        );
+        contract.get_prices_callback(oracles.iter().map(|o| o.parse().unwrap()).collect());
+    }
+
+    #[test]
+    fn test_circuit_breaker_halts_until_acknowledged() {
+        let oracles = ["priceoracle.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 1);
+        contract.add_guardian(accounts(4));
+        register(&mut contract, accounts(2));
+        let weth = test_assets()[0].asset_id.clone();
+        let sources: Vec<AccountId> = vec![oracles[0].parse().unwrap()];
+
+        // Unparseable and zero prices are never accepted
+        oracle_callback_context(
+            100,
+            vec![price_data(
+                100,
+                &[
+                    ("weth.fakes.testnet", "300000", 2),
+                    ("aurora.fakes.testnet", "0", 2),
+                ],
+            )],
+        );
+        assert_eq!(contract.get_prices_callback(sources.clone()).len(), 1);
+        assert_eq!(contract.accepted_prices.get(&weth).unwrap().price, U128(300_000));
+
+        // A third up in a minute is beyond the default 20% per hour
+        oracle_callback_context(
+            160,
+            vec![price_data(160, &[("weth.fakes.testnet", "400000", 2)])],
+        );
+        contract.get_prices_callback(sources.clone());
+        let halt = contract.get_price_halt().unwrap();
+        assert_eq!(halt.accepted.price, U128(300_000));
+        assert_eq!(halt.rejected.price, U128(400_000));
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        assert!(matches!(
+            contract.ft_on_transfer(accounts(2), U128(1000), "".to_string()),
+            PromiseOrValue::Value(U128(1000))
+        ));
+
+        // Later prices aren't accepted until the guardian vouches for the move
+        oracle_callback_context(
+            200,
+            vec![price_data(200, &[("weth.fakes.testnet", "310000", 2)])],
+        );
+        contract.get_prices_callback(sources);
+        assert_eq!(contract.accepted_prices.get(&weth).unwrap().price, U128(300_000));
+
+        testing_env!(get_context(accounts(4)).build());
+        contract.acknowledge_price_halt(true);
+        assert!(contract.get_price_halt().is_none());
+        assert_eq!(contract.accepted_prices.get(&weth).unwrap().price, U128(400_000));
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(1000));
+    }
+
+    #[test]
+    fn test_cached_prices_expire() {
+        let oracles = ["priceoracle.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 1);
+        register(&mut contract, accounts(2));
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+        let _refresh = contract.refresh_prices();
+
+        oracle_callback_context(
+            100,
+            vec![price_data(
+                100,
+                &[
+                    ("weth.fakes.testnet", "300000", 2),
+                    ("aurora.fakes.testnet", "150", 2),
+                ],
+            )],
+        );
+        contract.get_prices_callback(vec![oracles[0].parse().unwrap()]);
+
+        // 700 ETH at 3000.00 and 300 AURORA at 1.50
+        let expiry = 100 + contract.get_price_max_age();
+        oracle_callback_context(expiry, Vec::new());
+        assert_eq!(contract.get_cached_prices().len(), 2);
+        assert_eq!(
+            contract
+                .get_cached_price(test_assets()[1].asset_id.clone())
+                .unwrap()
+                .price,
+            U128(150)
+        );
+        assert_eq!(
+            contract.get_cached_portfolio_value(accounts(2)),
+            U128(2_100_450)
+        );
+
+        oracle_callback_context(expiry + 1, Vec::new());
+        assert!(contract.get_cached_prices().is_empty());
+        assert!(contract
+            .get_cached_price(test_assets()[1].asset_id.clone())
+            .is_none());
+    }
+
+    #[test]
+    #[should_panic(expected = "No fresh price for")]
+    fn test_cached_portfolio_value_requires_fresh_prices() {
+        let mut contract = contract_with_oracles(&["priceoracle.testnet"], 1);
+        register(&mut contract, accounts(2));
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+        contract.get_cached_portfolio_value(accounts(2));
+    }
+
+    #[test]
+    #[should_panic(expected = "No fresh price for")]
+    fn test_twap_portfolio_value_requires_averages() {
+        let mut contract = contract_with_oracles(&["priceoracle.testnet"], 1);
+        contract.set_pricing_mode(PricingMode::Twap { window_sec: 600 });
+        register(&mut contract, accounts(2));
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+        let balances = contract.get_user_balance(&accounts(2)).unwrap().clone();
+        contract.calculate_portfolio_value_callback(balances, Ok(Vec::new()));
+    }
+
+    #[test]
+    #[should_panic(expected = "TWAP window must be positive and at most")]
+    fn test_twap_window_is_capped() {
+        let mut contract = contract_with_oracles(&["priceoracle.testnet"], 1);
+        contract.set_pricing_mode(PricingMode::Twap {
+            window_sec: twap::MAX_TWAP_WINDOW_SEC + 1,
+        });
+    }
+
+    #[test]
+    fn test_twap_values_portfolio() {
+        let oracles = ["priceoracle.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 1);
+        contract.set_pricing_mode(PricingMode::Twap { window_sec: 600 });
+        register(&mut contract, accounts(2));
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        for (timestamp_sec, weth_price) in [(100, "300000"), (400, "330000")] {
+            oracle_callback_context(
+                timestamp_sec,
+                vec![price_data(
+                    timestamp_sec,
+                    &[
+                        ("weth.fakes.testnet", weth_price, 2),
+                        ("aurora.fakes.testnet", "150", 2),
+                    ],
+                )],
+            );
+            contract.get_prices_callback(vec![oracles[0].parse().unwrap()]);
+        }
+
+        // 3000.00 and 3300.00 for five minutes each
+        oracle_callback_context(700, Vec::new());
+        let weth = test_assets()[0].asset_id.clone();
+        assert_eq!(contract.get_twap(weth.clone(), 600).unwrap().price, U128(315_000));
+        assert_eq!(contract.get_twap(weth.clone(), 300).unwrap().price, U128(330_000));
+        assert_eq!(contract.get_twap(weth.clone(), 601), None);
+        assert_eq!(contract.get_cached_price(weth).unwrap().price, U128(330_000));
+        // 700 ETH at 3150.00 and 300 AURORA at 1.50
+        assert_eq!(
+            contract.get_cached_portfolio_value(accounts(2)),
+            U128(2_205_450)
+        );
+
+        // Past the max age the average can't be extended to now
+        oracle_callback_context(400 + contract.get_price_max_age() + 1, Vec::new());
+        assert_eq!(
+            contract.get_twap(test_assets()[0].asset_id.clone(), 600),
+            None
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Prices are halted until the circuit breaker is acknowledged")]
+    fn test_halted_prices_block_valuation() {
+        let oracles = ["priceoracle.testnet"];
+        let mut contract = contract_with_oracles(&oracles, 1);
+        contract.price_halt = Some(PriceHalt {
+            accepted: PriceFeedInfo {
+                asset_id: test_assets()[0].asset_id.clone(),
+                price: U128(300_000),
+                decimals: 2,
+                last_updated: 0,
+                sources: Vec::new(),
+            },
+            rejected: PriceFeedInfo {
+                asset_id: test_assets()[0].asset_id.clone(),
+                price: U128(400_000),
+                decimals: 2,
+                last_updated: 0,
+                sources: Vec::new(),
+            },
+            halted_at: 0,
+        });
+        contract.get_asset_price(test_assets()[0].asset_id.clone());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a guardian can call this method")]
+    fn test_price_halt_acknowledgement_requires_guardian() {
+        let mut contract = contract_with_oracles(&["priceoracle.testnet"], 1);
+        testing_env!(get_context(accounts(3)).build());
+        contract.acknowledge_price_halt(false);
+    }
+
     #[test]
     #[should_panic(expected = "Only USDC token is accepted")]
     fn test_invalid_token_deposit() {
@@ -579,9 +1400,11 @@ mod tests {
 
         let mut contract = Contract::new(
             accounts(1),
-            vec![],
+            test_assets(),
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
         );
 
         contract.ft_on_transfer(accounts(3), U128(1000), "".to_string());
@@ -596,14 +1419,1467 @@ mod tests {
             accounts(1),
             vec![AssetInfo {
                 name: "ETH".to_string(),
+                asset_id: "weth.fakes.testnet".to_string(),
                 contract_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
                 weight: 70,
+                kind: AssetKind::Erc20,
             }],
             "usdc.testnet".parse().unwrap(),
             "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
         );
 
         let _portfolio_value = contract.get_portfolio_value(accounts(1));
         // Note: Can't fully test portfolio valuation in unit tests due to cross-contract calls
     }
+
+    #[test]
+    fn test_deposit_mints_transferable_shares() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        register(&mut contract, accounts(3));
+
+        testing_env!(get_context(contract.usdc_contract.clone()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(1000));
+        assert_eq!(contract.ft_total_supply(), U128(1000));
+
+        let mut context = get_context(accounts(2));
+        context.attached_deposit(NearToken::from_yoctonear(1));
+        testing_env!(context.build());
+        contract.ft_transfer(accounts(3), U128(400), None);
+
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(600));
+        assert_eq!(contract.ft_balance_of(accounts(3)), U128(400));
+        let moved = contract.get_user_balance(&accounts(3)).unwrap();
+        assert_eq!(
+            moved.get("weth.fakes.testnet"),
+            Some(&U128(280))
+        );
+        assert_eq!(
+            moved.get("aurora.fakes.testnet"),
+            Some(&U128(120))
+        );
+    }
+
+    #[test]
+    fn test_metadata_is_owner_updatable() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        assert_eq!(contract.ft_metadata().symbol, "NXI");
+
+        let mut metadata = fund_metadata();
+        metadata.icon = Some("data:image/svg+xml,<svg></svg>".to_string());
+        contract.set_metadata(metadata);
+        assert!(contract.ft_metadata().icon.is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_metadata_update_requires_owner() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+
+        testing_env!(get_context(accounts(2)).build());
+        contract.set_metadata(fund_metadata());
+    }
+
+    #[test]
+    fn test_deposit_from_unregistered_account_is_refunded() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+
+        testing_env!(get_context(contract.usdc_contract.clone()).build());
+        let result = contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+        assert!(matches!(result, PromiseOrValue::Value(U128(1000))));
+        assert!(contract.get_user_balance(&accounts(2)).is_none());
+        assert_eq!(contract.ft_total_supply(), U128(0));
+    }
+
+    #[test]
+    fn test_storage_bounds_cover_position() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let contract = setup_contract();
+
+        let position_cost = env::storage_byte_cost()
+            .saturating_mul(storage::position_storage_usage(&test_assets()).into());
+        assert!(contract.storage_balance_bounds().min > position_cost);
+    }
+
+    #[test]
+    fn test_deposit_credits_exact_split_and_tracks_dust() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+
+        testing_env!(get_context(contract.usdc_contract.clone()).build());
+        contract.ft_on_transfer(accounts(2), U128(1_000_000_001), "".to_string());
+
+        let balance = contract.get_user_balance(&accounts(2)).unwrap();
+        let credited: u128 = balance.values().map(|b| b.0).sum();
+        assert_eq!(credited, 1_000_000_000);
+        assert_eq!(contract.get_dust(), U128(1));
+        assert_eq!(credited + contract.get_dust().0, 1_000_000_001);
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(credited));
+        assert_eq!(contract.get_total_assets(), U128(1_000_000_001));
+    }
+
+    #[test]
+    fn test_constructor_arguments_are_honored() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.near".parse().unwrap(),
+            "priceoracle.near".parse().unwrap(),
+            fund_metadata(),
+            None,
+        );
+        assert_eq!(
+            contract.get_usdc_contract(),
+            "usdc.near".parse::<AccountId>().unwrap()
+        );
+        assert_eq!(
+            contract.get_oracles(),
+            vec!["priceoracle.near".parse::<AccountId>().unwrap()]
+        );
+        assert_eq!(contract.get_oracle_quorum(), 1);
+
+        contract.set_usdc_contract("usdc.testnet".parse().unwrap());
+        contract.add_oracle("pyth-adapter.near".parse().unwrap());
+        contract.set_oracle_quorum(2);
+        assert_eq!(
+            contract.get_usdc_contract(),
+            "usdc.testnet".parse::<AccountId>().unwrap()
+        );
+        assert_eq!(contract.get_oracles().len(), 2);
+        assert_eq!(contract.get_oracle_quorum(), 2);
+        assert!(near_sdk::test_utils::get_logs()
+            .iter()
+            .any(|log| log.contains("oracle_added")));
+    }
+
+    #[test]
+    #[should_panic(expected = "The fund can't depend on itself")]
+    fn test_constructor_rejects_self_as_usdc() {
+        let context = get_context(accounts(1));
+        testing_env!(context.build());
+
+        Contract::new(
+            accounts(1),
+            test_assets(),
+            env::current_account_id(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_asset_registry() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let weth = RegisteredAsset {
+            asset_id: "weth.fakes.testnet".to_string(),
+            chain_id: 11155111,
+            evm_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
+            near_token: "weth.fakes.testnet".parse().unwrap(),
+            decimals: 18,
+        };
+        let mut contract = Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            Some(vec![weth.clone()]),
+        );
+        assert_eq!(
+            contract.get_registered_asset("weth.fakes.testnet".to_string()),
+            Some(weth)
+        );
+
+        let aurora = RegisteredAsset {
+            asset_id: "aurora.fakes.testnet".to_string(),
+            chain_id: 1313161555,
+            evm_address: "0xe09D8aDae1141181f4CddddeF97E4Cf68f5436E6".to_string(),
+            near_token: "aurora.fakes.testnet".parse().unwrap(),
+            decimals: 18,
+        };
+        contract.add_asset(aurora.clone());
+        contract.update_asset(RegisteredAsset {
+            decimals: 8,
+            ..aurora
+        });
+        assert_eq!(contract.get_registered_assets(None, None).len(), 2);
+        assert_eq!(
+            contract
+                .find_asset_by_address(AURORA_TESTNET, "0xe09d8adae1141181f4cddddef97e4cf68f5436e6")
+                .map(|asset| asset.decimals),
+            Some(8)
+        );
+    }
+
+    fn native_asset(asset_id: &str, chain_id: u64) -> RegisteredAsset {
+        RegisteredAsset {
+            asset_id: asset_id.to_string(),
+            chain_id,
+            evm_address: NATIVE_ASSET_ADDRESS.to_string(),
+            near_token: asset_id.parse().unwrap(),
+            decimals: 18,
+        }
+    }
+
+    #[test]
+    fn test_native_asset_is_registered_on_every_chain() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            Some(vec![native_asset("eth.fakes.testnet", SEPOLIA)]),
+        );
+        contract.add_asset(native_asset("aurora-eth.fakes.testnet", AURORA_TESTNET));
+        assert_eq!(
+            contract
+                .find_asset_by_address(AURORA_TESTNET, NATIVE_ASSET_ADDRESS)
+                .map(|asset| asset.asset_id.as_str()),
+            Some("aurora-eth.fakes.testnet")
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "is already registered on chain 11155111 as eth.fakes.testnet")]
+    fn test_address_is_registered_once_per_chain() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            Some(vec![native_asset("eth.fakes.testnet", SEPOLIA)]),
+        );
+        contract.add_asset(native_asset("weth.fakes.testnet", SEPOLIA));
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset is held by the fund")]
+    fn test_remove_held_asset() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = Contract::new(
+            accounts(1),
+            test_assets(),
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            Some(vec![RegisteredAsset {
+                asset_id: "weth.fakes.testnet".to_string(),
+                chain_id: 11155111,
+                evm_address: "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87".to_string(),
+                near_token: "weth.fakes.testnet".parse().unwrap(),
+                decimals: 18,
+            }]),
+        );
+
+        contract.remove_asset("weth.fakes.testnet".to_string());
+    }
+
+    #[test]
+    fn test_withdrawal_debits_up_front_and_refunds_failed_legs() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: vec![NetworkDetails {
+                chain_id: SEPOLIA,
+                max_priority_fee_per_gas: Some(1000000000),
+                max_fee_per_gas: Some(2000000000),
+                gas_limit: Some(100000),
+                access_list: vec![],
+            }],
+            amount: None,
+        });
+
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(0));
+        assert!(contract.get_user_balance(&accounts(2)).is_none());
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.account_id, accounts(2));
+        assert_eq!(withdrawal.legs.len(), 2);
+        assert!(withdrawal
+            .legs
+            .iter()
+            .all(|leg| leg.status == WithdrawalStatus::Pending));
+
+        testing_env!(get_context(env::current_account_id()).build());
+        let signed = contract.sign_callback(
+            0,
+            1,
+            EVMTransactionWrapper {
+                chain_id: 1,
+                nonce: 0,
+                to: None,
+                value: 0,
+                input: vec![],
+                gas_limit: 100000,
+                max_fee_per_gas: 2000000000,
+                max_priority_fee_per_gas: 1000000000,
+                access_list: vec![],
+                tx_type: TxType::DynamicFee,
+            },
+            Err(PromiseError::Failed),
+        );
+        assert!(signed.is_none());
+
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[0].status, WithdrawalStatus::Pending);
+        assert_eq!(withdrawal.legs[1].status, WithdrawalStatus::Refunded);
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(300));
+        assert_eq!(
+            contract
+                .get_user_balance(&accounts(2))
+                .unwrap()
+                .get("aurora.fakes.testnet"),
+            Some(&U128(300))
+        );
+        // The refunded leg held the latest nonce of its treasury, so it is reused
+        assert_eq!(contract.get_next_nonce(AURORA_TESTNET, AURORA_TREASURY_PATH.to_string()), 0);
+        assert_eq!(contract.get_next_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string()), 1);
+    }
+
+    #[test]
+    fn test_partial_withdrawal_matches_preview() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        let preview =
+            contract.preview_withdraw(accounts(2), Some(WithdrawAmount::BasisPoints(2_500)));
+        assert_eq!(
+            preview
+                .iter()
+                .map(|leg| (leg.treasury_path.as_str(), leg.amount.0))
+                .collect::<Vec<_>>(),
+            vec![(ETH_TREASURY_PATH, 175), (AURORA_TREASURY_PATH, 75)]
+        );
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: vec![NetworkDetails {
+                chain_id: SEPOLIA,
+                max_priority_fee_per_gas: Some(1000000000),
+                max_fee_per_gas: Some(2000000000),
+                gas_limit: Some(100000),
+                access_list: vec![],
+            }],
+            amount: Some(WithdrawAmount::BasisPoints(2_500)),
+        });
+
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        for (leg, previewed) in withdrawal.legs.iter().zip(preview.iter()) {
+            assert_eq!(leg.asset_id, previewed.asset_id);
+            assert_eq!(leg.amount, previewed.amount);
+        }
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(750));
+        let remaining: u128 = contract
+            .get_user_balance(&accounts(2))
+            .unwrap()
+            .values()
+            .map(|balance| balance.0)
+            .sum();
+        assert_eq!(remaining, 750);
+    }
+
+    #[test]
+    #[should_panic(expected = "Not enough shares")]
+    fn test_withdraw_more_shares_than_held() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        contract.preview_withdraw(accounts(2), Some(WithdrawAmount::Shares(U128(1001))));
+    }
+
+    #[test]
+    fn test_withdrawals_get_sequential_nonces() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        for _ in 0..2 {
+            let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+                destinations: test_destinations(),
+                network_details: Vec::new(),
+                amount: Some(WithdrawAmount::Shares(U128(100))),
+            });
+        }
+
+        let nonces = |id| {
+            contract
+                .get_withdrawal(id)
+                .unwrap()
+                .legs
+                .iter()
+                .map(|leg| (leg.treasury_path.clone(), leg.nonce))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            nonces(0),
+            vec![
+                (ETH_TREASURY_PATH.to_string(), Some(0)),
+                (AURORA_TREASURY_PATH.to_string(), Some(0))
+            ]
+        );
+        assert_eq!(
+            nonces(1),
+            vec![
+                (ETH_TREASURY_PATH.to_string(), Some(1)),
+                (AURORA_TREASURY_PATH.to_string(), Some(1))
+            ]
+        );
+        assert_eq!(contract.get_next_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string()), 2);
+        assert_eq!(contract.get_next_nonce(1, ETH_TREASURY_PATH.to_string()), 0);
+
+        testing_env!(get_context(accounts(1)).build());
+        contract.add_relayer(accounts(3));
+        testing_env!(get_context(accounts(3)).build());
+        contract.resync_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string(), 7);
+        assert_eq!(contract.get_next_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string()), 7);
+    }
+
+    #[test]
+    fn test_native_asset_is_withdrawn_as_value_transfer() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut assets = test_assets();
+        assets[0].asset_id = "eth.fakes.testnet".to_string();
+        assets[0].contract_address = NATIVE_ASSET_ADDRESS.to_string();
+        assets[0].kind = AssetKind::Native;
+        let mut contract = Contract::new(
+            accounts(1),
+            assets,
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
+        );
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        contract.add_asset(RegisteredAsset {
+            asset_id: "eth.fakes.testnet".to_string(),
+            chain_id: SEPOLIA,
+            evm_address: NATIVE_ASSET_ADDRESS.to_string(),
+            near_token: "eth.fakes.testnet".parse().unwrap(),
+            decimals: 18,
+        });
+        let mut sepolia = contract.get_chain(SEPOLIA).unwrap();
+        sepolia.supported_assets.push("eth.fakes.testnet".to_string());
+        contract.update_chain(sepolia);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        let leg = &withdrawal.legs[0];
+        assert_eq!(leg.kind, AssetKind::Native);
+        assert_eq!(leg.asset, NATIVE_ASSET_ADDRESS);
+        assert_eq!(withdrawal.legs[1].kind, AssetKind::Erc20);
+
+        let tx = contract.construct_native_transfer_tx(
+            leg.destination.clone(),
+            leg.amount.0,
+            leg.nonce.unwrap(),
+            NetworkDetails::defaults(SEPOLIA),
+        );
+        assert_eq!(tx.to, Some(parse_eth_address(&test_destinations()[&SEPOLIA])));
+        assert_eq!(tx.value, 700);
+        assert!(tx.input.is_empty());
+        assert_eq!(tx.gas_limit, 21_000);
+        assert_eq!(tx.chain_id, SEPOLIA);
+    }
+
+    #[test]
+    #[should_panic(expected = "Legacy transactions can't carry an access list")]
+    fn test_legacy_chain_rejects_access_list() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        let mut sepolia = contract.get_chain(SEPOLIA).unwrap();
+        sepolia.tx_type = TxType::Legacy;
+        contract.update_chain(sepolia);
+        assert_eq!(contract.get_chain(SEPOLIA).unwrap().tx_type, TxType::Legacy);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let mut network_details = NetworkDetails::defaults(SEPOLIA);
+        network_details.access_list = vec![([0x35; 20], vec![[0; 32]])];
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: vec![network_details],
+            amount: None,
+        });
+    }
+
+    #[test]
+    fn test_aurora_engine_withdrawal_finalizes_in_callback() {
+        testing_env!(get_context(accounts(1)).build());
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        let mut aurora = contract.get_chain(AURORA_TESTNET).unwrap();
+        aurora.kind = ChainKind::AuroraEngine {
+            engine: "aurora".parse().unwrap(),
+        };
+        contract.update_chain(aurora);
+        // The fund's own address rather than the one derived for the treasury path
+        assert_eq!(
+            contract.get_treasury_address(AURORA_TESTNET).to_lowercase(),
+            "0x10315b5be6b5369e2188c8d7b18ec932c936a21e"
+        );
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        let withdraw_half = |contract: &mut Contract| {
+            testing_env!(get_context(accounts(2)).build());
+            let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+                destinations: test_destinations(),
+                network_details: Vec::new(),
+                amount: Some(WithdrawAmount::BasisPoints(5000)),
+            });
+        };
+        let callback_context = |result| {
+            testing_env!(
+                get_context(env::current_account_id()).build(),
+                near_sdk::test_vm_config(),
+                near_sdk::RuntimeFeesConfig::test(),
+                Default::default(),
+                vec![result]
+            );
+        };
+
+        // Engine executed the transfer, which returned true
+        withdraw_half(&mut contract);
+        let mut submit_result = vec![7, 0, 32, 0, 0, 0];
+        submit_result.extend([0; 31]);
+        submit_result.push(1);
+        submit_result.extend(21_000u64.to_le_bytes());
+        submit_result.extend(0u32.to_le_bytes());
+        callback_context(near_sdk::PromiseResult::Successful(submit_result));
+        assert!(contract.aurora_call_callback(0, 1));
+        let leg = &contract.get_withdrawal(0).unwrap().legs[1];
+        assert_eq!(leg.amount, U128(150));
+        assert_eq!(leg.status, WithdrawalStatus::Confirmed);
+        assert_eq!(leg.nonce, None);
+        assert!(contract.get_signed_txs(None, None, None, None, None).is_empty());
+
+        // Engine call failed, the leg is credited back
+        withdraw_half(&mut contract);
+        callback_context(near_sdk::PromiseResult::Failed);
+        assert!(!contract.aurora_call_callback(1, 1));
+        assert_eq!(
+            contract.get_withdrawal(1).unwrap().legs[1].status,
+            WithdrawalStatus::Refunded
+        );
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(325));
+    }
+
+    #[test]
+    fn test_bitcoin_withdrawal_spends_reported_utxos() {
+        // Bitcoin has no chain id, the registry only needs a unique one
+        const BITCOIN_TESTNET: u64 = 18332;
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut assets = test_assets();
+        assets[1] = AssetInfo {
+            name: "BTC".to_string(),
+            asset_id: "btc.fakes.testnet".to_string(),
+            contract_address: NATIVE_ASSET_ADDRESS.to_string(),
+            weight: 30,
+            kind: AssetKind::Native,
+        };
+        let mut contract = Contract::new(
+            accounts(1),
+            assets,
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
+        );
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        contract.add_relayer(accounts(3));
+        contract.add_asset(RegisteredAsset {
+            asset_id: "btc.fakes.testnet".to_string(),
+            chain_id: BITCOIN_TESTNET,
+            evm_address: NATIVE_ASSET_ADDRESS.to_string(),
+            near_token: "btc.fakes.testnet".parse().unwrap(),
+            decimals: 8,
+        });
+        let mut bitcoin = contract.get_chain(SEPOLIA).unwrap();
+        bitcoin.chain_id = BITCOIN_TESTNET;
+        bitcoin.name = "Bitcoin Testnet".to_string();
+        bitcoin.kind = ChainKind::Bitcoin {
+            hrp: "tb".to_string(),
+        };
+        bitcoin.treasury_path = "btc-treasury".to_string();
+        bitcoin.supported_assets = vec!["btc.fakes.testnet".to_string()];
+        contract.add_chain(bitcoin);
+        // Satoshis per virtual byte
+        contract.update_fee_quote(BITCOIN_TESTNET, U128(2), U128(1));
+        assert!(contract
+            .get_treasury_address(BITCOIN_TESTNET)
+            .starts_with("tb1q"));
+
+        let utxo = |byte: u8, value| Utxo {
+            txid: hex::encode([byte; 32]),
+            vout: 0,
+            value: near_sdk::json_types::U64(value),
+        };
+        let utxos = vec![utxo(1, 400), utxo(2, 10_000), utxo(3, 5_000)];
+        testing_env!(get_context(accounts(3)).build());
+        contract.report_utxos(BITCOIN_TESTNET, "btc-treasury".to_string(), utxos.clone());
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let mut destinations = test_destinations();
+        destinations.insert(
+            BITCOIN_TESTNET,
+            "tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx".to_string(),
+        );
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations,
+            network_details: Vec::new(),
+            amount: None,
+        });
+
+        // Signing both inputs doesn't fit next to the Sepolia leg, which is sent on its own
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[0].status, WithdrawalStatus::Pending);
+        assert_eq!(withdrawal.legs[1].status, WithdrawalStatus::Queued);
+        assert_eq!(contract.get_utxos(BITCOIN_TESTNET, "btc-treasury".to_string()), utxos);
+        let _signing = contract.sign_queued_legs(0, Vec::new());
+
+        // 400 sats can't pay for 300 sats and the fee, so the next output is spent as well
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[1].status, WithdrawalStatus::Pending);
+        assert_eq!(withdrawal.legs[1].chain_id, BITCOIN_TESTNET);
+        assert_eq!(withdrawal.legs[1].amount, U128(300));
+        // Bitcoin has no account nonce, so none is taken from the treasury's counter
+        assert_eq!(withdrawal.legs[1].nonce, None);
+        assert_eq!(contract.get_next_nonce(BITCOIN_TESTNET, "btc-treasury".to_string()), 0);
+        assert_eq!(
+            contract.get_utxos(BITCOIN_TESTNET, "btc-treasury".to_string()),
+            vec![utxo(3, 5_000)]
+        );
+
+        // Reporting the picked outputs again before their transaction is broadcast doesn't
+        // put them back in the set
+        testing_env!(get_context(accounts(3)).build());
+        contract.report_utxos(BITCOIN_TESTNET, "btc-treasury".to_string(), utxos.clone());
+        assert_eq!(
+            contract.get_utxos(BITCOIN_TESTNET, "btc-treasury".to_string()),
+            vec![utxo(3, 5_000)]
+        );
+
+        // A failed signature puts the outputs back and refunds the leg
+        testing_env!(
+            get_context(env::current_account_id()).build(),
+            near_sdk::test_vm_config(),
+            near_sdk::RuntimeFeesConfig::test(),
+            Default::default(),
+            vec![near_sdk::PromiseResult::Failed, near_sdk::PromiseResult::Failed]
+        );
+        let signed = contract.bitcoin_sign_callback(
+            0,
+            1,
+            bitcoin::BitcoinSpend {
+                inputs: utxos[..2].to_vec(),
+                outputs: Vec::new(),
+            },
+        );
+        assert!(signed.is_none());
+        assert_eq!(
+            contract.get_withdrawal(0).unwrap().legs[1].status,
+            WithdrawalStatus::Refunded
+        );
+        assert_eq!(
+            contract.get_utxos(BITCOIN_TESTNET, "btc-treasury".to_string()),
+            utxos
+        );
+    }
+
+    #[test]
+    fn test_legs_beyond_gas_budget_are_queued() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut assets = test_assets();
+        assets[0].weight = 50;
+        assets.push(AssetInfo {
+            name: "USDC".to_string(),
+            asset_id: "usdc.fakes.testnet".to_string(),
+            contract_address: "0xf08a50178dfcde18524640ea6618a1f965821715".to_string(),
+            weight: 20,
+            kind: AssetKind::Erc20,
+        });
+        let mut contract = Contract::new(
+            accounts(1),
+            assets,
+            "usdc.testnet".parse().unwrap(),
+            "priceoracle.testnet".parse().unwrap(),
+            fund_metadata(),
+            None,
+        );
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        contract.add_relayer(accounts(3));
+        contract.add_asset(RegisteredAsset {
+            asset_id: "usdc.fakes.testnet".to_string(),
+            chain_id: SEPOLIA,
+            evm_address: "0xf08a50178dfcde18524640ea6618a1f965821715".to_string(),
+            near_token: "usdc.fakes.testnet".parse().unwrap(),
+            decimals: 6,
+        });
+        let mut sepolia = contract.get_chain(SEPOLIA).unwrap();
+        sepolia.supported_assets.push("usdc.fakes.testnet".to_string());
+        contract.update_chain(sepolia);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        // Three legs of 110 Tgas don't fit in one call
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+        let statuses = |contract: &Contract| {
+            contract
+                .get_withdrawal(0)
+                .unwrap()
+                .legs
+                .iter()
+                .map(|leg| leg.status.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            statuses(&contract),
+            vec![
+                WithdrawalStatus::Pending,
+                WithdrawalStatus::Pending,
+                WithdrawalStatus::Queued
+            ]
+        );
+        assert_eq!(contract.get_next_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string()), 1);
+
+        let _signing = contract.sign_queued_legs(0, Vec::new());
+        assert!(statuses(&contract)
+            .iter()
+            .all(|status| *status == WithdrawalStatus::Pending));
+        assert_eq!(contract.get_withdrawal(0).unwrap().legs[2].nonce, Some(1));
+
+        // The first leg's callback never ran; a day later, once its nonce is used up, a
+        // relayer credits it back
+        let mut context = get_context(accounts(3));
+        context.block_timestamp(STUCK_LEG_TIMEOUT_SEC * 1_000_000_000);
+        testing_env!(context.build());
+        contract.resync_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string(), 2);
+        contract.refund_stuck_leg(0, 0);
+        assert_eq!(statuses(&contract)[0], WithdrawalStatus::Refunded);
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(500));
+    }
+
+    #[test]
+    #[should_panic(expected = "Withdrawal leg is not stuck yet")]
+    fn test_pending_leg_is_not_refunded_before_timeout() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+
+        testing_env!(get_context(accounts(1)).build());
+        contract.refund_stuck_leg(0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Nonce of the leg must be used up by a resync before it is refunded")]
+    fn test_stuck_leg_is_not_refunded_while_its_nonce_is_unused() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+
+        let mut context = get_context(accounts(1));
+        context.block_timestamp(STUCK_LEG_TIMEOUT_SEC * 1_000_000_000);
+        testing_env!(context.build());
+        contract.resync_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string(), 0);
+        contract.refund_stuck_leg(0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a relayer can call this method")]
+    fn test_resync_nonce_requires_relayer() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+
+        testing_env!(get_context(accounts(2)).build());
+        contract.resync_nonce(SEPOLIA, ETH_TREASURY_PATH.to_string(), 7);
+    }
+
+    #[test]
+    fn test_gas_fees_follow_policy() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+        assert!(near_sdk::test_utils::get_logs()
+            .iter()
+            .any(|log| log.contains("fee_quote_updated") && log.contains("\"2000000000\"")));
+
+        let details = |max_fee_per_gas, gas_limit| NetworkDetails {
+            chain_id: SEPOLIA,
+            max_priority_fee_per_gas: None,
+            max_fee_per_gas,
+            gas_limit,
+            access_list: vec![],
+        };
+        assert_eq!(
+            contract.internal_gas_fees(&details(None, None), CallType::Erc20Transfer),
+            fees::GasFees {
+                max_fee_per_gas: 2_000_000_000,
+                max_priority_fee_per_gas: 1_000_000_000,
+                gas_limit: 65_000,
+            }
+        );
+        let outbid = contract.internal_gas_fees(
+            &details(Some(5_000_000_000), Some(90_000)),
+            CallType::Erc20Transfer,
+        );
+        assert_eq!(outbid.max_fee_per_gas, 5_000_000_000);
+        assert_eq!(outbid.gas_limit, 90_000);
+
+        // An address and two storage keys cost 2400 + 2 * 1900 on top of the transfer
+        let mut listed = details(None, None);
+        listed.access_list = vec![([0x35; 20], vec![[0; 32], [1; 32]])];
+        assert_eq!(
+            contract
+                .internal_gas_fees(&listed, CallType::Erc20Transfer)
+                .gas_limit,
+            71_200
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Access list can't hold more than 16 addresses and storage keys")]
+    fn test_access_list_size_is_capped() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        let mut network_details = NetworkDetails::defaults(SEPOLIA);
+        network_details.access_list = vec![([0x35; 20], vec![[0; 32]; 16])];
+        contract.internal_gas_fees(&network_details, CallType::Erc20Transfer);
+    }
+
+    #[test]
+    #[should_panic(expected = "Max fee per gas exceeds the fee policy")]
+    fn test_gas_fees_above_cap_are_rejected() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        contract.internal_gas_fees(
+            &NetworkDetails {
+                chain_id: SEPOLIA,
+                max_priority_fee_per_gas: None,
+                max_fee_per_gas: Some(1_000_000_000_000),
+                gas_limit: None,
+                access_list: vec![],
+            },
+            CallType::Erc20Transfer,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Fee quote for chain 11155111 is stale")]
+    fn test_stale_fee_quote_is_rejected() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        let mut context = get_context(accounts(2));
+        context.block_timestamp(301 * 1_000_000_000);
+        testing_env!(context.build());
+        contract.internal_gas_fees(
+            &NetworkDetails {
+                chain_id: SEPOLIA,
+                max_priority_fee_per_gas: None,
+                max_fee_per_gas: None,
+                gas_limit: None,
+                access_list: vec![],
+            },
+            CallType::Erc20Transfer,
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "No destination for chain 1313161555")]
+    fn test_withdrawal_requires_destination_per_chain() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let mut destinations = test_destinations();
+        destinations.remove(&AURORA_TESTNET);
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations,
+            network_details: Vec::new(),
+            amount: None,
+        });
+    }
+
+    #[test]
+    fn test_chain_registry() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        let sepolia = contract.get_chain(SEPOLIA).unwrap();
+        assert_eq!(sepolia.treasury_path, ETH_TREASURY_PATH);
+        assert_eq!(contract.get_chains(None, None).len(), 2);
+        assert_eq!(
+            contract
+                .withdrawal_chain("weth.fakes.testnet")
+                .chain_id,
+            SEPOLIA
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Asset aurora.fakes.testnet is registered on another chain")]
+    fn test_chain_rejects_assets_of_other_chains() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        let mut sepolia = contract.get_chain(SEPOLIA).unwrap();
+        sepolia
+            .supported_assets
+            .push("aurora.fakes.testnet".to_string());
+        contract.update_chain(sepolia);
+    }
+
+    #[test]
+    #[should_panic(expected = "Chain has assets held by the fund")]
+    fn test_remove_chain_with_held_assets() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        contract.remove_chain(AURORA_TESTNET);
+    }
+
+    #[test]
+    fn test_treasury_address_is_derived_from_mpc_key() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+
+        let mut context = get_context(accounts(2));
+        context.current_account_id("fund.testnet".parse().unwrap());
+        testing_env!(context.build());
+        assert_eq!(
+            contract.get_treasury_address(SEPOLIA),
+            "0x20846Bf17144F7d6aE9f94be743259E216db2135"
+        );
+        assert_eq!(
+            contract.get_derived_address(AURORA_TREASURY_PATH.to_string()),
+            "0x8965DfAC66e47E22a6eD8F7D4544FCe341C93728"
+        );
+    }
+
+    #[test]
+    fn test_treasury_keys_follow_mpc_key() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        add_test_chains(&mut contract);
+        let cached_address = |contract: &Contract| {
+            kdf::to_checksum_address(&contract.internal_treasury_key(ETH_TREASURY_PATH).evm_address)
+        };
+        let derived = contract.get_derived_address(ETH_TREASURY_PATH.to_string());
+        assert_eq!(cached_address(&contract), derived);
+
+        // The generator point, the key of private key 1
+        let generator = hex::decode(concat!(
+            "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8"
+        ))
+        .unwrap();
+        contract.set_mpc_public_key(
+            PublicKey::from_parts(near_sdk::CurveType::SECP256K1, generator).unwrap(),
+        );
+        assert_ne!(cached_address(&contract), derived);
+        assert_eq!(
+            cached_address(&contract),
+            contract.get_derived_address(ETH_TREASURY_PATH.to_string())
+        );
+    }
+
+    #[test]
+    fn test_signature_from_another_key_is_refunded() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+
+        // A well-formed signature, but not by the Sepolia treasury key
+        let big_r = "03bb50e2d89a4ed70663d080659fe0ad4b9bc3e06c17a227433966cb59ceee020d";
+        let s = "be8ed28da0162c4688056d7140f46204df307e3f8eb067e3fa4c9a1d530aaa7c";
+        testing_env!(get_context(env::current_account_id()).build());
+        let signed = contract.sign_callback(
+            0,
+            0,
+            EVMTransactionWrapper {
+                chain_id: SEPOLIA,
+                nonce: 0,
+                to: None,
+                value: 0,
+                input: vec![],
+                gas_limit: 65000,
+                max_fee_per_gas: 2000000000,
+                max_priority_fee_per_gas: 1000000000,
+                access_list: vec![],
+                tx_type: TxType::DynamicFee,
+            },
+            Ok(SignResult {
+                big_r: signer::AffinePoint {
+                    affine_point: big_r.to_string(),
+                },
+                s: signer::Scalar {
+                    scalar: s.to_string(),
+                },
+                recovery_id: 1,
+            }),
+        );
+
+        assert!(signed.is_none());
+        assert!(contract
+            .get_signed_txs(None, None, None, None, None)
+            .is_empty());
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[0].status, WithdrawalStatus::Refunded);
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(700));
+    }
+
+    #[test]
+    fn test_signed_tx_ledger() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        contract.add_relayer(accounts(3));
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+        let eth_tx = record_signed_tx(&mut contract, 0, vec![0x02, 0xf8]);
+        let aurora_tx = record_signed_tx(&mut contract, 1, vec![0x02, 0xf9]);
+        contract.internal_sign_leg(0, 0);
+        contract.internal_sign_leg(0, 1);
+
+        let signed_tx = contract.get_signed_tx(eth_tx).unwrap();
+        assert_eq!(signed_tx.account_id, Some(accounts(2)));
+        assert_eq!(signed_tx.chain_id, SEPOLIA);
+        assert_eq!(signed_tx.status, ledger::SignedTxStatus::Pending);
+        assert_eq!(
+            signed_tx.tx_hash,
+            format!("0x{}", hex::encode(env::keccak256_array(&[0x02, 0xf8])))
+        );
+        let on_aurora = contract.get_signed_txs(None, None, Some(AURORA_TESTNET), None, None);
+        assert_eq!(on_aurora.len(), 1);
+        assert_eq!(on_aurora[0].id, aurora_tx);
+        // A page reads `limit` transactions, and the chain filter applies to those
+        assert!(contract
+            .get_signed_txs(None, None, Some(AURORA_TESTNET), None, Some(1))
+            .is_empty());
+        assert_eq!(
+            contract
+                .get_signed_txs(None, None, Some(AURORA_TESTNET), Some(1), Some(1))
+                .len(),
+            1
+        );
+
+        testing_env!(get_context(accounts(3)).build());
+        let tx_hash = contract.get_signed_tx(eth_tx).unwrap().tx_hash;
+        contract.report_broadcast(eth_tx, tx_hash);
+        contract.report_confirmation(eth_tx, 100, true);
+        let confirmed = Some(ledger::SignedTxStatus::Confirmed);
+        assert_eq!(
+            contract
+                .get_signed_txs(Some(accounts(2)), confirmed, None, None, None)
+                .len(),
+            1
+        );
+        let pending = Some(ledger::SignedTxStatus::Pending);
+        let still_pending = contract.get_signed_txs(None, pending, None, None, None);
+        assert_eq!(still_pending.len(), 1);
+        assert_eq!(still_pending[0].id, aurora_tx);
+
+        assert_eq!(contract.prune_signed_txs(None), 1);
+        assert!(contract.get_signed_tx(eth_tx).is_none());
+        assert_eq!(
+            contract.get_signed_txs(None, None, None, None, None).len(),
+            1
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Signed transaction can't go from Pending to Confirmed")]
+    fn test_signed_tx_must_be_broadcast_before_confirmation() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+        let tx_id = record_signed_tx(&mut contract, 0, vec![0x02, 0xf8]);
+
+        testing_env!(get_context(accounts(1)).build());
+        contract.report_confirmation(tx_id, 100, true);
+    }
+
+    #[test]
+    fn test_failed_transaction_is_signed_again() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        contract.add_relayer(accounts(3));
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+        for leg_index in 0..2 {
+            record_signed_tx(&mut contract, leg_index, vec![0x02, leg_index as u8]);
+            contract.internal_sign_leg(0, leg_index);
+        }
+
+        testing_env!(get_context(accounts(3)).build());
+        for tx_id in 0..2 {
+            let tx_hash = contract.get_signed_tx(tx_id).unwrap().tx_hash;
+            contract.report_broadcast(tx_id, tx_hash.to_uppercase().replace("0X", "0x"));
+        }
+        contract.report_confirmation(0, 100, true);
+        contract.report_confirmation(1, 101, false);
+
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[0].status, WithdrawalStatus::Confirmed);
+        assert_eq!(withdrawal.legs[1].status, WithdrawalStatus::Failed);
+        assert_eq!(withdrawal.finalized_at, None);
+        assert_eq!(contract.get_signed_tx(1).unwrap().block_number, Some(101));
+
+        let _retry = contract.retry_withdrawal_leg(0, 1, None);
+        let leg = &contract.get_withdrawal(0).unwrap().legs[1];
+        assert_eq!(leg.status, WithdrawalStatus::Pending);
+        assert_eq!(leg.nonce, Some(1));
+
+        // Signing the retry fails too, which refunds the leg and settles the withdrawal
+        testing_env!(get_context(env::current_account_id()).build());
+        contract.sign_callback(
+            0,
+            1,
+            EVMTransactionWrapper {
+                chain_id: AURORA_TESTNET,
+                nonce: 1,
+                to: None,
+                value: 0,
+                input: vec![],
+                gas_limit: 65000,
+                max_fee_per_gas: 2000000000,
+                max_priority_fee_per_gas: 1000000000,
+                access_list: vec![],
+                tx_type: TxType::DynamicFee,
+            },
+            Err(PromiseError::Failed),
+        );
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[1].status, WithdrawalStatus::Refunded);
+        assert!(withdrawal.finalized_at.is_some());
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(300));
+    }
+
+    /// Contract with a withdrawal of `accounts(2)` whose Aurora leg was broadcast and dropped.
+    fn contract_with_dropped_leg() -> Contract {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+        contract.add_relayer(accounts(3));
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+        record_signed_tx(&mut contract, 1, vec![0x02, 0x01]);
+        contract.internal_sign_leg(0, 1);
+
+        testing_env!(get_context(accounts(3)).build());
+        let tx_hash = contract.get_signed_tx(0).unwrap().tx_hash;
+        contract.report_broadcast(0, tx_hash);
+        contract.report_dropped(0);
+        contract
+    }
+
+    #[test]
+    fn test_dropped_transaction_is_signed_again_at_its_nonce() {
+        let mut contract = contract_with_dropped_leg();
+        let signed_tx = contract.get_signed_tx(0).unwrap();
+        assert_eq!(signed_tx.status, ledger::SignedTxStatus::Dropped);
+        assert!(signed_tx.raw_tx.is_empty());
+        assert_eq!(
+            contract.get_withdrawal(0).unwrap().legs[1].status,
+            WithdrawalStatus::Failed
+        );
+
+        // The dropped transaction can still be mined, so the retry takes its nonce
+        let _retry = contract.retry_withdrawal_leg(0, 1, None);
+        assert_eq!(contract.get_withdrawal(0).unwrap().legs[1].nonce, Some(0));
+        assert_eq!(
+            contract.get_next_nonce(AURORA_TESTNET, AURORA_TREASURY_PATH.to_string()),
+            1
+        );
+
+        // Failing to sign it again leaves the leg to retry instead of refunding it
+        testing_env!(get_context(env::current_account_id()).build());
+        contract.sign_callback(
+            0,
+            1,
+            EVMTransactionWrapper {
+                chain_id: AURORA_TESTNET,
+                nonce: 0,
+                to: None,
+                value: 0,
+                input: vec![],
+                gas_limit: 65000,
+                max_fee_per_gas: 2000000000,
+                max_priority_fee_per_gas: 1000000000,
+                access_list: vec![],
+                tx_type: TxType::DynamicFee,
+            },
+            Err(PromiseError::Failed),
+        );
+        assert_eq!(
+            contract.get_withdrawal(0).unwrap().legs[1].status,
+            WithdrawalStatus::Failed
+        );
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(0));
+
+        // Resyncing the treasury to the dropped nonce leaves it unused, so the retry keeps it
+        // and the counter moves past it
+        testing_env!(get_context(accounts(3)).build());
+        contract.resync_nonce(AURORA_TESTNET, AURORA_TREASURY_PATH.to_string(), 0);
+        let _retry = contract.retry_withdrawal_leg(0, 1, None);
+        assert_eq!(contract.get_withdrawal(0).unwrap().legs[1].nonce, Some(0));
+        assert_eq!(
+            contract.get_next_nonce(AURORA_TESTNET, AURORA_TREASURY_PATH.to_string()),
+            1
+        );
+
+        // Once the treasury is resynced past it the nonce is used up, and a new one is taken
+        contract.internal_fail_leg(0, 1, WithdrawalStatus::Pending);
+        contract.resync_nonce(AURORA_TESTNET, AURORA_TREASURY_PATH.to_string(), 1);
+        let _retry = contract.retry_withdrawal_leg(0, 1, None);
+        assert_eq!(contract.get_withdrawal(0).unwrap().legs[1].nonce, Some(1));
+        assert_eq!(
+            contract.get_next_nonce(AURORA_TESTNET, AURORA_TREASURY_PATH.to_string()),
+            2
+        );
+    }
+
+    #[test]
+    fn test_dropped_transaction_mined_late_settles_its_leg() {
+        let mut contract = contract_with_dropped_leg();
+        let _retry = contract.retry_withdrawal_leg(0, 1, None);
+        record_signed_tx(&mut contract, 1, vec![0x02, 0x02]);
+        contract.internal_sign_leg(0, 1);
+
+        // The dropped transaction is mined after all, so the one signed at its nonce can't be
+        contract.report_confirmation(0, 100, true);
+        let withdrawal = contract.get_withdrawal(0).unwrap();
+        assert_eq!(withdrawal.legs[1].status, WithdrawalStatus::Confirmed);
+        assert_eq!(withdrawal.legs[1].tx_id, Some(0));
+        assert_eq!(
+            contract.get_signed_tx(0).unwrap().status,
+            ledger::SignedTxStatus::Confirmed
+        );
+        let replaced = contract.get_signed_tx(1).unwrap();
+        assert_eq!(replaced.status, ledger::SignedTxStatus::Dropped);
+        assert!(replaced.raw_tx.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "A transaction signed for the leg may still be mined")]
+    fn test_dropped_leg_is_not_refunded() {
+        let mut contract = contract_with_dropped_leg();
+        let _retry = contract.retry_withdrawal_leg(0, 1, None);
+
+        let mut context = get_context(accounts(3));
+        context.block_timestamp(STUCK_LEG_TIMEOUT_SEC * 1_000_000_000);
+        testing_env!(context.build());
+        contract.refund_stuck_leg(0, 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "Transaction hash doesn't match the signed transaction")]
+    fn test_broadcast_hash_must_match() {
+        testing_env!(get_context(accounts(1)).build());
+
+        let mut contract = setup_contract();
+        register(&mut contract, accounts(2));
+        add_test_chains(&mut contract);
+
+        testing_env!(get_context("usdc.testnet".parse().unwrap()).build());
+        contract.ft_on_transfer(accounts(2), U128(1000), "".to_string());
+
+        testing_env!(get_context(accounts(2)).build());
+        let _withdrawal = contract.withdraw_underlying_assets(WithdrawRequest {
+            destinations: test_destinations(),
+            network_details: Vec::new(),
+            amount: None,
+        });
+        let tx_id = record_signed_tx(&mut contract, 0, vec![0x02, 0xf8]);
+
+        testing_env!(get_context(accounts(1)).build());
+        contract.report_broadcast(tx_id, format!("0x{}", "00".repeat(32)));
+    }
 }
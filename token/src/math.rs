@@ -0,0 +1,101 @@
+//! Exact integer arithmetic for the deposit split and portfolio valuation.
+//!
+//! Intermediate products are computed in 256 bits so they never overflow, and every division
+//! rounds down, i.e. in favor of the fund: a depositor is never credited, and a position is never
+//! valued, above the exact rational result. Whatever rounding leaves behind is returned to the
+//! caller as dust so it can be accounted for instead of silently disappearing.
+
+use near_sdk::env;
+
+pub use u256::U256;
+
+// The macro expansion trips lints that are out of our hands
+#[allow(
+    clippy::all,
+    deprecated,
+    semicolon_in_expressions_from_non_local_macros
+)]
+mod u256 {
+    uint::construct_uint! {
+        /// 256-bit unsigned integer used for intermediate products.
+        pub struct U256(4);
+    }
+}
+
+/// Asset weights are expressed in whole percent.
+pub const WEIGHT_DENOMINATOR: u128 = 100;
+
+/// Computes `a * b / denominator`, rounded down.
+pub fn mul_div_floor(a: u128, b: u128, denominator: u128) -> u128 {
+    assert_ne!(denominator, 0, "Division by zero");
+    let result = U256::from(a) * U256::from(b) / U256::from(denominator);
+    assert!(result <= U256::from(u128::MAX), "Arithmetic overflow");
+    result.as_u128()
+}
+
+/// Splits `amount` across `weights`, rounding every part down.
+///
+/// Returns the parts, in the order of `weights`, and the dust left over by rounding, so that
+/// the parts plus the dust always add up to `amount` exactly.
+pub fn split_by_weights(amount: u128, weights: &[u8]) -> (Vec<u128>, u128) {
+    let parts: Vec<u128> = weights
+        .iter()
+        .map(|weight| mul_div_floor(amount, u128::from(*weight), WEIGHT_DENOMINATOR))
+        .collect();
+    let credited: u128 = parts.iter().sum();
+    assert!(credited <= amount, "Weights exceed 100%");
+    (parts, amount - credited)
+}
+
+/// Values `balance` at `price`, where `price` carries `decimals` decimal places, rounded down.
+pub fn value_at_price(balance: u128, price: u128, decimals: u8) -> u128 {
+    let scale = 10u128
+        .checked_pow(u32::from(decimals))
+        .unwrap_or_else(|| env::panic_str(&format!("Unsupported price decimals {}", decimals)));
+    mul_div_floor(balance, price, scale)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mul_div_floor_does_not_overflow() {
+        assert_eq!(mul_div_floor(u128::MAX, u128::MAX, u128::MAX), u128::MAX);
+        assert_eq!(mul_div_floor(7, 3, 2), 10);
+    }
+
+    #[test]
+    #[should_panic(expected = "Arithmetic overflow")]
+    fn test_mul_div_floor_rejects_overflowing_result() {
+        mul_div_floor(u128::MAX, 2, 1);
+    }
+
+    #[test]
+    fn test_split_by_weights_tracks_dust() {
+        let (parts, dust) = split_by_weights(1001, &[70, 30]);
+        assert_eq!(parts, vec![700, 300]);
+        assert_eq!(dust, 1);
+
+        let (parts, dust) = split_by_weights(10, &[33, 33, 34]);
+        assert_eq!(parts, vec![3, 3, 3]);
+        assert_eq!(dust, 1);
+    }
+
+    #[test]
+    fn test_split_by_weights_is_exact_above_f64_precision() {
+        // 2^53 + 1 can't be represented as an f64
+        let amount = (1u128 << 53) + 1;
+        let (parts, dust) = split_by_weights(amount, &[50, 50]);
+        assert_eq!(parts, vec![1u128 << 52, 1u128 << 52]);
+        assert_eq!(dust, 1);
+        assert_eq!(parts.iter().sum::<u128>() + dust, amount);
+    }
+
+    #[test]
+    fn test_value_at_price_rounds_down() {
+        // 1.5 units priced at 2.999 with 3 decimals
+        assert_eq!(value_at_price(1_500, 2_999, 3), 4_498);
+        assert_eq!(value_at_price(u128::MAX / 10, 10, 1), u128::MAX / 10);
+    }
+}
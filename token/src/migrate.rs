@@ -0,0 +1,401 @@
+use near_contract_standards::fungible_token::events::FtMint;
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::FungibleToken;
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::LazyOption;
+use near_sdk::json_types::U128;
+use near_sdk::store::{IterableMap, IterableSet, LookupMap, Vector};
+use near_sdk::{env, log, near_bindgen, AccountId};
+use std::collections::HashMap;
+
+use crate::breaker::PriceGuard;
+use crate::ledger::SignedTxIndex;
+use crate::rlp;
+use crate::storage::position_storage_usage;
+use crate::twap::PricingMode;
+use crate::{
+    AssetInfo, AssetKind, Contract, ContractExt, StorageKey, DEFAULT_ORACLE_MAX_AGE_SEC,
+    DEFAULT_PRICE_MAX_AGE_SEC, MPC_ROOT_PUBLIC_KEY,
+};
+
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldAssetInfo {
+    name: String,
+    contract_address: String,
+    weight: u8,
+}
+
+/// State layout of funds deployed before shares were issued, when every collection lived in
+/// the root state record.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldContract {
+    total_assets: U128,
+    assets: Vec<OldAssetInfo>,
+    owner_id: AccountId,
+    user_balances: HashMap<AccountId, HashMap<String, U128>>,
+    usdc_contract: AccountId,
+    oracle_contract: AccountId,
+    latest_signed_txs: Vec<Vec<u8>>,
+}
+
+/// Chain id and nonce of an EIP-1559 transaction, the only kind the old layout signed.
+fn decode_legacy_tx(raw_tx: &[u8]) -> Option<(u64, u64)> {
+    let (&tx_type, payload) = raw_tx.split_first()?;
+    if tx_type != 0x02 {
+        return None;
+    }
+    let fields = rlp::decode_list(payload)?;
+    Some((
+        rlp::decode_uint(fields.first()?)?,
+        rlp::decode_uint(fields.get(1)?)?,
+    ))
+}
+
+impl Contract {
+    /// Registry id `migrate` gave the asset the old layout held at `contract_address`.
+    fn legacy_asset_id(&self, contract_address: &str) -> String {
+        self.assets
+            .iter()
+            .find(|asset| asset.contract_address == contract_address)
+            .map_or_else(
+                || contract_address.to_string(),
+                |asset| asset.asset_id.clone(),
+            )
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Converts the state of a fund deployed with the old layout.
+    ///
+    /// The old balances are set aside one record per holder, for `migrate_holders` to
+    /// convert in batches: a fund with thousands of holders can't convert them all in one call.
+    /// Only the old layout deserializes here, so the migration can't run twice.
+    ///
+    /// `asset_ids` maps the contract address of every old asset to its registry id, as the
+    /// factory recorded them for the fund when it migrated: `get_fund` lists them.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate(metadata: FungibleTokenMetadata, asset_ids: HashMap<String, String>) -> Self {
+        let old: OldContract =
+            env::state_read().unwrap_or_else(|| env::panic_str("No contract state to migrate"));
+        metadata.assert_valid();
+
+        // Only ERC-20 tokens could be held back then
+        let assets: Vec<AssetInfo> = old
+            .assets
+            .into_iter()
+            .map(|asset| AssetInfo {
+                name: asset.name,
+                asset_id: asset_ids
+                    .get(&asset.contract_address)
+                    .cloned()
+                    .unwrap_or_else(|| {
+                        env::panic_str(&format!("No registry id for {}", asset.contract_address))
+                    }),
+                contract_address: asset.contract_address,
+                weight: asset.weight,
+                kind: AssetKind::Erc20,
+            })
+            .collect();
+        let mut token = FungibleToken::new(StorageKey::FungibleToken);
+        token.account_storage_usage += position_storage_usage(&assets);
+        let mut oracles = IterableSet::new(StorageKey::Oracles);
+        oracles.insert(old.oracle_contract);
+
+        let mut contract = Self {
+            total_assets: old.total_assets,
+            assets,
+            owner_id: old.owner_id,
+            user_balances: LookupMap::new(StorageKey::UserBalances),
+            usdc_contract: old.usdc_contract,
+            oracles,
+            oracle_quorum: 1,
+            oracle_max_age_sec: DEFAULT_ORACLE_MAX_AGE_SEC,
+            accepted_prices: IterableMap::new(StorageKey::AcceptedPrices),
+            price_guard: PriceGuard::default(),
+            price_halt: None,
+            price_max_age_sec: DEFAULT_PRICE_MAX_AGE_SEC,
+            guardians: IterableSet::new(StorageKey::Guardians),
+            twaps: LookupMap::new(StorageKey::Twaps),
+            pricing_mode: PricingMode::Spot,
+            signed_txs: IterableMap::new(StorageKey::SignedTxs),
+            signed_tx_index: SignedTxIndex::new(),
+            next_signed_tx_id: 0,
+            token,
+            dust: U128(0),
+            metadata: LazyOption::new(StorageKey::Metadata, Some(&metadata)),
+            // The old build priced assets from a compiled-in table; the owner re-registers them
+            // under the ids the assets were given
+            asset_registry: IterableMap::new(StorageKey::AssetRegistry),
+            withdrawals: LookupMap::new(StorageKey::Withdrawals),
+            next_withdrawal_id: 0,
+            // Nonces used to be supplied by callers; the relayer resyncs them after migrating
+            nonces: LookupMap::new(StorageKey::Nonces),
+            synced_nonces: LookupMap::new(StorageKey::SyncedNonces),
+            relayers: IterableSet::new(StorageKey::Relayers),
+            // The old build routed withdrawals by asset name; the owner registers the chains
+            chains: IterableMap::new(StorageKey::Chains),
+            fee_quotes: LookupMap::new(StorageKey::FeeQuotes),
+            mpc_public_key: MPC_ROOT_PUBLIC_KEY.parse().unwrap(),
+            treasury_keys: LookupMap::new(StorageKey::TreasuryKeys),
+            utxos: LookupMap::new(StorageKey::Utxos),
+            held_utxos: LookupMap::new(StorageKey::HeldUtxos),
+            spent_utxos: LookupMap::new(StorageKey::SpentUtxos),
+            legacy_balances: None,
+            migrated_holders: 0,
+        };
+
+        // Whatever the old float split lost between deposits and positions belongs to the fund
+        let shares: u128 = old
+            .user_balances
+            .values()
+            .flat_map(|balances| balances.values())
+            .map(|balance| balance.0)
+            .sum();
+        contract.dust = U128(contract.total_assets.0.saturating_sub(shares));
+
+        let holders = old.user_balances.len();
+        let mut legacy_balances = Vector::new(StorageKey::LegacyBalances);
+        legacy_balances.extend(old.user_balances);
+        contract.legacy_balances = Some(legacy_balances);
+        // Old signed transactions may still be waiting to be broadcast. They carry no
+        // withdrawal, so the relayers only track them to their outcome through the ledger
+        let legacy_txs = old.latest_signed_txs.len();
+        let mut dropped = 0;
+        for signed_tx in old.latest_signed_txs {
+            match decode_legacy_tx(&signed_tx) {
+                Some((chain_id, nonce)) => {
+                    contract.internal_import_signed_tx(chain_id, nonce, signed_tx);
+                }
+                None => {
+                    dropped += 1;
+                    log!(
+                        "Dropped undecodable legacy signed transaction 0x{}",
+                        hex::encode(env::keccak256_array(&signed_tx))
+                    );
+                }
+            }
+        }
+
+        log!(
+            "{} holders to migrate, imported {} of {} legacy signed transactions",
+            holders,
+            legacy_txs - dropped,
+            legacy_txs
+        );
+        contract
+    }
+
+    /// Converts up to `limit` of the holders set aside by `migrate` and returns how many are
+    /// left.
+    ///
+    /// Every holder is registered with the share ledger and minted one share per unit of their
+    /// position, now keyed by registry id like the fund's assets. Holders who registered and
+    /// deposited since the upgrade keep what they have, the old position being added to it.
+    pub fn migrate_holders(&mut self, limit: u32) -> u32 {
+        self.assert_owner();
+        let legacy_balances = self
+            .legacy_balances
+            .as_mut()
+            .unwrap_or_else(|| env::panic_str("No holders left to migrate"));
+        // Only the holders converted are read, so a batch costs the same however many are left
+        let batch: Vec<_> = (0..limit).map_while(|_| legacy_balances.pop()).collect();
+        let left = legacy_balances.len();
+
+        self.migrated_holders += batch.len() as u32;
+        for (account_id, balances) in batch {
+            let shares: u128 = balances.values().map(|balance| balance.0).sum();
+            if !self.token.accounts.contains_key(&account_id) {
+                self.token.internal_register_account(&account_id);
+            }
+            self.token.internal_deposit(&account_id, shares);
+            FtMint {
+                owner_id: &account_id,
+                amount: U128(shares),
+                memo: Some("migration"),
+            }
+            .emit();
+            self.internal_add_position(
+                &account_id,
+                balances
+                    .iter()
+                    .map(|(address, balance)| (self.legacy_asset_id(address), balance.0))
+                    .collect(),
+            );
+        }
+        if left == 0 {
+            self.legacy_balances = None;
+            log!("Migrated {} holders", self.migrated_holders);
+        }
+        left
+    }
+
+    /// Holders `migrate_holders` has yet to convert.
+    pub fn get_holders_to_migrate(&self) -> u32 {
+        self.legacy_balances
+            .as_ref()
+            .map_or(0, |balances| balances.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_contract_standards::fungible_token::core::FungibleTokenCore;
+    use near_contract_standards::fungible_token::metadata::FT_METADATA_SPEC;
+    use near_sdk::test_utils::{accounts, get_logs, VMContextBuilder};
+    use near_sdk::testing_env;
+
+    const WETH: &str = "0x2e5221B0f855Be4ea5Cefffb8311EED0563B6e87";
+
+    fn write_old_state(user_balances: HashMap<AccountId, HashMap<String, U128>>) {
+        env::state_write(&OldContract {
+            total_assets: U128(1000),
+            assets: vec![
+                OldAssetInfo {
+                    name: "ETH".to_string(),
+                    contract_address: WETH.to_string(),
+                    weight: 70,
+                },
+                OldAssetInfo {
+                    name: "OTHER".to_string(),
+                    contract_address: "0xother".to_string(),
+                    weight: 30,
+                },
+            ],
+            owner_id: accounts(1),
+            user_balances,
+            usdc_contract: "usdc.testnet".parse().unwrap(),
+            oracle_contract: "priceoracle.testnet".parse().unwrap(),
+            latest_signed_txs: vec![vec![0x02, 0xf8], legacy_tx()],
+        });
+    }
+
+    /// Signed EIP-1559 transaction at nonce 7 on Sepolia, its fields past the nonce left empty.
+    fn legacy_tx() -> Vec<u8> {
+        let mut fields = vec![rlp::encode_uint(11155111), rlp::encode_uint(7)];
+        fields.extend(vec![rlp::encode_bytes(&[]); 10]);
+        [vec![0x02], rlp::encode_list(&fields)].concat()
+    }
+
+    fn metadata() -> FungibleTokenMetadata {
+        FungibleTokenMetadata {
+            spec: FT_METADATA_SPEC.to_string(),
+            name: "Nexus Index".to_string(),
+            symbol: "NXI".to_string(),
+            icon: None,
+            reference: None,
+            reference_hash: None,
+            decimals: 6,
+        }
+    }
+
+    fn migrate() -> Contract {
+        let asset_ids = HashMap::from([
+            (WETH.to_string(), "weth.fakes.testnet".to_string()),
+            ("0xother".to_string(), "0xother".to_string()),
+        ]);
+        Contract::migrate(metadata(), asset_ids)
+    }
+
+    #[test]
+    fn test_migrate_from_old_layout() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+
+        let mut position = HashMap::new();
+        position.insert(WETH.to_string(), U128(699));
+        position.insert("0xother".to_string(), U128(299));
+        let mut user_balances = HashMap::new();
+        user_balances.insert(accounts(2), position);
+        write_old_state(user_balances);
+
+        let mut contract = migrate();
+        assert_eq!(contract.get_holders_to_migrate(), 1);
+        let asset_ids: Vec<String> = contract
+            .get_assets()
+            .into_iter()
+            .map(|asset| asset.asset_id)
+            .collect();
+        assert_eq!(asset_ids, vec!["weth.fakes.testnet", "0xother"]);
+        assert!(get_logs().contains(&format!(
+            "Dropped undecodable legacy signed transaction 0x{}",
+            hex::encode(env::keccak256_array(&[0x02, 0xf8]))
+        )));
+        let imported = contract.get_signed_txs(None, None, None, None, None);
+        assert_eq!(imported.len(), 1);
+        assert_eq!(
+            (imported[0].chain_id, imported[0].nonce),
+            (11155111, Some(7))
+        );
+        assert_eq!(imported[0].withdrawal_id, None);
+        assert_eq!(imported[0].raw_tx, legacy_tx());
+        assert_eq!(imported[0].status, crate::ledger::SignedTxStatus::Pending);
+
+        // Relayers settle it like any other transaction, with no leg to update
+        let tx_hash = imported[0].tx_hash.clone();
+        contract.report_broadcast(imported[0].id, tx_hash);
+        contract.report_confirmation(imported[0].id, 100, true);
+
+        assert_eq!(contract.migrate_holders(10), 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)), U128(998));
+        assert_eq!(contract.get_dust(), U128(2));
+        let position = contract.get_user_balance(&accounts(2)).unwrap();
+        assert_eq!(position.get("weth.fakes.testnet"), Some(&U128(699)));
+        assert_eq!(position.get("0xother"), Some(&U128(299)));
+        assert_eq!(contract.get_holders_to_migrate(), 0);
+    }
+
+    #[test]
+    fn test_migrate_holders_in_batches() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+
+        let mut user_balances = HashMap::new();
+        for (holder, balance) in [(2, 300), (3, 200), (4, 100)] {
+            let mut position = HashMap::new();
+            position.insert(WETH.to_string(), U128(balance));
+            user_balances.insert(accounts(holder), position);
+        }
+        write_old_state(user_balances);
+
+        let mut contract = migrate();
+        assert_eq!(contract.get_dust(), U128(400));
+        assert_eq!(contract.migrate_holders(2), 1);
+        assert_eq!(contract.get_holders_to_migrate(), 1);
+
+        assert_eq!(contract.migrate_holders(2), 0);
+        for (holder, balance) in [(2, 300), (3, 200), (4, 100)] {
+            assert_eq!(contract.ft_balance_of(accounts(holder)), U128(balance));
+        }
+        assert_eq!(contract.token.total_supply, 600);
+    }
+
+    #[test]
+    #[should_panic(expected = "No registry id for 0xother")]
+    fn test_migrate_requires_every_asset_id() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+        write_old_state(HashMap::new());
+
+        let asset_ids = HashMap::from([(WETH.to_string(), "weth.fakes.testnet".to_string())]);
+        Contract::migrate(metadata(), asset_ids);
+    }
+
+    #[test]
+    #[should_panic(expected = "No holders left to migrate")]
+    fn test_migrate_holders_after_completion() {
+        let mut context = VMContextBuilder::new();
+        context.predecessor_account_id(accounts(1));
+        testing_env!(context.build());
+        write_old_state(HashMap::new());
+
+        let mut contract = migrate();
+        assert_eq!(contract.migrate_holders(10), 0);
+        contract.migrate_holders(10);
+    }
+}
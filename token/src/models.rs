@@ -1,6 +1,8 @@
 use omni_transaction::evm::evm_transaction::EVMTransaction;
 use near_sdk::serde::{Deserialize, Serialize};
 
+use crate::transaction::TxType;
+
 pub type Address = [u8; 20];
 pub type AccessList = Vec<(Address, Vec<[u8; 32]>)>;
 
@@ -16,24 +18,12 @@ pub struct EVMTransactionWrapper {
     pub max_fee_per_gas: u128,
     pub max_priority_fee_per_gas: u128,
     pub access_list: AccessList,
+    #[serde(default)]
+    pub tx_type: TxType,
 }
 
 impl EVMTransactionWrapper {
-    pub fn to_evm_transaction(&self) -> EVMTransaction {
-        EVMTransaction {
-            chain_id: self.chain_id,
-            nonce: self.nonce,
-            to: self.to,
-            value: self.value,
-            input: self.input.clone(),
-            gas_limit: self.gas_limit,
-            max_fee_per_gas: self.max_fee_per_gas,
-            max_priority_fee_per_gas: self.max_priority_fee_per_gas,
-            access_list: self.access_list.clone(),
-        }
-    }
-
-    pub fn from_evm_transaction(evm_tx: &EVMTransaction) -> Self {
+    pub fn from_evm_transaction(evm_tx: &EVMTransaction, tx_type: TxType) -> Self {
         Self {
             chain_id: evm_tx.chain_id,
             nonce: evm_tx.nonce,
@@ -44,6 +34,7 @@ impl EVMTransactionWrapper {
             max_fee_per_gas: evm_tx.max_fee_per_gas,
             max_priority_fee_per_gas: evm_tx.max_priority_fee_per_gas,
             access_list: evm_tx.access_list.clone(),
+            tx_type,
         }
     }
 }
\ No newline at end of file
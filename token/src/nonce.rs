@@ -0,0 +1,115 @@
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::events::FundEvent;
+use crate::{Contract, ContractExt};
+
+/// Key of the nonce counter of the address derived from `path` on `chain_id`.
+pub(crate) fn nonce_key(chain_id: u64, path: &str) -> String {
+    format!("{}:{}", chain_id, path)
+}
+
+impl Contract {
+    /// Hands out the next nonce of the treasury address derived from `path` on `chain_id`.
+    pub(crate) fn internal_allocate_nonce(&mut self, chain_id: u64, path: &str) -> u64 {
+        let next = self.nonces.entry(nonce_key(chain_id, path)).or_insert(0);
+        let nonce = *next;
+        *next += 1;
+        nonce
+    }
+
+    /// Gives back a nonce whose transaction was never signed.
+    ///
+    /// Only the most recently allocated nonce can be reused; an older one leaves a gap that
+    /// has to be closed with `resync_nonce` once the later transactions are dealt with. A nonce
+    /// the treasury was resynced past is used up and never given back.
+    pub(crate) fn internal_release_nonce(&mut self, chain_id: u64, path: &str, nonce: u64) {
+        if self.internal_nonce_used(chain_id, path, nonce) {
+            return;
+        }
+        let key = nonce_key(chain_id, path);
+        match self.nonces.get_mut(&key) {
+            Some(next) if *next == nonce + 1 => *next = nonce,
+            _ => env::log_str(&format!(
+                "Nonce {} of {} is not the latest one, resync required",
+                nonce, key
+            )),
+        }
+    }
+
+    /// Keeps a nonce that is signed at again from being handed out, after the counter was
+    /// resynced to it or below.
+    pub(crate) fn internal_reserve_nonce(&mut self, chain_id: u64, path: &str, nonce: u64) {
+        let next = self.nonces.entry(nonce_key(chain_id, path)).or_insert(0);
+        *next = (*next).max(nonce + 1);
+    }
+
+    /// Whether `nonce` of the treasury address is known to be used, because the treasury was
+    /// resynced past it.
+    pub(crate) fn internal_nonce_used(&self, chain_id: u64, path: &str, nonce: u64) -> bool {
+        self.synced_nonces
+            .get(&nonce_key(chain_id, path))
+            .is_some_and(|synced| *synced > nonce)
+    }
+
+    pub(crate) fn assert_owner_or_relayer(&self) {
+        let caller = env::predecessor_account_id();
+        assert!(
+            caller == self.owner_id || self.relayers.contains(&caller),
+            "Only the owner or a relayer can call this method"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_relayer(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.relayers.insert(account_id.clone()),
+            "Account is already a relayer"
+        );
+        FundEvent::RelayerAdded { account_id }.emit();
+    }
+
+    pub fn remove_relayer(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(
+            self.relayers.remove(&account_id),
+            "Account is not a relayer"
+        );
+        FundEvent::RelayerRemoved { account_id }.emit();
+    }
+
+    pub fn get_relayers(&self) -> Vec<AccountId> {
+        self.relayers.iter().cloned().collect()
+    }
+
+    /// Nonce the next transaction signed for `path` on `chain_id` will use.
+    pub fn get_next_nonce(&self, chain_id: u64, path: String) -> u64 {
+        self.nonces
+            .get(&nonce_key(chain_id, &path))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Sets the next nonce of a treasury address to its on-chain transaction count, e.g. after
+    /// a signed transaction was dropped.
+    ///
+    /// Every nonce below `next_nonce` is then known to be used, so a leg whose dropped
+    /// transaction was signed at one of them is signed again at a new nonce, and a stuck leg
+    /// holding one of them can be refunded.
+    pub fn resync_nonce(&mut self, chain_id: u64, path: String, next_nonce: u64) {
+        self.assert_owner_or_relayer();
+
+        let key = nonce_key(chain_id, &path);
+        self.synced_nonces.insert(key.clone(), next_nonce);
+        let old_nonce = self.nonces.insert(key, next_nonce).unwrap_or(0);
+        FundEvent::NonceResynced {
+            chain_id,
+            path,
+            old_nonce,
+            new_nonce: next_nonce,
+        }
+        .emit();
+    }
+}
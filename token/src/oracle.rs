@@ -0,0 +1,391 @@
+//! Prices aggregated from every oracle the fund trusts.
+//!
+//! Each oracle answers `get_price_data` in the priceoracle format, adapters translating other
+//! feeds into it. An asset is priced at the median of its fresh quotes, once at least the
+//! quorum of oracles quoted it, so a single faulty feed can't move the fund's valuation.
+
+use std::collections::BTreeMap;
+
+use near_sdk::json_types::U128;
+use near_sdk::{env, near_bindgen, serde_json, AccountId, Gas, NearToken, Promise, PromiseResult};
+
+use crate::events::FundEvent;
+use crate::math;
+use crate::{Contract, ContractExt, OraclePriceData, PriceFeedInfo};
+
+/// Most oracles queried at once, so that their calls fit in a transaction's gas.
+pub const MAX_ORACLES: u32 = 5;
+
+/// Price of an asset as reported by one oracle.
+#[derive(Clone, Debug, PartialEq)]
+struct Quote {
+    source: AccountId,
+    price: u128,
+    decimals: u8,
+    /// Timestamp of the oracle's prices, in nanoseconds
+    timestamp: u64,
+}
+
+/// Median of `quotes` as a price, its decimals and the quotes it was taken from, every quote
+/// scaled to the most precise one's decimals. With an even number of quotes the two middle
+/// prices are averaged, rounding down. Quotes that can't be scaled without overflowing are left
+/// out, and so missing from the quotes returned.
+fn median_price(quotes: &[Quote]) -> Option<(u128, u8, Vec<&Quote>)> {
+    let decimals = quotes.iter().map(|quote| quote.decimals).max()?;
+    let (mut prices, used): (Vec<u128>, Vec<&Quote>) = quotes
+        .iter()
+        .filter_map(|quote| {
+            10u128
+                .checked_pow(u32::from(decimals - quote.decimals))
+                .and_then(|scale| quote.price.checked_mul(scale))
+                .map(|price| (price, quote))
+        })
+        .unzip();
+    prices.sort_unstable();
+
+    let middle = prices.len() / 2;
+    let median = match prices.len() {
+        0 => return None,
+        len if len % 2 == 1 => prices[middle],
+        _ => {
+            let (low, high) = (prices[middle - 1], prices[middle]);
+            low / 2 + high / 2 + (low % 2 + high % 2) / 2
+        }
+    };
+    Some((median, decimals, used))
+}
+
+impl Contract {
+    /// Accepted price of `asset_id`, unless it is older than the price max age.
+    pub(crate) fn internal_cached_price(&self, asset_id: &str) -> Option<&PriceFeedInfo> {
+        self.accepted_prices.get(asset_id).filter(|feed| {
+            env::block_timestamp().saturating_sub(feed.last_updated)
+                <= self.price_max_age_sec.saturating_mul(1_000_000_000)
+        })
+    }
+
+    fn assert_valid_oracle_quorum(&self, quorum: u8) {
+        assert!(
+            quorum > 0 && u32::from(quorum) <= self.oracles.len(),
+            "Quorum must be between 1 and the number of oracles"
+        );
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_oracle(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        Self::assert_valid_dependency(&account_id);
+        assert_ne!(
+            account_id, self.usdc_contract,
+            "USDC and oracle contracts must differ"
+        );
+        assert!(
+            self.oracles.len() < MAX_ORACLES,
+            "The fund can't query more than {} oracles",
+            MAX_ORACLES
+        );
+        assert!(
+            self.oracles.insert(account_id.clone()),
+            "Account is already an oracle"
+        );
+        FundEvent::OracleAdded { account_id }.emit();
+    }
+
+    pub fn remove_oracle(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        assert!(self.oracles.remove(&account_id), "Account is not an oracle");
+        assert!(
+            self.oracles.len() >= u32::from(self.oracle_quorum),
+            "Removing the oracle would leave fewer oracles than the quorum"
+        );
+        FundEvent::OracleRemoved { account_id }.emit();
+    }
+
+    /// Sets how many oracles must quote an asset, with fresh prices, for it to be priced.
+    pub fn set_oracle_quorum(&mut self, quorum: u8) {
+        self.assert_owner();
+        self.assert_valid_oracle_quorum(quorum);
+        self.oracle_quorum = quorum;
+        FundEvent::OracleQuorumUpdated { quorum }.emit();
+    }
+
+    pub fn get_oracles(&self) -> Vec<AccountId> {
+        self.oracles.iter().cloned().collect()
+    }
+
+    pub fn get_oracle_quorum(&self) -> u8 {
+        self.oracle_quorum
+    }
+
+    /// Sets how old cached prices may be for the `get_cached_*` views to use them.
+    pub fn set_price_max_age(&mut self, max_age_sec: u64) {
+        self.assert_owner();
+        assert!(max_age_sec > 0, "Price max age must be positive");
+        self.price_max_age_sec = max_age_sec;
+        FundEvent::PriceMaxAgeUpdated { max_age_sec }.emit();
+    }
+
+    pub fn get_price_max_age(&self) -> u64 {
+        self.price_max_age_sec
+    }
+
+    /// Sets how old the prices an oracle returns may be for them to be aggregated.
+    pub fn set_oracle_max_age(&mut self, max_age_sec: u64) {
+        self.assert_owner();
+        assert!(max_age_sec > 0, "Oracle max age must be positive");
+        self.oracle_max_age_sec = max_age_sec;
+        FundEvent::OracleMaxAgeUpdated { max_age_sec }.emit();
+    }
+
+    pub fn get_oracle_max_age(&self) -> u64 {
+        self.oracle_max_age_sec
+    }
+
+    // Price Feed Functions
+    /// Fetches prices from the oracles into the cache read by the `get_cached_*` views, and
+    /// the time weighted averages read by `get_twap`.
+    ///
+    /// Every fetch fills both, through the circuit breaker; this one exists for keepers that
+    /// only want them kept fresh, the averages gaining an observation per accepted price.
+    /// Attach 20 Tgas per oracle and 60 Tgas for the callback.
+    pub fn refresh_prices(&mut self) -> Promise {
+        self.get_current_prices()
+    }
+
+    pub fn get_current_prices(&self) -> Promise {
+        let sources = self.get_oracles();
+        sources
+            .iter()
+            .map(|source| {
+                Promise::new(source.clone()).function_call(
+                    "get_price_data".to_string(),
+                    Vec::new(),
+                    NearToken::from_near(0),
+                    Gas::from_tgas(20),
+                )
+            })
+            .reduce(|acc, promise| acc.and(promise))
+            .unwrap_or_else(|| env::panic_str("No oracles to query"))
+            .then(
+                Self::ext(env::current_account_id())
+                    .with_static_gas(Gas::from_tgas(50))
+                    .get_prices_callback(sources),
+            )
+    }
+
+    /// Aggregates the prices returned by `sources`, in the order they were queried.
+    ///
+    /// Oracles that failed or whose prices are older than the oracle max age are skipped, and
+    /// the call fails when fewer than the quorum are left. Only the first quote of an asset by
+    /// each oracle counts, and assets with fewer than the quorum of usable quotes are left out.
+    ///
+    /// Prices that aren't positive integers are rejected, and the aggregated ones go through
+    /// the circuit breaker, which may halt prices for the consumers of this call.
+    #[private]
+    pub fn get_prices_callback(&mut self, sources: Vec<AccountId>) -> Vec<PriceFeedInfo> {
+        let current_time = env::block_timestamp();
+        let quorum = usize::from(self.oracle_quorum);
+
+        // Asset id -> quotes of the fresh oracles
+        let mut quotes: BTreeMap<String, Vec<Quote>> = BTreeMap::new();
+        let mut fresh_sources = 0;
+        for (index, source) in sources.into_iter().enumerate() {
+            let price_data = match env::promise_result(index as u64) {
+                PromiseResult::Successful(result) => {
+                    serde_json::from_slice::<OraclePriceData>(&result).ok()
+                }
+                PromiseResult::Failed => None,
+            };
+            let Some(price_data) = price_data else {
+                env::log_str(&format!("Oracle {} returned no price data", source));
+                continue;
+            };
+            let timestamp = price_data.timestamp.parse::<u64>().unwrap_or(0);
+            if current_time.saturating_sub(timestamp)
+                > self.oracle_max_age_sec.saturating_mul(1_000_000_000)
+            {
+                env::log_str(&format!("Price data of oracle {} is too old", source));
+                continue;
+            }
+
+            fresh_sources += 1;
+            for price in price_data.prices {
+                let Some(price_info) = price.price else {
+                    continue;
+                };
+                match (
+                    price_info.multiplier.parse::<u128>(),
+                    u8::try_from(price_info.decimals),
+                ) {
+                    (Ok(multiplier), Ok(decimals)) if multiplier > 0 => {
+                        let asset_quotes = quotes.entry(price.asset_id.clone()).or_default();
+                        if asset_quotes.iter().any(|quote| quote.source == source) {
+                            env::log_str(&format!(
+                                "Oracle {} quoted {} more than once",
+                                source, price.asset_id
+                            ));
+                            continue;
+                        }
+                        asset_quotes.push(Quote {
+                            source: source.clone(),
+                            price: multiplier,
+                            decimals,
+                            timestamp,
+                        });
+                    }
+                    _ => env::log_str(&format!(
+                        "Oracle {} quoted an invalid price for {}",
+                        source, price.asset_id
+                    )),
+                }
+            }
+        }
+        if fresh_sources < quorum {
+            env::panic_str(&format!(
+                "Only {} oracles returned fresh prices, {} required",
+                fresh_sources, quorum
+            ));
+        }
+
+        let mut price_feeds = Vec::new();
+        for (asset_id, quotes) in quotes {
+            if !self.asset_registry.contains_key(&asset_id) {
+                continue;
+            }
+            if quotes.len() < quorum {
+                env::log_str(&format!(
+                    "Only {} oracles quoted {}, {} required",
+                    quotes.len(),
+                    asset_id,
+                    quorum
+                ));
+                continue;
+            }
+            let Some((price, decimals, used)) = median_price(&quotes) else {
+                continue;
+            };
+            if used.len() < quorum {
+                env::log_str(&format!(
+                    "Only {} quotes of {} could be scaled, {} required",
+                    used.len(),
+                    asset_id,
+                    quorum
+                ));
+                continue;
+            }
+            price_feeds.push(PriceFeedInfo {
+                asset_id,
+                price: U128(price),
+                decimals,
+                // As old as the oldest quote that went into the median
+                last_updated: used.iter().map(|quote| quote.timestamp).min().unwrap_or(0),
+                sources: used.into_iter().map(|quote| quote.source.clone()).collect(),
+            });
+        }
+
+        self.internal_guard_prices(&price_feeds);
+        price_feeds
+    }
+
+    /// Last accepted price of `asset_id`, if it is no older than the price max age.
+    pub fn get_cached_price(&self, asset_id: String) -> Option<PriceFeedInfo> {
+        self.assert_prices_not_halted();
+        self.internal_cached_price(&asset_id).cloned()
+    }
+
+    /// Every accepted price no older than the price max age.
+    pub fn get_cached_prices(&self) -> Vec<PriceFeedInfo> {
+        self.assert_prices_not_halted();
+        self.accepted_prices
+            .keys()
+            .filter_map(|asset_id| self.internal_cached_price(asset_id))
+            .cloned()
+            .collect()
+    }
+
+    /// Value of `account_id`'s position at the cached prices, or their time weighted averages
+    /// under the TWAP pricing mode, in the unit of the prices.
+    ///
+    /// Fails rather than undervalue the position when an asset has no fresh price, or not
+    /// enough history for its average.
+    pub fn get_cached_portfolio_value(&self, account_id: AccountId) -> U128 {
+        self.assert_prices_not_halted();
+        let balances = self
+            .get_user_balance(&account_id)
+            .expect("No balance found for user");
+
+        let mut total_value: u128 = 0;
+        for (asset_id, balance) in balances {
+            let feed = self
+                .internal_valuation_price(asset_id)
+                .unwrap_or_else(|| env::panic_str(&format!("No fresh price for {}", asset_id)));
+            let asset_value = math::value_at_price(balance.0, feed.price.0, feed.decimals);
+            total_value = total_value
+                .checked_add(asset_value)
+                .unwrap_or_else(|| env::panic_str("Portfolio value overflow"));
+        }
+        U128(total_value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn quote(source: &str, price: u128, decimals: u8) -> Quote {
+        Quote {
+            source: source.parse().unwrap(),
+            price,
+            decimals,
+            timestamp: 0,
+        }
+    }
+
+    /// Median price and decimals of `quotes`, without the quotes used.
+    fn median(quotes: &[Quote]) -> Option<(u128, u8)> {
+        median_price(quotes).map(|(price, decimals, _)| (price, decimals))
+    }
+
+    #[test]
+    fn test_median_of_odd_quotes() {
+        let quotes = [
+            quote("a.testnet", 3_000, 0),
+            quote("b.testnet", 1, 0),
+            quote("c.testnet", 3_100, 0),
+        ];
+        assert_eq!(median(&quotes), Some((3_000, 0)));
+    }
+
+    #[test]
+    fn test_median_of_even_quotes_rounds_down() {
+        let quotes = [quote("a.testnet", 3, 0), quote("b.testnet", 6, 0)];
+        assert_eq!(median(&quotes), Some((4, 0)));
+        let quotes = [
+            quote("a.testnet", u128::MAX, 0),
+            quote("b.testnet", u128::MAX, 0),
+        ];
+        assert_eq!(median(&quotes), Some((u128::MAX, 0)));
+    }
+
+    #[test]
+    fn test_median_scales_to_most_precise_quote() {
+        // 3000.00, 3001.000 and 2999.5
+        let quotes = [
+            quote("a.testnet", 300_000, 2),
+            quote("b.testnet", 3_001_000, 3),
+            quote("c.testnet", 29_995, 1),
+        ];
+        assert_eq!(median(&quotes), Some((3_000_000, 3)));
+    }
+
+    #[test]
+    fn test_median_skips_quotes_that_overflow() {
+        let quotes = [quote("a.testnet", u128::MAX, 0), quote("b.testnet", 5, 2)];
+        let (price, decimals, used) = median_price(&quotes).unwrap();
+        assert_eq!((price, decimals), (5, 2));
+        assert_eq!(used, vec![&quotes[1]]);
+        assert_eq!(median(&[]), None);
+    }
+}
@@ -0,0 +1,105 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId};
+
+use crate::events::FundEvent;
+use crate::{Contract, ContractExt};
+
+/// Everything the fund needs to know about a token it can price or hold.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct RegisteredAsset {
+    /// Id the price oracle reports the asset under
+    pub asset_id: String,
+    pub chain_id: u64,
+    /// Token contract on `chain_id`, as used in `AssetInfo::contract_address`. Unique per
+    /// chain only, e.g. `NATIVE_ASSET_ADDRESS` is registered once for every chain
+    pub evm_address: String,
+    /// Token the asset is represented by on NEAR
+    pub near_token: AccountId,
+    pub decimals: u8,
+}
+
+impl Contract {
+    pub(crate) fn find_asset_by_address(
+        &self,
+        chain_id: u64,
+        evm_address: &str,
+    ) -> Option<&RegisteredAsset> {
+        self.asset_registry.values().find(|asset| {
+            asset.chain_id == chain_id && asset.evm_address.eq_ignore_ascii_case(evm_address)
+        })
+    }
+
+    pub(crate) fn internal_register_asset(&mut self, asset: RegisteredAsset) {
+        let duplicate = self
+            .find_asset_by_address(asset.chain_id, &asset.evm_address)
+            .filter(|existing| existing.asset_id != asset.asset_id);
+        if let Some(existing) = duplicate {
+            env::panic_str(&format!(
+                "Address {} is already registered on chain {} as {}",
+                asset.evm_address, asset.chain_id, existing.asset_id
+            ));
+        }
+        self.asset_registry.insert(asset.asset_id.clone(), asset);
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn add_asset(&mut self, asset: RegisteredAsset) {
+        self.assert_owner();
+        assert!(
+            !self.asset_registry.contains_key(&asset.asset_id),
+            "Asset is already registered"
+        );
+
+        let asset_id = asset.asset_id.clone();
+        self.internal_register_asset(asset);
+        FundEvent::AssetAdded { asset_id }.emit();
+    }
+
+    pub fn update_asset(&mut self, asset: RegisteredAsset) {
+        self.assert_owner();
+        assert!(
+            self.asset_registry.contains_key(&asset.asset_id),
+            "Asset is not registered"
+        );
+
+        let asset_id = asset.asset_id.clone();
+        self.internal_register_asset(asset);
+        FundEvent::AssetUpdated { asset_id }.emit();
+    }
+
+    pub fn remove_asset(&mut self, asset_id: String) {
+        self.assert_owner();
+        assert!(
+            self.asset_registry.contains_key(&asset_id),
+            "Asset is not registered"
+        );
+        assert!(
+            !self.assets.iter().any(|held| held.asset_id == asset_id),
+            "Asset is held by the fund"
+        );
+
+        self.asset_registry.remove(&asset_id);
+        FundEvent::AssetRemoved { asset_id }.emit();
+    }
+
+    pub fn get_registered_asset(&self, asset_id: String) -> Option<RegisteredAsset> {
+        self.asset_registry.get(&asset_id).cloned()
+    }
+
+    pub fn get_registered_assets(
+        &self,
+        from_index: Option<u32>,
+        limit: Option<u32>,
+    ) -> Vec<RegisteredAsset> {
+        self.asset_registry
+            .values()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(u32::MAX) as usize)
+            .cloned()
+            .collect()
+    }
+}
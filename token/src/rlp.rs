@@ -0,0 +1,194 @@
+//! Recursive length prefix encoding, the serialization of EVM transactions.
+//!
+//! Decoding only goes as far as reading back the fields of a transaction; anything not in the
+//! canonical form encoding produces is rejected.
+
+/// Encodes a byte string.
+pub fn encode_bytes(bytes: &[u8]) -> Vec<u8> {
+    match bytes {
+        [byte] if *byte < 0x80 => vec![*byte],
+        _ => {
+            let mut encoded = length_prefix(bytes.len(), 0x80);
+            encoded.extend_from_slice(bytes);
+            encoded
+        }
+    }
+}
+
+/// Encodes an integer as its big endian bytes, zero being the empty string.
+pub fn encode_uint(value: u128) -> Vec<u8> {
+    encode_bytes(strip_leading_zeros(&value.to_be_bytes()))
+}
+
+/// Encodes a list of items, each already encoded.
+pub fn encode_list(items: &[Vec<u8>]) -> Vec<u8> {
+    let payload = items.concat();
+    let mut encoded = length_prefix(payload.len(), 0xc0);
+    encoded.extend(payload);
+    encoded
+}
+
+/// Big endian integer without its leading zeros, as integers are encoded.
+pub fn strip_leading_zeros(bytes: &[u8]) -> &[u8] {
+    let start = bytes
+        .iter()
+        .position(|byte| *byte != 0)
+        .unwrap_or(bytes.len());
+    &bytes[start..]
+}
+
+/// Payloads of the items of the list `encoded` holds, nested lists left encoded.
+pub fn decode_list(encoded: &[u8]) -> Option<Vec<&[u8]>> {
+    let (mut payload, is_list, rest) = split_item(encoded)?;
+    if !is_list || !rest.is_empty() {
+        return None;
+    }
+    let mut items = Vec::new();
+    while !payload.is_empty() {
+        let (item, is_list, rest) = split_item(payload)?;
+        items.push(if is_list {
+            &payload[..payload.len() - rest.len()]
+        } else {
+            item
+        });
+        payload = rest;
+    }
+    Some(items)
+}
+
+/// Integer a decoded payload holds, if it fits in a `u64`.
+pub fn decode_uint(payload: &[u8]) -> Option<u64> {
+    if payload.len() > 8 || payload.first() == Some(&0) {
+        return None;
+    }
+    Some(
+        payload
+            .iter()
+            .fold(0, |value, byte| (value << 8) | u64::from(*byte)),
+    )
+}
+
+/// Splits the first item off `encoded`: its payload, whether it is a list and what follows it.
+fn split_item(encoded: &[u8]) -> Option<(&[u8], bool, &[u8])> {
+    let (&prefix, rest) = encoded.split_first()?;
+    let (offset, is_list) = match prefix {
+        0x00..=0x7f => return Some((&encoded[..1], false, rest)),
+        0x80..=0xbf => (0x80, false),
+        0xc0..=0xff => (0xc0, true),
+    };
+    let (len, rest) = match prefix - offset {
+        short @ 0..=55 => (usize::from(short), rest),
+        long => {
+            let len_len = usize::from(long - 55);
+            let len_bytes = rest.get(..len_len)?;
+            if len_bytes[0] == 0 || len_len > std::mem::size_of::<usize>() {
+                return None;
+            }
+            let len = len_bytes
+                .iter()
+                .fold(0, |len, byte| (len << 8) | usize::from(*byte));
+            if len < 56 {
+                return None;
+            }
+            (len, &rest[len_len..])
+        }
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (payload, rest) = rest.split_at(len);
+    // A single byte below 0x80 is its own encoding
+    if !is_list && len == 1 && prefix == 0x81 && payload[0] < 0x80 {
+        return None;
+    }
+    Some((payload, is_list, rest))
+}
+
+fn length_prefix(len: usize, offset: u8) -> Vec<u8> {
+    if len < 56 {
+        return vec![offset + len as u8];
+    }
+    let len_bytes = len.to_be_bytes();
+    let len_bytes = strip_leading_zeros(&len_bytes);
+    let mut prefix = vec![offset + 55 + len_bytes.len() as u8];
+    prefix.extend_from_slice(len_bytes);
+    prefix
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Examples from the RLP specification
+    #[test]
+    fn test_encode_bytes() {
+        assert_eq!(encode_bytes(b"dog"), [0x83, b'd', b'o', b'g']);
+        assert_eq!(encode_bytes(b""), [0x80]);
+        assert_eq!(encode_bytes(&[0x0f]), [0x0f]);
+        assert_eq!(encode_bytes(&[0x80]), [0x81, 0x80]);
+
+        let lorem = b"Lorem ipsum dolor sit amet, consectetur adipisicing elit";
+        let encoded = encode_bytes(lorem);
+        assert_eq!(encoded[..2], [0xb8, 0x38]);
+        assert_eq!(&encoded[2..], lorem);
+    }
+
+    #[test]
+    fn test_encode_uint() {
+        assert_eq!(encode_uint(0), [0x80]);
+        assert_eq!(encode_uint(15), [0x0f]);
+        assert_eq!(encode_uint(1024), [0x82, 0x04, 0x00]);
+        let mut max = vec![0x90];
+        max.extend([0xff; 16]);
+        assert_eq!(encode_uint(u128::MAX), max);
+    }
+
+    #[test]
+    fn test_encode_list() {
+        assert_eq!(encode_list(&[]), [0xc0]);
+        assert_eq!(
+            hex::encode(encode_list(&[encode_bytes(b"cat"), encode_bytes(b"dog")])),
+            "c88363617483646f67"
+        );
+        // The set theoretical representation of three, [ [], [[]], [ [], [[]] ] ]
+        let empty = encode_list(&[]);
+        let one = encode_list(&[empty.clone()]);
+        let two = encode_list(&[empty.clone(), one.clone()]);
+        assert_eq!(
+            hex::encode(encode_list(&[empty, one, two])),
+            "c7c0c1c0c3c0c1c0"
+        );
+
+        let long = encode_list(&vec![encode_bytes(b"dog"); 14]);
+        assert_eq!(long[..2], [0xf8, 56]);
+    }
+
+    #[test]
+    fn test_decode_list() {
+        let nested = encode_list(&[encode_bytes(b"cat")]);
+        let encoded = encode_list(&[encode_uint(1024), encode_bytes(b""), nested.clone()]);
+        assert_eq!(
+            decode_list(&encoded),
+            Some(vec![&[0x04, 0x00][..], &[][..], &nested[..]])
+        );
+
+        let long = encode_list(&vec![encode_bytes(b"dog"); 14]);
+        assert_eq!(decode_list(&long).map(|items| items.len()), Some(14));
+
+        // Truncated, trailing bytes, not a list, non-canonical lengths
+        assert_eq!(decode_list(&encoded[..encoded.len() - 1]), None);
+        assert_eq!(decode_list(&[encoded.clone(), vec![0x80]].concat()), None);
+        assert_eq!(decode_list(&encode_bytes(b"dog")), None);
+        assert_eq!(decode_list(&[0xc2, 0x81, 0x05]), None);
+        assert_eq!(decode_list(&[0xf8, 0x01, 0x80]), None);
+    }
+
+    #[test]
+    fn test_decode_uint() {
+        assert_eq!(decode_uint(&[]), Some(0));
+        assert_eq!(decode_uint(&[0x04, 0x00]), Some(1024));
+        assert_eq!(decode_uint(&[0xff; 8]), Some(u64::MAX));
+        assert_eq!(decode_uint(&[0x00, 0x01]), None);
+        assert_eq!(decode_uint(&[0x01; 9]), None);
+    }
+}
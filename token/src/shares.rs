@@ -0,0 +1,198 @@
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
+use near_contract_standards::fungible_token::events::{FtBurn, FtMint};
+use near_contract_standards::fungible_token::metadata::{
+    FungibleTokenMetadata, FungibleTokenMetadataProvider,
+};
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
+use near_sdk::json_types::U128;
+use near_sdk::{env, log, near_bindgen, AccountId, PromiseOrValue};
+use std::collections::HashMap;
+
+use crate::math;
+use crate::{Contract, ContractExt};
+
+/// Per-asset amounts backing a number of fund shares, by registry id in `self.assets` order.
+pub type PositionSlice = Vec<(String, u128)>;
+
+impl Contract {
+    pub(crate) fn internal_mint_shares(
+        &mut self,
+        account_id: &AccountId,
+        amount: u128,
+        memo: &str,
+    ) {
+        self.token.internal_deposit(account_id, amount);
+        FtMint {
+            owner_id: account_id,
+            amount: U128(amount),
+            memo: Some(memo),
+        }
+        .emit();
+    }
+
+    pub(crate) fn internal_burn_shares(&mut self, account_id: &AccountId, amount: u128) {
+        self.token.internal_withdraw(account_id, amount);
+        FtBurn {
+            owner_id: account_id,
+            amount: U128(amount),
+            memo: Some("redemption"),
+        }
+        .emit();
+    }
+
+    /// Part of `account_id`'s per-asset balances that backs `shares`.
+    ///
+    /// Every asset gives up its pro-rata part rounded down; the rounding leftover is then taken
+    /// from assets that still hold balance, so the slice always sums to exactly `shares`.
+    pub(crate) fn position_slice(&self, account_id: &AccountId, shares: u128) -> PositionSlice {
+        let position = self
+            .user_balances
+            .get(account_id)
+            .unwrap_or_else(|| env::panic_str("No balance found for user"));
+
+        let balances: Vec<(String, u128)> = self
+            .assets
+            .iter()
+            .map(|asset| {
+                let balance = position.get(&asset.asset_id).map_or(0, |b| b.0);
+                (asset.asset_id.clone(), balance)
+            })
+            .collect();
+        let total: u128 = balances.iter().map(|(_, balance)| balance).sum();
+        assert!(shares <= total, "Not enough balance to cover the shares");
+        if shares == 0 {
+            return Vec::new();
+        }
+
+        let mut slice: PositionSlice = balances
+            .iter()
+            .map(|(asset, balance)| (asset.clone(), math::mul_div_floor(*balance, shares, total)))
+            .collect();
+        let mut leftover = shares - slice.iter().map(|(_, part)| part).sum::<u128>();
+        for ((_, part), (_, balance)) in slice.iter_mut().zip(balances.iter()) {
+            let extra = (balance - *part).min(leftover);
+            *part += extra;
+            leftover -= extra;
+        }
+
+        slice
+    }
+
+    /// Removes the part of `account_id`'s per-asset balances that backs `shares` and returns it.
+    pub(crate) fn internal_take_position(
+        &mut self,
+        account_id: &AccountId,
+        shares: u128,
+    ) -> PositionSlice {
+        let slice = self.position_slice(account_id, shares);
+        if slice.is_empty() {
+            return slice;
+        }
+
+        let position = self
+            .user_balances
+            .get_mut(account_id)
+            .unwrap_or_else(|| env::panic_str("No balance found for user"));
+        for (asset, part) in &slice {
+            if let Some(balance) = position.get_mut(asset) {
+                *balance = U128(balance.0 - part);
+            }
+        }
+        if position.values().all(|balance| balance.0 == 0) {
+            self.user_balances.remove(account_id);
+        }
+
+        slice
+    }
+
+    pub(crate) fn internal_add_position(&mut self, account_id: &AccountId, slice: PositionSlice) {
+        let position = self
+            .user_balances
+            .entry(account_id.clone())
+            .or_insert_with(HashMap::new);
+
+        for (asset, amount) in slice {
+            position
+                .entry(asset)
+                .and_modify(|balance| *balance = U128(balance.0 + amount))
+                .or_insert(U128(amount));
+        }
+    }
+
+    /// Moves the underlying position behind `shares` along with the shares themselves.
+    fn internal_transfer_position(
+        &mut self,
+        sender_id: &AccountId,
+        receiver_id: &AccountId,
+        shares: u128,
+    ) {
+        let slice = self.internal_take_position(sender_id, shares);
+        self.internal_add_position(receiver_id, slice);
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_position(&sender_id, &receiver_id, amount.0);
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        let sender_id = env::predecessor_account_id();
+        self.internal_transfer_position(&sender_id, &receiver_id, amount.0);
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, burned_amount) =
+            self.token
+                .internal_ft_resolve_transfer(&sender_id, receiver_id.clone(), amount);
+
+        let refund_amount = amount.0 - used_amount;
+        if burned_amount > 0 {
+            // The sender unregistered while the transfer was in flight, so the refunded shares
+            // were burned and the position behind them leaves the fund's liabilities.
+            self.internal_take_position(&receiver_id, burned_amount);
+            self.total_assets = U128(self.total_assets.0 - burned_amount);
+            log!("Account @{} burned {}", sender_id, burned_amount);
+        } else if refund_amount > 0 {
+            self.internal_transfer_position(&receiver_id, &sender_id, refund_amount);
+        }
+
+        used_amount.into()
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenMetadataProvider for Contract {
+    fn ft_metadata(&self) -> FungibleTokenMetadata {
+        self.metadata.get().unwrap()
+    }
+}
@@ -1,4 +1,7 @@
-use near_sdk::{ext_contract, serde::{Deserialize, Serialize}};
+use k256::elliptic_curve::scalar::IsHigh;
+use k256::elliptic_curve::PrimeField;
+use near_sdk::{env, ext_contract, serde::{Deserialize, Serialize}};
+use omni_transaction::evm::types::Signature as OmniSignature;
 
 #[derive(Serialize, Deserialize)]
 #[serde(crate = "near_sdk::serde")]
@@ -31,4 +34,121 @@ pub struct Scalar {
 #[ext_contract(mpc)]
 pub trait MPC {
     fn sign(&self, request: SignRequest) -> near_sdk::PromiseOrValue<SignResult>;
-}
\ No newline at end of file
+}
+
+/// Checks that an MPC signature of `payload` was made by the key behind `expected_address`
+/// and converts it to the canonical low-s form Ethereum accepts.
+pub fn verify_signature(
+    sign_result: &SignResult,
+    payload: &[u8; 32],
+    expected_address: &[u8; 20],
+) -> Result<OmniSignature, String> {
+    let big_r = hex::decode(&sign_result.big_r.affine_point)
+        .map_err(|_| "R is not hex encoded".to_string())?;
+    let (r_parity, r) = match big_r.split_first() {
+        Some((&0x02, r)) if r.len() == 32 => (0, r),
+        Some((&0x03, r)) if r.len() == 32 => (1, r),
+        _ => return Err("R is not a compressed point".to_string()),
+    };
+    // The MPC network reports the parity of R; recovery ids 2 and 3, for an R whose x
+    // coordinate overflows the curve order, are never produced and can't be used on Ethereum
+    if sign_result.recovery_id != r_parity {
+        return Err(format!(
+            "Recovery id {} doesn't match R",
+            sign_result.recovery_id
+        ));
+    }
+
+    let s_bytes: [u8; 32] = hex::decode(&sign_result.s.scalar)
+        .ok()
+        .and_then(|s| s.try_into().ok())
+        .ok_or_else(|| "s is not a 32 byte scalar".to_string())?;
+    let s: k256::Scalar = Option::from(k256::Scalar::from_repr(s_bytes.into()))
+        .ok_or_else(|| "s is not a valid scalar".to_string())?;
+    // (r, s) and (r, -s) are both valid, Ethereum only accepts the one with the low s, which
+    // belongs to the negated R and hence flips the recovery id
+    let (s, v) = if bool::from(s.is_high()) {
+        (-s, sign_result.recovery_id ^ 1)
+    } else {
+        (s, sign_result.recovery_id)
+    };
+    let s = s.to_repr().to_vec();
+
+    let signature = [r, s.as_slice()].concat();
+    let public_key = env::ecrecover(payload, &signature, v as u8, true)
+        .ok_or_else(|| "Signature doesn't recover to a public key".to_string())?;
+    let signer = &env::keccak256_array(&public_key)[12..];
+    if signer != expected_address {
+        return Err(format!(
+            "Signed by 0x{} instead of 0x{}",
+            hex::encode(signer),
+            hex::encode(expected_address)
+        ));
+    }
+
+    Ok(OmniSignature {
+        v,
+        r: r.to_vec(),
+        s,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Signature of keccak256("nexusfi") by 0x4c0883a6...3f362318, with the high s
+    const PAYLOAD: &str = "1ac327fd8234831a574a06da9f6f9f94dcd2a6cc0764632c789b9e4875570edb";
+    const BIG_R: &str = "03bb50e2d89a4ed70663d080659fe0ad4b9bc3e06c17a227433966cb59ceee020d";
+    const HIGH_S: &str = "be8ed28da0162c4688056d7140f46204df307e3f8eb067e3fa4c9a1d530aaa7c";
+    const LOW_S: &str = "41712d725fe9d3b977fa928ebf0b9df9db7e5ea720983857c585c46f7d2b96c5";
+    const SIGNER: &str = "2c7536e3605d9c16a7a3d7b1898e529396a65c23";
+
+    fn sign_result(big_r: &str, s: &str, recovery_id: u64) -> SignResult {
+        SignResult {
+            big_r: AffinePoint {
+                affine_point: big_r.to_string(),
+            },
+            s: Scalar {
+                scalar: s.to_string(),
+            },
+            recovery_id,
+        }
+    }
+
+    fn verify(sign_result: &SignResult, signer: &str) -> Result<OmniSignature, String> {
+        let payload: [u8; 32] = hex::decode(PAYLOAD).unwrap().try_into().unwrap();
+        let signer: [u8; 20] = hex::decode(signer).unwrap().try_into().unwrap();
+        verify_signature(sign_result, &payload, &signer)
+    }
+
+    #[test]
+    fn test_high_s_is_normalized() {
+        let Ok(signature) = verify(&sign_result(BIG_R, HIGH_S, 1), SIGNER) else {
+            env::panic_str("Signature was rejected");
+        };
+        assert_eq!(signature.v, 0);
+        assert_eq!(signature.r, hex::decode(&BIG_R[2..]).unwrap());
+        assert_eq!(signature.s, hex::decode(LOW_S).unwrap());
+    }
+
+    #[test]
+    fn test_wrong_signer_is_rejected() {
+        let result = verify(
+            &sign_result(BIG_R, HIGH_S, 1),
+            "7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+        );
+        assert_eq!(
+            result.err().as_deref(),
+            Some("Signed by 0x2c7536e3605d9c16a7a3d7b1898e529396a65c23 instead of \
+             0x7e5f4552091a69125d5dfcb7b8c2659029395bdf")
+        );
+    }
+
+    #[test]
+    fn test_recovery_id_must_match_r() {
+        for recovery_id in [0, 2, 3] {
+            assert!(verify(&sign_result(BIG_R, HIGH_S, recovery_id), SIGNER).is_err());
+        }
+    }
+}
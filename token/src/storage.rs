@@ -0,0 +1,65 @@
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::json_types::U128;
+use near_sdk::{log, near_bindgen, AccountId, NearToken, StorageUsage};
+
+use crate::{AssetInfo, Contract, ContractExt};
+
+const MAX_ACCOUNT_ID_LEN: StorageUsage = 64;
+/// Bytes the protocol charges for every trie record on top of its key and value.
+const STORAGE_RECORD_OVERHEAD: StorageUsage = 40;
+
+/// Upper bound on the bytes a depositor's `user_balances` entry adds to the contract state.
+///
+/// This is charged on top of the share ledger entry, so `storage_balance_bounds().min` covers
+/// everything a registered account can make the fund store.
+pub(crate) fn position_storage_usage(assets: &[AssetInfo]) -> StorageUsage {
+    let per_asset: StorageUsage = assets
+        .iter()
+        .map(|asset| 4 + asset.asset_id.len() as StorageUsage + 16)
+        .sum();
+    // Key: collection prefix and the account id with its length prefix.
+    // Value: the length prefix of the per-asset map and its entries.
+    1 + 4 + MAX_ACCOUNT_ID_LEN + STORAGE_RECORD_OVERHEAD + 4 + per_asset
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        self.token.storage_deposit(account_id, registration_only)
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<NearToken>) -> StorageBalance {
+        self.token.storage_withdraw(amount)
+    }
+
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        if let Some((account_id, balance)) = self.token.internal_storage_unregister(force) {
+            // Force-closing burns the account's shares, so the position behind them goes too
+            if balance > 0 {
+                self.internal_take_position(&account_id, balance);
+                self.total_assets = U128(self.total_assets.0 - balance);
+            }
+            log!("Closed @{} with {}", account_id, balance);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.token.storage_balance_of(account_id)
+    }
+}
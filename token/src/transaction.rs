@@ -0,0 +1,300 @@
+//! Encoding of the EVM transactions the treasuries sign, in each of the types a chain may accept.
+//!
+//! Legacy and access list transactions pay a single gas price, for which the max fee per gas is
+//! used; their priority fee is ignored.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::serde::{Deserialize, Serialize};
+use omni_transaction::evm::types::Signature as OmniSignature;
+
+use crate::models::EVMTransactionWrapper;
+use crate::rlp;
+
+#[derive(
+    BorshDeserialize,
+    BorshSerialize,
+    Serialize,
+    Deserialize,
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+)]
+#[serde(crate = "near_sdk::serde")]
+pub enum TxType {
+    /// Type 0, replay protected by the chain id as per EIP-155
+    Legacy,
+    /// Type 1, EIP-2930
+    AccessList,
+    /// Type 2, EIP-1559
+    #[default]
+    DynamicFee,
+}
+
+impl EVMTransactionWrapper {
+    /// Bytes whose keccak hash the treasury key signs.
+    pub fn build_for_signing(&self) -> Vec<u8> {
+        let mut fields = self.fields();
+        if self.tx_type == TxType::Legacy {
+            fields.extend([
+                rlp::encode_uint(self.chain_id.into()),
+                rlp::encode_uint(0),
+                rlp::encode_uint(0),
+            ]);
+        }
+        self.envelope(&fields)
+    }
+
+    /// Signed transaction, ready for `eth_sendRawTransaction`.
+    pub fn build_with_signature(&self, signature: &OmniSignature) -> Vec<u8> {
+        let v = match self.tx_type {
+            TxType::Legacy => u128::from(signature.v) + u128::from(self.chain_id) * 2 + 35,
+            TxType::AccessList | TxType::DynamicFee => signature.v.into(),
+        };
+        let mut fields = self.fields();
+        fields.extend([
+            rlp::encode_uint(v),
+            rlp::encode_bytes(rlp::strip_leading_zeros(&signature.r)),
+            rlp::encode_bytes(rlp::strip_leading_zeros(&signature.s)),
+        ]);
+        self.envelope(&fields)
+    }
+
+    fn fields(&self) -> Vec<Vec<u8>> {
+        let nonce = rlp::encode_uint(self.nonce.into());
+        let gas_limit = rlp::encode_uint(self.gas_limit);
+        let to = rlp::encode_bytes(self.to.as_ref().map_or(&[][..], |to| &to[..]));
+        let value = rlp::encode_uint(self.value);
+        let input = rlp::encode_bytes(&self.input);
+        let chain_id = rlp::encode_uint(self.chain_id.into());
+
+        match self.tx_type {
+            TxType::Legacy => {
+                assert!(
+                    self.access_list.is_empty(),
+                    "Legacy transactions can't carry an access list"
+                );
+                let gas_price = rlp::encode_uint(self.max_fee_per_gas);
+                vec![nonce, gas_price, gas_limit, to, value, input]
+            }
+            TxType::AccessList => {
+                let gas_price = rlp::encode_uint(self.max_fee_per_gas);
+                let access_list = self.encode_access_list();
+                vec![
+                    chain_id,
+                    nonce,
+                    gas_price,
+                    gas_limit,
+                    to,
+                    value,
+                    input,
+                    access_list,
+                ]
+            }
+            TxType::DynamicFee => vec![
+                chain_id,
+                nonce,
+                rlp::encode_uint(self.max_priority_fee_per_gas),
+                rlp::encode_uint(self.max_fee_per_gas),
+                gas_limit,
+                to,
+                value,
+                input,
+                self.encode_access_list(),
+            ],
+        }
+    }
+
+    fn encode_access_list(&self) -> Vec<u8> {
+        let entries: Vec<Vec<u8>> = self
+            .access_list
+            .iter()
+            .map(|(address, storage_keys)| {
+                let storage_keys: Vec<Vec<u8>> = storage_keys
+                    .iter()
+                    .map(|key| rlp::encode_bytes(key))
+                    .collect();
+                rlp::encode_list(&[rlp::encode_bytes(address), rlp::encode_list(&storage_keys)])
+            })
+            .collect();
+        rlp::encode_list(&entries)
+    }
+
+    fn envelope(&self, fields: &[Vec<u8>]) -> Vec<u8> {
+        let list = rlp::encode_list(fields);
+        match self.tx_type {
+            TxType::Legacy => list,
+            TxType::AccessList => [vec![0x01], list].concat(),
+            TxType::DynamicFee => [vec![0x02], list].concat(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use near_sdk::env;
+
+    /// Address of the private key `0x4646..46` used by the EIP-155 example.
+    const SENDER: &str = "9d8a62f656a8d1615c1294fd71e9cfb3e4855a4f";
+
+    fn signature(r: &str, s: &str, v: u64) -> OmniSignature {
+        OmniSignature {
+            v,
+            r: hex::decode(r).unwrap(),
+            s: hex::decode(s).unwrap(),
+        }
+    }
+
+    fn signer(tx: &EVMTransactionWrapper, signature: &OmniSignature) -> String {
+        let payload = env::keccak256_array(&tx.build_for_signing());
+        let rs = [signature.r.as_slice(), &signature.s].concat();
+        let public_key = env::ecrecover(&payload, &rs, signature.v as u8, true).unwrap();
+        hex::encode(&env::keccak256_array(&public_key)[12..])
+    }
+
+    fn transfer(tx_type: TxType) -> EVMTransactionWrapper {
+        EVMTransactionWrapper {
+            chain_id: 1,
+            nonce: 9,
+            to: Some([0x35; 20]),
+            value: 1_000_000_000_000_000_000,
+            input: vec![],
+            gas_limit: 21_000,
+            max_fee_per_gas: 20_000_000_000,
+            max_priority_fee_per_gas: 0,
+            access_list: vec![],
+            tx_type,
+        }
+    }
+
+    // The example of EIP-155
+    #[test]
+    fn test_legacy_transaction() {
+        let tx = transfer(TxType::Legacy);
+        assert_eq!(
+            hex::encode(tx.build_for_signing()),
+            concat!(
+                "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a76400",
+                "0080018080",
+            )
+        );
+        assert_eq!(
+            hex::encode(env::keccak256_array(&tx.build_for_signing())),
+            "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+        );
+
+        let signature = signature(
+            "28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+            "67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+            0,
+        );
+        assert_eq!(signer(&tx, &signature), SENDER);
+        assert_eq!(
+            hex::encode(tx.build_with_signature(&signature)),
+            concat!(
+                "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764",
+                "00008025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cb",
+                "e9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+            )
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Legacy transactions can't carry an access list")]
+    fn test_legacy_transaction_rejects_access_list() {
+        let mut tx = transfer(TxType::Legacy);
+        tx.access_list = vec![([0x35; 20], vec![])];
+        tx.build_for_signing();
+    }
+
+    #[test]
+    fn test_access_list_transaction() {
+        let mut tx = transfer(TxType::AccessList);
+        tx.gas_limit = 30_000;
+        let mut storage_key = [0; 32];
+        storage_key[31] = 1;
+        tx.access_list = vec![(
+            hex::decode("2e5221b0f855be4ea5cefffb8311eed0563b6e87")
+                .unwrap()
+                .try_into()
+                .unwrap(),
+            vec![storage_key],
+        )];
+        assert_eq!(
+            hex::encode(tx.build_for_signing()),
+            concat!(
+                "01f86401098504a817c800827530943535353535353535353535353535353535353535880de0b6b3",
+                "a764000080f838f7942e5221b0f855be4ea5cefffb8311eed0563b6e87e1a0000000000000000000",
+                "0000000000000000000000000000000000000000000001",
+            )
+        );
+
+        let signature = signature(
+            "1392445a4323cc42137c3f1d2c0901c44acc871ac3d8e64d412788bf7ad2dc0f",
+            "64365b934e35cfd63140574677ec5458d5a23522edee700ec8d3715a7ae64c7c",
+            1,
+        );
+        assert_eq!(signer(&tx, &signature), SENDER);
+        assert_eq!(
+            hex::encode(tx.build_with_signature(&signature)),
+            concat!(
+                "01f8a701098504a817c800827530943535353535353535353535353535353535353535880de0b6b3",
+                "a764000080f838f7942e5221b0f855be4ea5cefffb8311eed0563b6e87e1a0000000000000000000",
+                "000000000000000000000000000000000000000000000101a01392445a4323cc42137c3f1d2c0901",
+                "c44acc871ac3d8e64d412788bf7ad2dc0fa064365b934e35cfd63140574677ec5458d5a23522edee",
+                "700ec8d3715a7ae64c7c",
+            )
+        );
+    }
+
+    #[test]
+    fn test_dynamic_fee_transaction() {
+        let mut recipient = [0; 20];
+        recipient
+            .copy_from_slice(&hex::decode("1234567890123456789012345678901234567890").unwrap());
+        let tx = EVMTransactionWrapper {
+            chain_id: 11155111,
+            nonce: 3,
+            to: Some(
+                hex::decode("2e5221b0f855be4ea5cefffb8311eed0563b6e87")
+                    .unwrap()
+                    .try_into()
+                    .unwrap(),
+            ),
+            value: 0,
+            input: crate::abi::erc20_transfer(recipient, 700u64.into()),
+            gas_limit: 65_000,
+            max_fee_per_gas: 2_000_000_000,
+            max_priority_fee_per_gas: 1_000_000_000,
+            access_list: vec![],
+            tx_type: TxType::DynamicFee,
+        };
+        assert_eq!(
+            hex::encode(tx.build_for_signing()),
+            concat!(
+                "02f86f83aa36a703843b9aca00847735940082fde8942e5221b0f855be4ea5cefffb8311eed0563b",
+                "6e8780b844a9059cbb00000000000000000000000012345678901234567890123456789012345678",
+                "9000000000000000000000000000000000000000000000000000000000000002bcc0",
+            )
+        );
+
+        let signature = signature(
+            "e6bf3f02fa1d3e19d86f434c46e698cdddd710c2b26a2ab78600ae333a3fdf5e",
+            "02d23b5fb20bbfe3360122dfa0b502a35de6e85eb58457e3cad9a415b6f2c67f",
+            1,
+        );
+        assert_eq!(signer(&tx, &signature), SENDER);
+        assert_eq!(
+            hex::encode(tx.build_with_signature(&signature)),
+            concat!(
+                "02f8b283aa36a703843b9aca00847735940082fde8942e5221b0f855be4ea5cefffb8311eed0563b",
+                "6e8780b844a9059cbb00000000000000000000000012345678901234567890123456789012345678",
+                "9000000000000000000000000000000000000000000000000000000000000002bcc001a0e6bf3f02",
+                "fa1d3e19d86f434c46e698cdddd710c2b26a2ab78600ae333a3fdf5ea002d23b5fb20bbfe3360122",
+                "dfa0b502a35de6e85eb58457e3cad9a415b6f2c67f",
+            )
+        );
+    }
+}
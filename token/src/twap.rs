@@ -0,0 +1,296 @@
+//! Time weighted average prices of the assets, accumulated from the accepted prices.
+//!
+//! The first accepted price of every interval is an observation holding the running sum of
+//! price times seconds since the first one, so however often prices are refreshed the
+//! observations kept span the same time. The average over a window is the difference of that
+//! sum between its ends divided by its length, each price counting for as long as it stayed the
+//! accepted one. The sum wraps around on overflow, as only differences over windows far shorter
+//! than the wrap are ever taken.
+
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen};
+
+use crate::events::FundEvent;
+use crate::{Contract, ContractExt, PriceFeedInfo};
+
+/// Observations kept per asset, bounding the longest window an average can be taken over.
+pub const MAX_OBSERVATIONS: usize = 48;
+/// Length of the intervals an asset gets at most one observation in.
+pub const OBSERVATION_INTERVAL_SEC: u64 = 300;
+/// Longest TWAP window, which the observations kept always cover: one per interval, the oldest
+/// anywhere within its own.
+pub const MAX_TWAP_WINDOW_SEC: u64 = (MAX_OBSERVATIONS as u64 - 2) * OBSERVATION_INTERVAL_SEC;
+
+/// Prices the portfolio valuations value positions at.
+///
+/// Deposits and redemptions take no price under either mode: a deposit is split by weight
+/// and a redemption pays out its slice of every asset in kind. Valuing them at the TWAP needs
+/// deposits priced into asset amounts first, which this mode doesn't cover.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Copy, Debug, Default)]
+#[serde(crate = "near_sdk::serde")]
+pub enum PricingMode {
+    /// The latest accepted price
+    #[default]
+    Spot,
+    /// The time weighted average over the last `window_sec`
+    Twap { window_sec: u64 },
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Observation {
+    /// Oracle timestamp of the price, in seconds
+    pub timestamp_sec: u64,
+    pub price: U128,
+    /// Sum of price times seconds up to `timestamp_sec`, wrapping
+    pub cumulative: U128,
+}
+
+/// Accepted prices of an asset, all in the decimals of the first one.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct TwapAccumulator {
+    pub decimals: u8,
+    /// Oldest first
+    pub observations: Vec<Observation>,
+}
+
+impl TwapAccumulator {
+    fn new(price: u128, decimals: u8, timestamp_sec: u64) -> Self {
+        Self {
+            decimals,
+            observations: vec![Observation {
+                timestamp_sec,
+                price: U128(price),
+                cumulative: U128(0),
+            }],
+        }
+    }
+
+    /// Records `price` from `timestamp_sec`, unless the interval it falls in already has an
+    /// observation or it doesn't fit in the accumulator's decimals.
+    fn observe(&mut self, price: u128, decimals: u8, timestamp_sec: u64) -> bool {
+        let last = self
+            .observations
+            .last()
+            .expect("Accumulators start with a price");
+        if timestamp_sec / OBSERVATION_INTERVAL_SEC <= last.timestamp_sec / OBSERVATION_INTERVAL_SEC
+        {
+            return false;
+        }
+        let Some(price) = rescale(price, decimals, self.decimals) else {
+            return false;
+        };
+
+        let cumulative = self
+            .cumulative_at(timestamp_sec)
+            .expect("Later than the last price");
+        if self.observations.len() == MAX_OBSERVATIONS {
+            self.observations.remove(0);
+        }
+        self.observations.push(Observation {
+            timestamp_sec,
+            price: U128(price),
+            cumulative: U128(cumulative),
+        });
+        true
+    }
+
+    /// Running sum at `timestamp_sec`, the price in effect then counting up to it. `None`
+    /// before the first observation.
+    fn cumulative_at(&self, timestamp_sec: u64) -> Option<u128> {
+        let observation = self
+            .observations
+            .iter()
+            .rev()
+            .find(|observation| observation.timestamp_sec <= timestamp_sec)?;
+        let elapsed = u128::from(timestamp_sec - observation.timestamp_sec);
+        Some(
+            observation
+                .cumulative
+                .0
+                .wrapping_add(observation.price.0.wrapping_mul(elapsed)),
+        )
+    }
+
+    /// Average price over the `window_sec` ending at `now_sec`, rounded down. `None` when the
+    /// window starts before the first observation.
+    pub fn average(&self, window_sec: u64, now_sec: u64) -> Option<u128> {
+        if window_sec == 0 {
+            return None;
+        }
+        let start = self.cumulative_at(now_sec.checked_sub(window_sec)?)?;
+        let end = self.cumulative_at(now_sec)?;
+        Some(end.wrapping_sub(start) / u128::from(window_sec))
+    }
+}
+
+/// `price` with `from` decimals expressed with `to` decimals, rounded down.
+fn rescale(price: u128, from: u8, to: u8) -> Option<u128> {
+    if from >= to {
+        Some(
+            price
+                / 10u128
+                    .checked_pow(u32::from(from - to))
+                    .unwrap_or(u128::MAX),
+        )
+    } else {
+        10u128
+            .checked_pow(u32::from(to - from))
+            .and_then(|scale| price.checked_mul(scale))
+    }
+}
+
+impl Contract {
+    /// Makes `feed` the accepted price of its asset and adds it to the asset's average.
+    pub(crate) fn internal_accept_price(&mut self, feed: PriceFeedInfo) {
+        let (price, decimals) = (feed.price.0, feed.decimals);
+        let timestamp_sec = feed.last_updated / 1_000_000_000;
+        match self.twaps.get_mut(&feed.asset_id) {
+            Some(twap) => {
+                if !twap.observe(price, decimals, timestamp_sec) {
+                    env::log_str(&format!(
+                        "Price of {} left out of its average",
+                        feed.asset_id
+                    ));
+                }
+            }
+            None => {
+                self.twaps.insert(
+                    feed.asset_id.clone(),
+                    TwapAccumulator::new(price, decimals, timestamp_sec),
+                );
+            }
+        }
+        self.accepted_prices.insert(feed.asset_id.clone(), feed);
+    }
+
+    /// Average of `asset_id` over the last `window_sec`, as long as its latest price is
+    /// fresh enough to be extended to now.
+    fn internal_twap(&self, asset_id: &str, window_sec: u64) -> Option<PriceFeedInfo> {
+        let spot = self.internal_cached_price(asset_id)?;
+        let twap = self.twaps.get(asset_id)?;
+        let price = twap.average(window_sec, env::block_timestamp() / 1_000_000_000)?;
+        Some(PriceFeedInfo {
+            price: U128(price),
+            decimals: twap.decimals,
+            ..spot.clone()
+        })
+    }
+
+    /// Price `asset_id` is valued at under the pricing mode, if fresh enough.
+    pub(crate) fn internal_valuation_price(&self, asset_id: &str) -> Option<PriceFeedInfo> {
+        match self.pricing_mode {
+            PricingMode::Spot => self.internal_cached_price(asset_id).cloned(),
+            PricingMode::Twap { window_sec } => self.internal_twap(asset_id, window_sec),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Sets the prices `get_portfolio_value` and `get_cached_portfolio_value` use.
+    pub fn set_pricing_mode(&mut self, mode: PricingMode) {
+        self.assert_owner();
+        if let PricingMode::Twap { window_sec } = mode {
+            assert!(
+                window_sec > 0 && window_sec <= MAX_TWAP_WINDOW_SEC,
+                "TWAP window must be positive and at most {} seconds",
+                MAX_TWAP_WINDOW_SEC
+            );
+        }
+        self.pricing_mode = mode;
+        FundEvent::PricingModeUpdated { mode }.emit();
+    }
+
+    pub fn get_pricing_mode(&self) -> PricingMode {
+        self.pricing_mode
+    }
+
+    /// Time weighted average price of `asset_id` over the last `window_sec`.
+    ///
+    /// `None` without a fresh price or with less history than the window, which can't exceed
+    /// the time spanned by the last `MAX_OBSERVATIONS` observations.
+    pub fn get_twap(&self, asset_id: String, window_sec: u64) -> Option<PriceFeedInfo> {
+        self.assert_prices_not_halted();
+        self.internal_twap(&asset_id, window_sec)
+    }
+
+    pub fn get_twap_observations(&self, asset_id: String) -> Option<TwapAccumulator> {
+        self.twaps.get(&asset_id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: u64 = OBSERVATION_INTERVAL_SEC;
+
+    #[test]
+    fn test_average_weights_prices_by_time() {
+        let mut twap = TwapAccumulator::new(100, 0, 0);
+        // 100 for two intervals, then 200 for one
+        assert!(twap.observe(200, 0, 2 * INTERVAL));
+        assert_eq!(twap.average(3 * INTERVAL, 3 * INTERVAL), Some(133));
+        assert_eq!(twap.average(INTERVAL, 3 * INTERVAL), Some(200));
+        // A window starting mid-interval only counts its part of it
+        assert_eq!(twap.average(2 * INTERVAL, 3 * INTERVAL), Some(150));
+        assert_eq!(twap.average(3 * INTERVAL + 1, 3 * INTERVAL), None);
+        assert_eq!(twap.average(0, 3 * INTERVAL), None);
+    }
+
+    #[test]
+    fn test_observe_ignores_older_prices() {
+        let mut twap = TwapAccumulator::new(100, 0, 1_000);
+        assert!(!twap.observe(200, 0, 1_000));
+        assert!(!twap.observe(200, 0, 900));
+        assert_eq!(twap.observations.len(), 1);
+    }
+
+    #[test]
+    fn test_observe_once_per_interval() {
+        let mut twap = TwapAccumulator::new(100, 0, INTERVAL);
+        assert!(!twap.observe(200, 0, 2 * INTERVAL - 1));
+        assert!(twap.observe(200, 0, 2 * INTERVAL));
+        assert!(!twap.observe(300, 0, 2 * INTERVAL + 1));
+        assert_eq!(twap.observations.len(), 2);
+    }
+
+    #[test]
+    fn test_observe_rescales_decimals() {
+        let mut twap = TwapAccumulator::new(300_000, 2, 0);
+        assert!(twap.observe(3_100_000, 3, INTERVAL));
+        assert!(twap.observe(3_200, 0, 2 * INTERVAL));
+        assert_eq!(twap.observations[1].price, U128(310_000));
+        assert_eq!(twap.observations[2].price, U128(320_000));
+        assert!(!twap.observe(u128::MAX, 0, 3 * INTERVAL));
+    }
+
+    #[test]
+    fn test_cumulative_wraps_around() {
+        let price = u128::MAX / 1_000;
+        let mut twap = TwapAccumulator::new(price, 0, 0);
+        for interval in 1..=8 {
+            assert!(twap.observe(price, 0, interval * INTERVAL));
+        }
+        assert_eq!(twap.average(2 * INTERVAL, 8 * INTERVAL), Some(price));
+    }
+
+    #[test]
+    fn test_oldest_observations_are_dropped() {
+        let mut twap = TwapAccumulator::new(100, 0, 0);
+        // Refreshing every second doesn't squeeze the observations into fewer intervals
+        for timestamp_sec in 1..=(MAX_OBSERVATIONS as u64 * INTERVAL) {
+            twap.observe(100, 0, timestamp_sec);
+        }
+        assert_eq!(twap.observations.len(), MAX_OBSERVATIONS);
+        assert_eq!(twap.observations[0].timestamp_sec, INTERVAL);
+        let now = MAX_OBSERVATIONS as u64 * INTERVAL;
+        assert_eq!(twap.average(MAX_TWAP_WINDOW_SEC, now), Some(100));
+        assert_eq!(twap.average(now - INTERVAL, now), Some(100));
+        assert_eq!(twap.average(now - INTERVAL + 1, now), None);
+    }
+}
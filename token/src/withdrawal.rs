@@ -0,0 +1,546 @@
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::{env, near_bindgen, AccountId, Gas, Promise};
+
+use crate::bitcoin::{Utxo, BITCOIN_CALLBACK_GAS, MAX_INPUTS};
+use crate::chains::ChainKind;
+use crate::events::FundEvent;
+use crate::ledger::SignedTxStatus;
+use crate::math;
+use crate::{AssetKind, Contract, ContractExt, NetworkDetails};
+
+/// A position is split in basis points, 10_000 being all of it.
+pub const BASIS_POINTS: u16 = 10_000;
+/// Gas of the call sending a leg: the MPC `sign` call, once per input on Bitcoin, or the
+/// Aurora Engine `call`.
+pub(crate) const LEG_CALL_GAS: Gas = Gas::from_tgas(100);
+/// Gas of the callback settling a leg: verifying the signature with `ecrecover` against the
+/// cached treasury key, then storing the signed transaction, the leg and their events. Deriving
+/// a key takes k256 arithmetic far beyond it, hence `treasury_keys`.
+pub(crate) const LEG_CALLBACK_GAS: Gas = Gas::from_tgas(20);
+/// Gas one call hands to the promises of its legs, out of the 300 Tgas a transaction can
+/// use. Legs that don't fit are queued for `sign_queued_legs`.
+pub const LEG_GAS_BUDGET: Gas = Gas::from_tgas(240);
+/// How long a leg may wait for its signature before the owner or a relayer can refund it.
+pub const STUCK_LEG_TIMEOUT_SEC: u64 = 24 * 60 * 60;
+
+/// How much of their position a user redeems. Omitted, the whole position is redeemed.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub enum WithdrawAmount {
+    Shares(U128),
+    BasisPoints(u16),
+}
+
+/// What a withdrawal would send for one underlying asset.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalPreview {
+    /// Registry id of the asset
+    pub asset_id: String,
+    pub amount: U128,
+    pub chain_id: u64,
+    pub treasury_path: String,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(crate = "near_sdk::serde")]
+pub enum WithdrawalStatus {
+    /// Debited from the user, waiting for a call with gas left to send it
+    Queued,
+    /// Debited from the user, waiting for the MPC signature or the Aurora Engine call
+    Pending,
+    /// Signed transaction is stored and ready to be broadcast
+    Signed,
+    /// Transfer was mined, the leg is final
+    Confirmed,
+    /// Signed transaction reverted or was dropped, waiting to be signed again
+    Failed,
+    /// Signing failed and the amount was credited back to the user
+    Refunded,
+}
+
+/// Transfer of a single underlying asset to the user's destination.
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct WithdrawalLeg {
+    /// Token contract transferred, see `AssetInfo::contract_address`
+    pub asset: String,
+    /// Registry id of the asset, which the leg is credited back under
+    pub asset_id: String,
+    pub kind: AssetKind,
+    pub amount: U128,
+    pub destination: String,
+    pub treasury_path: String,
+    pub chain_id: u64,
+    /// Nonce of the treasury address the transfer is signed with, set once an EVM leg is sent.
+    /// Bitcoin and Aurora Engine legs have no account nonce and leave it unset
+    pub nonce: Option<u64>,
+    pub status: WithdrawalStatus,
+    /// When the leg was last sent for signing, in nanoseconds
+    pub sent_at: u64,
+    /// Ledger id of the transaction last signed for the leg
+    pub tx_id: Option<u64>,
+    /// Outputs a Bitcoin leg spends, picked when it is first signed and spent by every
+    /// transaction signed for it
+    pub inputs: Vec<Utxo>,
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize, Clone, Debug)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Withdrawal {
+    pub id: u64,
+    pub account_id: AccountId,
+    pub legs: Vec<WithdrawalLeg>,
+    pub created_at: u64,
+    /// Set once every leg is confirmed or refunded
+    pub finalized_at: Option<u64>,
+}
+
+impl Contract {
+    /// Number of shares `amount` redeems out of `account_id`'s balance, rounded down.
+    pub(crate) fn shares_to_redeem(
+        &self,
+        account_id: &AccountId,
+        amount: Option<WithdrawAmount>,
+    ) -> u128 {
+        let balance = self.token.internal_unwrap_balance_of(account_id);
+        let shares = match amount {
+            None => balance,
+            Some(WithdrawAmount::Shares(shares)) => {
+                assert!(shares.0 <= balance, "Not enough shares");
+                shares.0
+            }
+            Some(WithdrawAmount::BasisPoints(basis_points)) => {
+                assert!(
+                    basis_points <= BASIS_POINTS,
+                    "Basis points can't exceed {}",
+                    BASIS_POINTS
+                );
+                math::mul_div_floor(balance, u128::from(basis_points), u128::from(BASIS_POINTS))
+            }
+        };
+        assert!(shares > 0, "Nothing to withdraw");
+        shares
+    }
+
+    pub(crate) fn internal_create_withdrawal(
+        &mut self,
+        account_id: AccountId,
+        legs: Vec<WithdrawalLeg>,
+    ) -> u64 {
+        let id = self.next_withdrawal_id;
+        self.next_withdrawal_id += 1;
+
+        FundEvent::WithdrawalCreated {
+            withdrawal_id: id,
+            account_id: account_id.clone(),
+        }
+        .emit();
+        self.withdrawals.insert(
+            id,
+            Withdrawal {
+                id,
+                account_id,
+                legs,
+                created_at: env::block_timestamp(),
+                finalized_at: None,
+            },
+        );
+        id
+    }
+
+    pub(crate) fn internal_leg(&self, withdrawal_id: u64, leg_index: u32) -> &WithdrawalLeg {
+        self.withdrawals
+            .get(&withdrawal_id)
+            .and_then(|withdrawal| withdrawal.legs.get(leg_index as usize))
+            .unwrap_or_else(|| env::panic_str("Withdrawal leg not found"))
+    }
+
+    /// Moves a leg from status `from` to `to` and returns it, finalizing the withdrawal once
+    /// none of its legs can change anymore.
+    fn internal_move_leg(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        from: WithdrawalStatus,
+        to: WithdrawalStatus,
+    ) -> (AccountId, WithdrawalLeg) {
+        let withdrawal = self
+            .withdrawals
+            .get_mut(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"));
+        let leg = withdrawal
+            .legs
+            .get_mut(leg_index as usize)
+            .unwrap_or_else(|| env::panic_str("Withdrawal leg not found"));
+        assert_eq!(leg.status, from, "Withdrawal leg is not {:?}", from);
+
+        leg.status = to;
+        let moved = (withdrawal.account_id.clone(), leg.clone());
+        let is_final = withdrawal.legs.iter().all(|leg| {
+            matches!(
+                leg.status,
+                WithdrawalStatus::Confirmed | WithdrawalStatus::Refunded
+            )
+        });
+        if is_final {
+            withdrawal.finalized_at = Some(env::block_timestamp());
+            FundEvent::WithdrawalFinalized { withdrawal_id }.emit();
+        }
+        moved
+    }
+
+    pub(crate) fn internal_sign_leg(&mut self, withdrawal_id: u64, leg_index: u32) {
+        self.internal_move_leg(
+            withdrawal_id,
+            leg_index,
+            WithdrawalStatus::Pending,
+            WithdrawalStatus::Signed,
+        );
+        FundEvent::WithdrawalLegSigned {
+            withdrawal_id,
+            leg_index,
+        }
+        .emit();
+    }
+
+    /// Confirms a leg whose transaction was mined: a signed one, or a failed one whose dropped
+    /// transaction was mined after all.
+    pub(crate) fn internal_confirm_leg(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        from: WithdrawalStatus,
+    ) {
+        let (_, leg) =
+            self.internal_move_leg(withdrawal_id, leg_index, from, WithdrawalStatus::Confirmed);
+        self.internal_release_utxos(&leg);
+        FundEvent::WithdrawalLegConfirmed {
+            withdrawal_id,
+            leg_index,
+        }
+        .emit();
+    }
+
+    /// Confirms a leg that was sent within the withdrawal, with no transaction to broadcast.
+    pub(crate) fn internal_execute_leg(&mut self, withdrawal_id: u64, leg_index: u32) {
+        self.internal_move_leg(
+            withdrawal_id,
+            leg_index,
+            WithdrawalStatus::Pending,
+            WithdrawalStatus::Confirmed,
+        );
+        FundEvent::WithdrawalLegConfirmed {
+            withdrawal_id,
+            leg_index,
+        }
+        .emit();
+    }
+
+    /// Reopens a leg whose transaction reverted or was dropped, or whose new signature failed,
+    /// so it can be signed again.
+    pub(crate) fn internal_fail_leg(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        from: WithdrawalStatus,
+    ) {
+        self.internal_move_leg(withdrawal_id, leg_index, from, WithdrawalStatus::Failed);
+        FundEvent::WithdrawalLegFailed {
+            withdrawal_id,
+            leg_index,
+        }
+        .emit();
+    }
+
+    /// Whether the transaction last signed for `leg` was dropped, and so could still be mined.
+    pub(crate) fn internal_leg_may_be_mined(&self, leg: &WithdrawalLeg) -> bool {
+        leg.tx_id
+            .and_then(|tx_id| self.signed_txs.get(&tx_id))
+            .is_some_and(|signed_tx| signed_tx.status == SignedTxStatus::Dropped)
+    }
+
+    /// Settles a leg whose signing failed and returns whether it was refunded.
+    ///
+    /// A leg is only credited back to the user when no transaction signed for it before can
+    /// still be mined; otherwise it stays failed until it is signed again.
+    pub(crate) fn internal_settle_unsigned_leg(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+    ) -> bool {
+        if self.internal_leg_may_be_mined(self.internal_leg(withdrawal_id, leg_index)) {
+            self.internal_fail_leg(withdrawal_id, leg_index, WithdrawalStatus::Pending);
+            false
+        } else {
+            self.internal_refund_leg(withdrawal_id, leg_index);
+            true
+        }
+    }
+
+    /// Gas the promises sending `leg` take.
+    fn internal_leg_gas(&self, leg: &WithdrawalLeg) -> Gas {
+        match self.internal_chain(leg.chain_id).kind {
+            ChainKind::Bitcoin { .. } => Gas::from_gas(
+                LEG_CALL_GAS.as_gas() * MAX_INPUTS as u64 + BITCOIN_CALLBACK_GAS.as_gas(),
+            ),
+            ChainKind::Evm | ChainKind::AuroraEngine { .. } => {
+                Gas::from_gas(LEG_CALL_GAS.as_gas() + LEG_CALLBACK_GAS.as_gas())
+            }
+        }
+    }
+
+    /// Hands out the next nonce of the treasury a leg is signed with, on chains with account
+    /// nonces.
+    fn internal_allocate_leg_nonce(&mut self, leg: &WithdrawalLeg) -> Option<u64> {
+        match self.internal_chain(leg.chain_id).kind {
+            ChainKind::Evm => Some(self.internal_allocate_nonce(leg.chain_id, &leg.treasury_path)),
+            ChainKind::Bitcoin { .. } | ChainKind::AuroraEngine { .. } => None,
+        }
+    }
+
+    /// Sends the queued legs of a withdrawal in order, as many as `LEG_GAS_BUDGET` covers.
+    ///
+    /// Nonces of EVM legs are allocated as the legs are sent, so queued legs never hold one up.
+    pub(crate) fn internal_send_queued_legs(
+        &mut self,
+        withdrawal_id: u64,
+        network_details: &[NetworkDetails],
+    ) -> Promise {
+        let legs = self
+            .withdrawals
+            .get(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+            .legs
+            .clone();
+
+        let mut budget = LEG_GAS_BUDGET;
+        let mut promises: Vec<Promise> = Vec::new();
+        let mut queued = 0;
+        for (leg_index, leg) in legs.into_iter().enumerate() {
+            if leg.status != WithdrawalStatus::Queued {
+                continue;
+            }
+            let Some(left) = budget.checked_sub(self.internal_leg_gas(&leg)) else {
+                queued += 1;
+                budget = Gas::from_gas(0);
+                continue;
+            };
+            budget = left;
+
+            let network_details = network_details
+                .iter()
+                .find(|details| details.chain_id == leg.chain_id)
+                .cloned()
+                .unwrap_or_else(|| NetworkDetails::defaults(leg.chain_id));
+            let nonce = self.internal_allocate_leg_nonce(&leg);
+            let leg = &mut self
+                .withdrawals
+                .get_mut(&withdrawal_id)
+                .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+                .legs[leg_index];
+            leg.nonce = nonce;
+            leg.status = WithdrawalStatus::Pending;
+            leg.sent_at = env::block_timestamp();
+            let leg = leg.clone();
+
+            promises.push(self.create_and_sign_withdrawal(
+                withdrawal_id,
+                leg_index as u32,
+                &leg,
+                network_details,
+            ));
+        }
+        if queued > 0 {
+            env::log_str(&format!(
+                "{} legs of withdrawal {} queued for sign_queued_legs",
+                queued, withdrawal_id
+            ));
+        }
+
+        promises
+            .into_iter()
+            .reduce(|acc, promise| acc.and(promise))
+            .unwrap_or_else(|| env::panic_str("No queued legs to send"))
+    }
+
+    /// Credits a leg that couldn't be signed back to the user, shares included.
+    pub(crate) fn internal_refund_leg(&mut self, withdrawal_id: u64, leg_index: u32) {
+        let (account_id, leg) = self.internal_move_leg(
+            withdrawal_id,
+            leg_index,
+            WithdrawalStatus::Pending,
+            WithdrawalStatus::Refunded,
+        );
+
+        // The user may have closed their storage registration after redeeming everything;
+        // the refund must not fail because of that, so the fund covers the registration
+        if !self.token.accounts.contains_key(&account_id) {
+            self.token.internal_register_account(&account_id);
+        }
+        if let Some(nonce) = leg.nonce {
+            self.internal_release_nonce(leg.chain_id, &leg.treasury_path, nonce);
+        }
+        self.internal_release_utxos(&leg);
+        self.internal_add_position(&account_id, vec![(leg.asset_id, leg.amount.0)]);
+        self.internal_mint_shares(&account_id, leg.amount.0, "refund");
+        self.total_assets = U128(self.total_assets.0 + leg.amount.0);
+
+        FundEvent::WithdrawalLegRefunded {
+            withdrawal_id,
+            leg_index,
+        }
+        .emit();
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    pub fn get_withdrawal(&self, withdrawal_id: u64) -> Option<Withdrawal> {
+        self.withdrawals.get(&withdrawal_id).cloned()
+    }
+
+    /// Signs a failed leg again.
+    ///
+    /// A dropped transaction can still be mined for as long as its nonce is unused, so the leg
+    /// is signed again at the same nonce, or spending the same outputs on Bitcoin, and at most
+    /// one of the two is ever mined. A new nonce is only taken once the old one is known to be
+    /// used: by the reverted transaction, or because the treasury was resynced past it.
+    pub fn retry_withdrawal_leg(
+        &mut self,
+        withdrawal_id: u64,
+        leg_index: u32,
+        network_details: Option<NetworkDetails>,
+    ) -> Promise {
+        self.assert_owner_or_relayer();
+        let leg = self.internal_leg(withdrawal_id, leg_index).clone();
+        assert_eq!(
+            leg.status,
+            WithdrawalStatus::Failed,
+            "Withdrawal leg is not {:?}",
+            WithdrawalStatus::Failed
+        );
+        let chain_id = leg.chain_id;
+        let reverted = leg
+            .tx_id
+            .and_then(|tx_id| self.signed_txs.get(&tx_id))
+            .is_some_and(|signed_tx| signed_tx.status == SignedTxStatus::Reverted);
+        let network_details = network_details.unwrap_or_else(|| NetworkDetails::defaults(chain_id));
+        assert_eq!(
+            network_details.chain_id, chain_id,
+            "Network details are for another chain"
+        );
+
+        let used = leg
+            .nonce
+            .is_some_and(|nonce| self.internal_nonce_used(chain_id, &leg.treasury_path, nonce));
+        let nonce = match leg.nonce {
+            Some(_) if reverted || used => self.internal_allocate_leg_nonce(&leg),
+            Some(nonce) => {
+                self.internal_reserve_nonce(chain_id, &leg.treasury_path, nonce);
+                Some(nonce)
+            }
+            None => None,
+        };
+        let leg = &mut self
+            .withdrawals
+            .get_mut(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+            .legs[leg_index as usize];
+        leg.nonce = nonce;
+        leg.status = WithdrawalStatus::Pending;
+        leg.sent_at = env::block_timestamp();
+        let leg = leg.clone();
+
+        self.create_and_sign_withdrawal(withdrawal_id, leg_index, &leg, network_details)
+    }
+
+    /// Sends the legs of a withdrawal that didn't fit in the gas of the calls before, as many
+    /// as `LEG_GAS_BUDGET` covers. Attach 300 Tgas.
+    pub fn sign_queued_legs(
+        &mut self,
+        withdrawal_id: u64,
+        network_details: Vec<NetworkDetails>,
+    ) -> Promise {
+        let account_id = &self
+            .withdrawals
+            .get(&withdrawal_id)
+            .unwrap_or_else(|| env::panic_str("Withdrawal not found"))
+            .account_id;
+        if env::predecessor_account_id() != *account_id {
+            self.assert_owner_or_relayer();
+        }
+        self.internal_send_queued_legs(withdrawal_id, &network_details)
+    }
+
+    /// Credits a leg back to the user when its signing callback never settled it, e.g. because
+    /// it ran out of gas, `STUCK_LEG_TIMEOUT_SEC` after it was sent.
+    ///
+    /// The MPC signer may still have returned a signature in the outcome of its `sign`
+    /// receipt, and a transaction built from it would pay the leg a second time. The leg is
+    /// only refunded once no such transaction can be mined: an EVM leg once the treasury was
+    /// resynced past its nonce, e.g. after a cancelling transaction at that nonce, and a
+    /// Bitcoin leg once `report_utxos` reported every output it picked as spent.
+    pub fn refund_stuck_leg(&mut self, withdrawal_id: u64, leg_index: u32) {
+        self.assert_owner_or_relayer();
+        let leg = self.internal_leg(withdrawal_id, leg_index);
+        assert_eq!(
+            leg.status,
+            WithdrawalStatus::Pending,
+            "Withdrawal leg is not {:?}",
+            WithdrawalStatus::Pending
+        );
+        assert!(
+            env::block_timestamp().saturating_sub(leg.sent_at)
+                >= STUCK_LEG_TIMEOUT_SEC * 1_000_000_000,
+            "Withdrawal leg is not stuck yet"
+        );
+        assert!(
+            !self.internal_leg_may_be_mined(leg),
+            "A transaction signed for the leg may still be mined"
+        );
+        match self.internal_chain(leg.chain_id).kind {
+            ChainKind::Evm => assert!(
+                leg.nonce.is_some_and(|nonce| {
+                    self.internal_nonce_used(leg.chain_id, &leg.treasury_path, nonce)
+                }),
+                "Nonce of the leg must be used up by a resync before it is refunded"
+            ),
+            ChainKind::Bitcoin { .. } => assert!(
+                self.internal_utxos_spent(leg),
+                "Outputs of the leg must be reported spent before it is refunded"
+            ),
+            ChainKind::AuroraEngine { .. } => {}
+        }
+
+        env::log_str(&format!(
+            "Refunding stuck withdrawal {} leg {}",
+            withdrawal_id, leg_index
+        ));
+        self.internal_refund_leg(withdrawal_id, leg_index);
+    }
+
+    /// Amounts `withdraw_underlying_assets` would send for `amount` of `account_id`'s position.
+    pub fn preview_withdraw(
+        &self,
+        account_id: AccountId,
+        amount: Option<WithdrawAmount>,
+    ) -> Vec<WithdrawalPreview> {
+        let shares = self.shares_to_redeem(&account_id, amount);
+        self.position_slice(&account_id, shares)
+            .into_iter()
+            .filter(|(_, amount)| *amount > 0)
+            .map(|(asset_id, amount)| {
+                let chain = self.withdrawal_chain(&asset_id);
+                WithdrawalPreview {
+                    chain_id: chain.chain_id,
+                    treasury_path: chain.treasury_path.clone(),
+                    asset_id,
+                    amount: U128(amount),
+                }
+            })
+            .collect()
+    }
+}